@@ -0,0 +1,237 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Generators for the different kinds of build file which cargo_embargo can output.
+//!
+//! Each [`Backend`] is given the crates of each variant of a package in turn, and then returns the
+//! build files for the package.
+
+pub mod android_bp;
+pub mod bazel;
+pub mod gn;
+pub mod rules_mk;
+
+use crate::cargo::{Crate, CrateType};
+use crate::config::{PackageConfig, PackageVariantConfig, VariantConfig};
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+
+/// A variant of a package to generate build rules for.
+pub struct Variant<'a> {
+    pub cfg: &'a VariantConfig,
+    pub package_cfg: &'a PackageVariantConfig,
+    pub package_name: &'a str,
+    pub crates: &'a [Crate],
+    /// The package's build script outputs for the variant.
+    pub out_files: &'a [PathBuf],
+    /// The directory in the package to which `out_files` are copied if `copy_out` is enabled.
+    pub out_dir: String,
+}
+
+/// A package to generate build files for.
+pub struct Package<'a> {
+    pub name: &'a str,
+    pub dir: &'a Path,
+    pub cfg: &'a PackageConfig,
+    /// The crates of the package, indexed by variant.
+    pub crates: &'a [Vec<Crate>],
+    /// Whether hunks of patches which fail to apply should be written to `.rej` files.
+    pub write_rejects: bool,
+}
+
+/// A generator for one kind of build file.
+pub trait Backend<'a> {
+    /// Returns whether build rules should be generated for variants with the given config.
+    fn enabled(&self, cfg: &VariantConfig) -> bool;
+
+    /// Generates the build rules for a variant of the package.
+    fn add_variant(&mut self, variant: &Variant<'a>) -> Result<()>;
+
+    /// Returns the path and contents of each build file for the package, after all its variants
+    /// have been added.
+    fn build_files(self: Box<Self>, package: &Package) -> Result<Vec<(PathBuf, String)>>;
+}
+
+/// Returns the crates of the variant for the first of its `targets`, for backends which don't
+/// support per-target differences.
+fn first_target_crates<'a>(variant: &Variant<'a>) -> Vec<&'a Crate> {
+    let first_target = variant.cfg.targets.first();
+    variant
+        .crates
+        .iter()
+        .filter(|c| c.target.is_none() || c.target.as_ref() == first_target)
+        .collect()
+}
+
+/// Returns the names of the library and proc macro crates, which other crates in the same package
+/// depend on by their target name rather than a label in another package.
+fn local_libraries<'a>(crates: &[&'a Crate]) -> BTreeSet<&'a str> {
+    crates
+        .iter()
+        .filter(|c| c.types.iter().any(|t| t.is_library() || *t == CrateType::ProcMacro))
+        .map(|c| c.name.as_str())
+        .collect()
+}
+
+/// Returns the name of the target for the given type of the crate, or `None` if it should be
+/// skipped, for the Bazel and GN backends.
+///
+/// `kind` describes the skipped target in the warning for benchmarks, e.g. "BUILD.gn target".
+fn target_name(
+    variant: &Variant,
+    crate_: &Crate,
+    crate_type: CrateType,
+    local_libraries: &BTreeSet<&str>,
+    kind: &str,
+) -> Option<String> {
+    match crate_type {
+        // A binary can't have the same name as a library in the package, such as from
+        // `src/main.rs` alongside `src/lib.rs`.
+        CrateType::Bin if local_libraries.contains(crate_.name.as_str()) => {
+            Some(format!("{}_bin", crate_.name))
+        }
+        CrateType::Example => Some(format!("{}_example_{}", crate_.package_name, crate_.name)),
+        CrateType::Test | CrateType::TestNoHarness => {
+            if crate_.empty_test {
+                return None;
+            }
+            let test_suffix =
+                crate_.main_src.to_string_lossy().replace('/', "_").replace(".rs", "");
+            Some(format!("{}_test_{test_suffix}", crate_.package_name))
+        }
+        CrateType::Bench => {
+            if variant.package_cfg.benches {
+                eprintln!("WARNING: skipped generation of {kind} for benchmark: {}", crate_.name);
+            }
+            None
+        }
+        _ => Some(crate_.name.clone()),
+    }
+}
+
+/// Returns the rustc flags for the crate's `cfg`s other than those in the `cfg_blocklist`, its
+/// `--cap-lints` and its codegen options.
+fn rustc_flags(crate_: &Crate, cfg: &VariantConfig) -> Vec<String> {
+    let mut flags: Vec<String> = crate_
+        .cfgs
+        .iter()
+        .filter(|crate_cfg| !cfg.cfg_blocklist.contains(crate_cfg))
+        .map(|crate_cfg| format!("--cfg={crate_cfg}"))
+        .collect();
+    if !crate_.cap_lints.is_empty() {
+        flags.push(crate_.cap_lints.split_whitespace().collect::<Vec<_>>().join("="));
+    }
+    flags.extend(crate_.codegens.iter().map(|codegen| format!("-C{codegen}")));
+    flags
+}
+
+/// Returns an error if `name` is already one of `names`, of which those from `variant_start` on
+/// were added for the current variant.
+fn check_unique_name<'b>(
+    mut names: impl Iterator<Item = &'b str>,
+    variant_start: usize,
+    name: &str,
+    crate_: &Crate,
+    kind: &str,
+) -> Result<()> {
+    match names.position(|existing| existing == name) {
+        Some(index) if index >= variant_start => {
+            bail!("Duplicate {kind} {name:?} for crate {}", crate_.name)
+        }
+        Some(_) => bail!("Duplicate {kind} {name:?} from more than one variant of the package"),
+        None => Ok(()),
+    }
+}
+
+/// Given a proposed module name, returns `None` if it is blocked by the given config, or
+/// else apply any name overrides and returns the name to use.
+fn override_module_name(
+    module_name: &str,
+    blocklist: &[String],
+    module_name_overrides: &BTreeMap<String, String>,
+    rename_map: &BTreeMap<&str, &str>,
+) -> Option<String> {
+    if blocklist.iter().any(|blocked_name| blocked_name == module_name) {
+        None
+    } else if let Some(overridden_name) = module_name_overrides.get(module_name) {
+        Some(overridden_name.to_string())
+    } else if let Some(renamed) = rename_map.get(module_name) {
+        Some(renamed.to_string())
+    } else {
+        Some(module_name.to_string())
+    }
+}
+
+/// Read and return license and other header lines from a build file.
+///
+/// Skips initial comment lines, then returns all lines before the first line
+/// starting with `rust_`, `genrule {`, or `LOCAL_DIR`.
+///
+/// If `path` could not be read, return a placeholder license TODO line.
+fn read_license_header(path: &Path) -> Result<String> {
+    // Keep the old license header.
+    match std::fs::read_to_string(path) {
+        Ok(s) => Ok(s
+            .lines()
+            .skip_while(|l| l.starts_with("//") || l.starts_with('#'))
+            .take_while(|l| {
+                !l.starts_with("rust_")
+                    && !l.starts_with("genrule {")
+                    && !l.starts_with("LOCAL_DIR")
+            })
+            .collect::<Vec<&str>>()
+            .join("\n")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok("// DO NOT SUBMIT: Add license before submitting.\n".to_string())
+        }
+        Err(e) => Err(anyhow!("error when reading {path:?}: {e}")),
+    }
+}
+
+/// Applies the given patch to the given file contents, returning the patched contents.
+///
+/// Prints a warning for each hunk which had to be applied with an offset or fuzz. If any hunks fail
+/// to apply and `reject_path` is given then they are written to it.
+fn patch_contents(
+    contents: &str,
+    file_name: &str,
+    patch_path: &Path,
+    reject_path: Option<&Path>,
+) -> Result<String> {
+    let patch =
+        read_to_string(patch_path).with_context(|| format!("failed to read {patch_path:?}"))?;
+    let patch = crate::patch::Patch::parse(&patch)
+        .with_context(|| format!("failed to parse patch {patch_path:?}"))?;
+    let result = patch.apply(contents, file_name);
+    for hunk in &result.inexact {
+        eprintln!(
+            "Warning: hunk #{} of {patch_path:?} applied at line {} of {file_name} with offset {} \
+             and fuzz {}.",
+            hunk.index, hunk.line, hunk.offset, hunk.fuzz
+        );
+    }
+    if !result.failures.is_empty() {
+        if let Some(reject_path) = reject_path {
+            write(reject_path, &result.rejects)
+                .with_context(|| format!("failed to write {reject_path:?}"))?;
+        }
+        bail!(
+            "failed to apply patch {patch_path:?} to {file_name}:\n{}",
+            result.failures.join("\n")
+        );
+    }
+    Ok(result.contents)
+}
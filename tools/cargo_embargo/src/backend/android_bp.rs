@@ -0,0 +1,2039 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend to generate Soong `Android.bp` files.
+//!
+//! Modules of variants with a `select` condition are merged at the end, so that properties which
+//! differ between them are wrapped in `select` statements.
+
+use super::{override_module_name, patch_contents, read_license_header, Backend, Package, Variant};
+use crate::bp::{
+    self, BpDefinition, BpFile, BpModule, BpProperties, BpSelect, BpSelectCondition,
+    BpSelectPattern, BpValue,
+};
+use crate::cargo::{CcLibrary, Crate, CrateType, ExternType};
+use crate::config::{
+    PackageConfig, PackageVariantConfig, SelectCondition, SelectValue, VariantConfig,
+};
+use crate::license::{choose_licenses, find_license_files, license_kinds};
+use anyhow::{bail, Context, Result};
+use spdx::LicenseReq;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// First line of the notice at the start of a generated Android.bp.
+const GENERATED_BANNER: &str = "// This file is generated by cargo_embargo.";
+
+/// Rust modules which shouldn't use the default generated names, to avoid conflicts or confusion.
+pub static RENAME_MAP: LazyLock<BTreeMap<&str, &str>> = LazyLock::new(|| {
+    [
+        ("libash", "libash_rust"),
+        ("libatomic", "libatomic_rust"),
+        ("libbacktrace", "libbacktrace_rust"),
+        ("libbase", "libbase_rust"),
+        ("libbase64", "libbase64_rust"),
+        ("libfuse", "libfuse_rust"),
+        ("libgcc", "libgcc_rust"),
+        ("liblog", "liblog_rust"),
+        ("libminijail", "libminijail_rust"),
+        ("libsync", "libsync_rust"),
+        ("libx86_64", "libx86_64_rust"),
+        ("libxml", "libxml_rust"),
+        ("protoc_gen_rust", "protoc-gen-rust"),
+    ]
+    .into_iter()
+    .collect()
+});
+
+/// Generates Android.bp files.
+#[derive(Default)]
+pub struct AndroidBpBackend<'a> {
+    bp_contents: String,
+    /// Modules for variants with a select condition, to be merged with other variants with the
+    /// same condition.
+    select_variants: BTreeMap<&'a SelectCondition, Vec<SelectVariant<'a>>>,
+}
+
+impl<'a> Backend<'a> for AndroidBpBackend<'a> {
+    fn enabled(&self, cfg: &VariantConfig) -> bool {
+        cfg.generate_androidbp
+    }
+
+    fn add_variant(&mut self, variant: &Variant<'a>) -> Result<()> {
+        if let Some(select) = &variant.cfg.select {
+            let modules = generate_android_bp_modules(
+                variant.cfg,
+                variant.package_cfg,
+                variant.package_name,
+                variant.crates,
+                variant.out_files,
+                &variant.out_dir,
+            )?;
+            self.select_variants
+                .entry(&select.condition)
+                .or_default()
+                .push((select.value.as_ref(), modules));
+        } else {
+            self.bp_contents += &generate_android_bp(
+                variant.cfg,
+                variant.package_cfg,
+                variant.package_name,
+                variant.crates,
+                variant.out_files,
+                &variant.out_dir,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn build_files(self: Box<Self>, package: &Package) -> Result<Vec<(PathBuf, String)>> {
+        let Package {
+            name: package_name,
+            dir: package_dir,
+            cfg: package_cfg,
+            crates,
+            write_rejects,
+        } = *package;
+        let mut bp_contents = self.bp_contents;
+        for (condition, variants) in self.select_variants {
+            let modules = merge_select_variants(condition, &variants)
+                .with_context(|| format!("failed to merge variants selected on {condition:?}"))?;
+            for m in modules {
+                m.write(&mut bp_contents)?;
+                bp_contents += "\n";
+            }
+        }
+
+        if let Some(path) = &package_cfg.add_toplevel_block {
+            bp_contents += &std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {path:?}"))?;
+            bp_contents += "\n";
+        }
+        if bp_contents.is_empty() {
+            return Ok(Vec::new());
+        }
+        let output_path = package_dir.join("Android.bp");
+        let license_modules = generate_license_modules(package_name, package_cfg, crates)?;
+        let mut generated =
+            bp::parser::parse(&bp_contents).context("failed to parse generated Android.bp")?;
+        if let Some(license_modules) = &license_modules {
+            if !license_modules.crate_licenses.is_empty() {
+                set_crate_licenses(&mut generated, &license_modules.crate_licenses);
+                bp_contents.clear();
+                generated.write(&mut bp_contents)?;
+            }
+        }
+        let license_modules = license_modules.map(|l| l.modules);
+        generated
+            .definitions
+            .extend(license_modules.iter().flatten().cloned().map(BpDefinition::Module));
+
+        let existing = read_existing_android_bp(&output_path)?;
+        let (license_header, trailing) = match &existing {
+            Some(existing) => {
+                let preserved = preserved_definitions(existing, &generated);
+                if license_modules.is_some() {
+                    // The license header isn't used, so keep everything after the generated
+                    // modules instead.
+                    (String::new(), [preserved.header, preserved.trailing].concat())
+                } else {
+                    let mut header = String::new();
+                    BpFile { definitions: preserved.header }.write(&mut header)?;
+                    (header, preserved.trailing)
+                }
+            }
+            None => (read_license_header(&output_path)?, Vec::new()),
+        };
+
+        let package_header =
+            generate_android_bp_package_header(license_modules, license_header.trim())?;
+        let bp_contents = package_header + &bp_contents;
+        let mut bp_contents = if let Some(patch) = package_cfg.patch.as_deref() {
+            let reject_path = write_rejects.then(|| package_dir.join("Android.bp.rej"));
+            patch_contents(&bp_contents, "Android.bp", patch, reject_path.as_deref())?
+        } else {
+            bp_contents
+        };
+
+        // Hand-written definitions may also have been added by the patch, in which case they are
+        // already there.
+        let patched =
+            bp::parser::parse(&bp_contents).context("failed to parse patched Android.bp")?;
+        if let Some(existing) = &existing {
+            warn_manual_edits(&output_path, existing, &patched);
+        }
+        let trailing: Vec<BpDefinition> =
+            trailing.into_iter().filter(|d| !is_defined_in(d, &patched)).collect();
+        BpFile { definitions: trailing }.write(&mut bp_contents)?;
+        Ok(vec![(output_path, bp_contents)])
+    }
+}
+
+/// Reads and parses the existing Android.bp at `path`, if there is one.
+///
+/// If it can't be parsed then a warning is printed and `None` is returned, so that only its header
+/// is kept.
+fn read_existing_android_bp(path: &Path) -> Result<Option<BpFile>> {
+    match read_to_string(path) {
+        Ok(contents) => match bp::parser::parse(&contents) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) => {
+                eprintln!("Warning: failed to parse {path:?}, only keeping its header: {e:#}");
+                Ok(None)
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {path:?}")),
+    }
+}
+
+/// Hand-written definitions from an existing Android.bp, to keep when it is regenerated.
+#[derive(Debug, Default)]
+struct PreservedDefinitions {
+    /// Definitions before the first generated module, which form the license header.
+    header: Vec<BpDefinition>,
+    /// Definitions after the first generated module.
+    trailing: Vec<BpDefinition>,
+}
+
+/// Returns the definitions from the existing Android.bp which weren't generated by cargo_embargo,
+/// and won't be replaced by anything in `generated`.
+///
+/// Any `rust_*` or `genrule` modules are assumed to have been generated, even if they are no longer
+/// part of `generated`.
+fn preserved_definitions(existing: &BpFile, generated: &BpFile) -> PreservedDefinitions {
+    let mut preserved = PreservedDefinitions::default();
+    let mut seen_generated_module = false;
+    for definition in &existing.definitions {
+        let is_generated = match definition {
+            BpDefinition::Comment(lines) => is_generated_banner(lines),
+            BpDefinition::Assignment { .. } => false,
+            BpDefinition::Module(m) => {
+                m.module_type.starts_with("rust_") || m.module_type == "genrule"
+            }
+        } || is_defined_in(definition, generated);
+        if is_generated {
+            seen_generated_module |= matches!(definition, BpDefinition::Module(_));
+            continue;
+        }
+        let mut definition = definition.clone();
+        if let BpDefinition::Module(m) = &mut definition {
+            if is_generated_banner(&m.comments) {
+                m.comments.clear();
+            }
+        }
+        if seen_generated_module {
+            preserved.trailing.push(definition);
+        } else {
+            preserved.header.push(definition);
+        }
+    }
+    preserved
+}
+
+/// Returns whether the given comment lines are the notice which cargo_embargo writes at the start
+/// of a generated Android.bp.
+fn is_generated_banner(comments: &[String]) -> bool {
+    comments.first().is_some_and(|line| line == GENERATED_BANNER)
+}
+
+/// Returns whether `file` already has a module of the same type and name as `definition`, an
+/// identical assignment, or an identical comment.
+fn is_defined_in(definition: &BpDefinition, file: &BpFile) -> bool {
+    match definition {
+        BpDefinition::Module(module) => {
+            file.modules().any(|m| m.module_type == module.module_type && m.name() == module.name())
+        }
+        BpDefinition::Assignment { name, value, append, .. } => file.definitions.iter().any(|d| {
+            matches!(d, BpDefinition::Assignment { name: n, value: v, append: a, .. }
+                    if n == name && v == value && a == append)
+        }),
+        BpDefinition::Comment(_) => file.definitions.contains(definition),
+    }
+}
+
+/// Warns about properties of modules in the existing Android.bp which aren't in the corresponding
+/// generated module, as they must have been added by hand and will be lost.
+fn warn_manual_edits(path: &Path, existing: &BpFile, generated: &BpFile) {
+    for module in generated.modules() {
+        let Some(name) = module.name() else {
+            continue;
+        };
+        let Some(existing_module) = existing
+            .modules()
+            .find(|m| m.module_type == module.module_type && m.name() == Some(name))
+        else {
+            continue;
+        };
+        let mut extra = Vec::new();
+        find_extra_properties(&existing_module.props, &module.props, "", &mut extra);
+        if !extra.is_empty() {
+            eprintln!(
+                "Warning: module {name:?} in {path:?} has manually added properties which will be \
+                 removed: {}. Use `add_module_block` or `patch` to keep them.",
+                extra.join(", ")
+            );
+        }
+    }
+}
+
+/// Adds the paths of properties which are in `existing` but not `generated` to `extra`, recursing
+/// into objects which are in both.
+fn find_extra_properties(
+    existing: &BpProperties,
+    generated: &BpProperties,
+    prefix: &str,
+    extra: &mut Vec<String>,
+) {
+    for (name, value) in &existing.map {
+        let path = format!("{prefix}{name}");
+        match (value, generated.map.get(name)) {
+            (_, None) => extra.push(path),
+            (BpValue::Object(existing), Some(BpValue::Object(generated))) => {
+                find_extra_properties(existing, generated, &format!("{path}."), extra)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The license modules for a package.
+#[derive(Debug)]
+struct LicenseModules {
+    /// The `package` module followed by the `license` modules.
+    modules: Vec<BpModule>,
+    /// The name of the `license` module for each crate whose licenses differ from the package's
+    /// default, by crate name.
+    crate_licenses: BTreeMap<String, String>,
+}
+
+/// Returns the `package` and `license` modules for the package, if the licenses of all of its
+/// crates are known.
+///
+/// There is a `license` module for each distinct set of licenses chosen for the crates, which
+/// normally means just one. The package's default is the license module of its first crate, and
+/// modules for crates with other licenses must set their own `licenses`.
+fn generate_license_modules(
+    package_name: &str,
+    package_cfg: &PackageConfig,
+    crates: &[Vec<Crate>],
+) -> Result<Option<LicenseModules>> {
+    // The licenses chosen for the crates, along with the first crate with each.
+    let mut licenses: Vec<(Vec<LicenseReq>, &Crate)> = Vec::new();
+    // The index in `licenses` of the licenses chosen for each crate.
+    let mut crate_license_indices: BTreeMap<&str, usize> = BTreeMap::new();
+    for c in crates.iter().flatten() {
+        let Some(license) = &c.license else {
+            eprintln!("Crate {} has no license.", c.name);
+            return Ok(None);
+        };
+        let chosen = match choose_licenses(license) {
+            Ok(chosen) => chosen,
+            Err(e) => {
+                eprintln!("{e:#}");
+                return Ok(None);
+            }
+        };
+        let index = match licenses.iter().position(|(other, _)| *other == chosen) {
+            Some(index) => index,
+            None => {
+                licenses.push((chosen, c));
+                licenses.len() - 1
+            }
+        };
+        crate_license_indices.entry(&c.name).or_insert(index);
+    }
+    if licenses.is_empty() {
+        return Ok(None);
+    }
+    if licenses.len() > 1 {
+        eprintln!("Crates have different licenses, generating a license module for each.");
+    }
+
+    let mut license_modules = Vec::new();
+    for (chosen, c) in &licenses {
+        let license_name = if licenses.len() == 1 {
+            format!("external_rust_crates_{}_license", package_name)
+        } else {
+            let suffix: Vec<String> =
+                chosen.iter().map(|license| license.to_string().replace(' ', "-")).collect();
+            format!("external_rust_crates_{}_{}_license", package_name, suffix.join("_"))
+        };
+        let mut kinds: Vec<String> = Vec::new();
+        for kind in chosen.iter().flat_map(license_kinds) {
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+
+        let mut license_module = BpModule::new("license".to_string());
+        license_module.props.set("name", license_name);
+        license_module.props.set("visibility", vec![":__subpackages__"]);
+        license_module.props.set("license_kinds", kinds);
+        license_module.props.set("license_text", license_texts(package_cfg, c, chosen)?);
+        license_modules.push(license_module);
+    }
+
+    let license_names: Vec<&str> = license_modules.iter().map(|m| m.name().unwrap()).collect();
+    let crate_licenses = crate_license_indices
+        .into_iter()
+        .filter(|(_, index)| *index != 0)
+        .map(|(crate_name, index)| (crate_name.to_string(), license_names[index].to_string()))
+        .collect();
+
+    let mut package_module = BpModule::new("package".to_string());
+    package_module.props.set("default_team", "trendy_team_android_rust");
+    package_module.props.set("default_applicable_licenses", vec![license_names[0]]);
+    Ok(Some(LicenseModules {
+        modules: [package_module].into_iter().chain(license_modules).collect(),
+        crate_licenses,
+    }))
+}
+
+/// Sets `licenses` on each module in `file` for a crate in `crate_licenses`, which maps crate names
+/// to the name of the crate's `license` module.
+fn set_crate_licenses(file: &mut BpFile, crate_licenses: &BTreeMap<String, String>) {
+    for definition in &mut file.definitions {
+        if let BpDefinition::Module(m) = definition {
+            if let Some(license) =
+                m.props.get_string("crate_name").and_then(|name| crate_licenses.get(name))
+            {
+                m.props.set("licenses", vec![license.clone()]);
+            }
+        }
+    }
+}
+
+/// Returns the `license_text` for the `license` module for the given licenses of a crate.
+///
+/// This is the configured `license_text` or the crate's `license_file` if there is one, or else
+/// the license file in the package for each license. Licenses without a file of their own fall back
+/// to the license files in the package which aren't for any particular license, such as `LICENSE`
+/// or `COPYING`.
+fn license_texts(
+    package_cfg: &PackageConfig,
+    crate_: &Crate,
+    licenses: &[LicenseReq],
+) -> Result<Vec<String>> {
+    if let Some(license_text) = package_cfg.license_text.as_ref().or(crate_.license_file.as_ref()) {
+        return Ok(vec![license_text.clone()]);
+    }
+    let license_files = find_license_files(&crate_.package_dir)
+        .with_context(|| format!("failed to find license files for {}", crate_.package_name))?;
+    let unclassified: Vec<&PathBuf> = license_files
+        .iter()
+        .filter(|(_, file_licenses)| file_licenses.is_empty())
+        .map(|(path, _)| path)
+        .collect();
+    let mut texts = Vec::new();
+    for license in licenses {
+        // A license file for e.g. Apache-2.0 also applies for Apache-2.0 WITH LLVM-exception.
+        let paths = match license_files.iter().find(|(_, file_licenses)| {
+            file_licenses.iter().any(|file_license| file_license.license == license.license)
+        }) {
+            Some((path, _)) => vec![path],
+            None if unclassified.is_empty() => bail!(
+                "no license file found for {license} in {:?}, set `license_text` for package {}",
+                crate_.package_dir,
+                crate_.package_name
+            ),
+            None => unclassified.clone(),
+        };
+        for path in paths {
+            let text = path.to_string_lossy().into_owned();
+            if !texts.contains(&text) {
+                texts.push(text);
+            }
+        }
+    }
+    Ok(texts)
+}
+
+/// Generates the start of an Android.bp, with either the given license modules or else the given
+/// hand-written license header.
+fn generate_android_bp_package_header(
+    license_modules: Option<Vec<BpModule>>,
+    license_header: &str,
+) -> Result<String> {
+    if let Some(modules) = license_modules {
+        let mut bp_contents = GENERATED_BANNER.to_owned()
+            + "\n// Do not modify this file because the changes will be overridden on upgrade.\n\n";
+        for m in modules {
+            m.write(&mut bp_contents)?;
+            bp_contents += "\n";
+        }
+        return Ok(bp_contents);
+    }
+
+    Ok(GENERATED_BANNER.to_owned()
+        + "\n"
+        + "// Do not modify this file after the first \"rust_*\" or \"genrule\" module\n"
+        + "// because the changes will be overridden on upgrade.\n"
+        + "// Content before the first \"rust_*\" or \"genrule\" module is preserved.\n\n"
+        + license_header
+        + "\n")
+}
+
+/// Generates and returns a Soong Blueprint for the given set of crates, for a single variant of a
+/// package.
+///
+/// `out_files` are the package's build script outputs for the variant, which are copied to
+/// `out_dir` if `copy_out` is enabled.
+fn generate_android_bp(
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    package_name: &str,
+    crates: &[Crate],
+    out_files: &[PathBuf],
+    out_dir: &str,
+) -> Result<String> {
+    let mut bp_contents = String::new();
+    for m in
+        generate_android_bp_modules(cfg, package_cfg, package_name, crates, out_files, out_dir)?
+    {
+        m.write(&mut bp_contents)?;
+        bp_contents += "\n";
+    }
+    Ok(bp_contents)
+}
+
+/// Generates the Soong modules for the given set of crates, for a single variant of a package,
+/// sorted by name.
+fn generate_android_bp_modules(
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    package_name: &str,
+    crates: &[Crate],
+    out_files: &[PathBuf],
+    out_dir: &str,
+) -> Result<Vec<BpModule>> {
+    let mut modules = Vec::new();
+
+    let extra_srcs = if package_cfg.copy_out && !out_files.is_empty() {
+        let outs: Vec<String> = out_files
+            .iter()
+            .map(|f| f.file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+
+        let mut m = BpModule::new("genrule".to_string());
+        if let Some(module_name) = override_module_name(
+            &format!("copy_{package_name}_build_{out_dir}"),
+            &cfg.module_blocklist,
+            &cfg.module_name_overrides,
+            &RENAME_MAP,
+        ) {
+            m.props.set("name", module_name.clone());
+            m.props.set("srcs", vec![format!("{out_dir}/*")]);
+            m.props.set("cmd", "cp $(in) $(genDir)");
+            m.props.set("out", outs);
+            modules.push(m);
+
+            vec![":".to_string() + &module_name]
+        } else {
+            vec![]
+        }
+    } else {
+        vec![]
+    };
+
+    let mut target_modules: BTreeMap<String, Vec<(String, BpModule)>> = BTreeMap::new();
+    for c in crates {
+        let crate_modules =
+            crate_to_bp_modules(c, cfg, package_cfg, &extra_srcs).with_context(|| {
+                format!(
+                    "failed to generate bp module for crate \"{}\" with package name \"{}\"",
+                    c.name, c.package_name
+                )
+            })?;
+        match &c.target {
+            // Modules for different targets are merged below.
+            Some(target) if cfg.targets.len() > 1 => {
+                for m in crate_modules {
+                    let name = m.props.get_string("name").unwrap().to_string();
+                    target_modules.entry(name).or_default().push((target.clone(), m));
+                }
+            }
+            _ => modules.extend(crate_modules),
+        }
+    }
+    for (name, mut target_modules) in target_modules {
+        target_modules.sort();
+        target_modules.dedup();
+        modules.push(
+            merge_target_modules(&cfg.targets, &target_modules)
+                .with_context(|| format!("failed to merge per-target modules for {name}"))?,
+        );
+    }
+
+    // In some cases there are nearly identical rustc invocations that that get processed into
+    // identical BP modules. So far, dedup'ing them is a good enough fix. At some point we might
+    // need something more complex, maybe like cargo2android's logic for merging crates.
+    modules.sort();
+    modules.dedup();
+
+    modules.sort_by_key(|m| m.props.get_string("name").unwrap().to_string());
+    Ok(modules)
+}
+
+/// The value of a variant's select condition, or `None` for the default variant, and the modules
+/// generated for it.
+type SelectVariant<'a> = (Option<&'a SelectValue>, Vec<BpModule>);
+
+/// Properties which may differ between variants with the same select condition. `srcs` differs if
+/// build script outputs are copied, as each variant has its own genrule to copy them.
+const SELECTABLE_PROPERTIES: [&str; 4] = ["cfgs", "features", "rustlibs", "srcs"];
+
+/// Merges the modules generated for several variants with the same select condition into a single
+/// set of modules, using Soong `select()` expressions for the properties which differ.
+fn merge_select_variants(
+    condition: &SelectCondition,
+    variants: &[SelectVariant],
+) -> Result<Vec<BpModule>> {
+    if variants.iter().filter(|(value, _)| value.is_none()).count() != 1 {
+        bail!("Exactly one variant must have no select value, to be used by default.");
+    }
+    for (i, (value, _)) in variants.iter().enumerate() {
+        if value.is_some() && variants[..i].iter().any(|(other, _)| other == value) {
+            bail!("Several variants have the same select value {value:?}.");
+        }
+    }
+    let condition = match condition {
+        SelectCondition::ReleaseFlag(flag) => {
+            BpSelectCondition { function: "release_flag".to_string(), args: vec![flag.clone()] }
+        }
+        SelectCondition::SoongConfigVariable(namespace, variable) => BpSelectCondition {
+            function: "soong_config_variable".to_string(),
+            args: vec![namespace.clone(), variable.clone()],
+        },
+    };
+    // Soong requires the default case to come last.
+    let mut variants: Vec<_> = variants.iter().collect();
+    variants.sort_by_key(|(value, _)| value.is_none());
+    let patterns: Vec<BpSelectPattern> = variants
+        .iter()
+        .map(|(value, _)| match value {
+            Some(SelectValue::Bool(b)) => BpSelectPattern::Bool(*b),
+            Some(SelectValue::String(s)) => BpSelectPattern::String(s.clone()),
+            None => BpSelectPattern::Default,
+        })
+        .collect();
+
+    // The genrules which copy the build script outputs of each variant are kept as they are, and
+    // selected between in the `srcs` of the other modules.
+    let mut merged_modules: Vec<BpModule> = Vec::new();
+    for m in variants.iter().flat_map(|(_, modules)| modules).filter(|m| m.module_type == "genrule")
+    {
+        match merged_modules.iter().find(|other| other.name() == m.name()) {
+            Some(other) if other != m => {
+                bail!("Genrule {:?} differs between variants.", m.name().unwrap_or_default())
+            }
+            Some(_) => {}
+            None => merged_modules.push(m.clone()),
+        }
+    }
+    let (_, first_modules) = variants[0];
+    let first_modules: Vec<_> =
+        first_modules.iter().filter(|m| m.module_type != "genrule").collect();
+    for (_, modules) in &variants {
+        if let Some(m) = modules.iter().find(|m| {
+            m.module_type != "genrule" && !first_modules.iter().any(|f| f.name() == m.name())
+        }) {
+            bail!("Module {:?} is only generated for some variants.", m.name().unwrap_or_default());
+        }
+    }
+    for first in first_modules {
+        let name = first.name().context("Generated module has no name")?;
+        let modules = variants
+            .iter()
+            .map(|(_, modules)| {
+                modules.iter().find(|m| m.name() == Some(name)).with_context(|| {
+                    format!("Module {name:?} is only generated for some variants.")
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let without_selectable = |m: &BpModule| {
+            let mut m = m.clone();
+            m.comments.clear();
+            m.props.map.retain(|k, _| !SELECTABLE_PROPERTIES.contains(&k.as_str()));
+            m.props.comments.retain(|k, _| !SELECTABLE_PROPERTIES.contains(&k.as_str()));
+            m
+        };
+        let mut merged = without_selectable(first);
+        if modules.iter().any(|m| without_selectable(m) != merged) {
+            bail!(
+                "Module {name:?} differs between variants in properties other than {}.",
+                SELECTABLE_PROPERTIES.join(", ")
+            );
+        }
+        merged.comments = merged_comments(modules.iter().copied());
+        let mut property_comments = merged_property_comments(modules.iter().copied());
+        for property in SELECTABLE_PROPERTIES {
+            if let Some(comments) = property_comments.remove(property) {
+                merged.props.comments.insert(property.to_string(), comments);
+            }
+            let values = modules
+                .iter()
+                .map(|m| match m.props.map.get(property) {
+                    None => Ok(Vec::new()),
+                    Some(BpValue::List(values)) => Ok(values.clone()),
+                    Some(value) => bail!("Property {property} of {name:?} isn't a list: {value:?}"),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            // Values which are the same for all variants are kept outside the select.
+            let common: Vec<BpValue> = values[0]
+                .iter()
+                .filter(|value| values.iter().all(|vs| vs.contains(value)))
+                .cloned()
+                .collect();
+            if values.iter().all(|vs| vs.len() == common.len()) {
+                merged.props.set_if_nonempty(property, common);
+                continue;
+            }
+            let cases = patterns
+                .iter()
+                .zip(values)
+                .map(|(pattern, values)| {
+                    let values = values.into_iter().filter(|v| !common.contains(v)).collect();
+                    (vec![pattern.clone()], Some(BpValue::List(values)))
+                })
+                .collect();
+            let select = BpValue::Select(BpSelect { conditions: vec![condition.clone()], cases });
+            merged.props.set(
+                property,
+                if common.is_empty() {
+                    select
+                } else {
+                    BpValue::Concat(vec![BpValue::List(common), select])
+                },
+            );
+        }
+        merged_modules.push(merged);
+    }
+    merged_modules.sort_by_key(|m| m.name().unwrap_or_default().to_string());
+    Ok(merged_modules)
+}
+
+/// Returns the comments of all the given modules without duplicates, so that a module merged from
+/// them is e.g. marked as having rustc warnings if any of them has.
+fn merged_comments<'a>(modules: impl IntoIterator<Item = &'a BpModule>) -> Vec<String> {
+    let mut comments = Vec::new();
+    for m in modules {
+        for comment in &m.comments {
+            if !comments.contains(comment) {
+                comments.push(comment.clone());
+            }
+        }
+    }
+    comments
+}
+
+/// Returns the comments on each property of the given modules without duplicates, keyed by property
+/// name.
+fn merged_property_comments<'a>(
+    modules: impl IntoIterator<Item = &'a BpModule>,
+) -> BTreeMap<String, Vec<String>> {
+    let mut property_comments: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for m in modules {
+        for (property, comments) in &m.props.comments {
+            let merged = property_comments.entry(property.clone()).or_default();
+            for comment in comments {
+                if !merged.contains(comment) {
+                    merged.push(comment.clone());
+                }
+            }
+        }
+    }
+    property_comments
+}
+
+/// The Soong OS class and architecture of a Rust target triple.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct SoongTarget {
+    /// The Soong OS, e.g. "android" or "linux_glibc".
+    os: &'static str,
+    /// The Soong architecture, e.g. "arm64" or "x86_64".
+    arch: &'static str,
+}
+
+impl SoongTarget {
+    /// Returns the Soong OS and architecture corresponding to the given Rust target triple.
+    fn from_triple(triple: &str) -> Result<Self> {
+        let arch = match triple.split('-').next().unwrap() {
+            "aarch64" => "arm64",
+            arch if arch.starts_with("arm") || arch.starts_with("thumbv7") => "arm",
+            "riscv64" | "riscv64gc" => "riscv64",
+            "x86_64" => "x86_64",
+            "i686" | "i586" => "x86",
+            _ => bail!("unsupported architecture in target triple {triple:?}"),
+        };
+        let os = if triple.contains("-android") {
+            "android"
+        } else if triple.ends_with("-linux-gnu") {
+            "linux_glibc"
+        } else if triple.ends_with("-linux-musl") {
+            "linux_musl"
+        } else if triple.ends_with("-apple-darwin") {
+            "darwin"
+        } else if triple.contains("-windows-") {
+            "windows"
+        } else {
+            bail!("unsupported OS in target triple {triple:?}");
+        };
+        Ok(Self { os, arch })
+    }
+
+    /// Returns whether this is a host rather than a device target.
+    fn is_host(self) -> bool {
+        self.os != "android"
+    }
+}
+
+/// Merges the given modules generated from the same crate for different target triples into a
+/// single module.
+///
+/// Properties which are the same for all targets are kept at the top level. Otherwise the
+/// differences are put in a `target: { android: {}, host: {} }` block if possible, else an
+/// `arch: {}` block if possible, else in per-target `target: { android_arm64: {}, ... }` blocks.
+/// For list properties, only the elements which are not common to all targets are moved.
+///
+/// Targets in `targets` for which there is no module have the merged module disabled.
+fn merge_target_modules(targets: &[String], modules: &[(String, BpModule)]) -> Result<BpModule> {
+    let (first_triple, first) = &modules[0];
+    for (triple, m) in modules {
+        if m.module_type != first.module_type {
+            bail!(
+                "module type for {triple} ({}) differs from that for {first_triple} ({})",
+                m.module_type,
+                first.module_type
+            );
+        }
+    }
+    let mut module_triples: Vec<&str> = modules.iter().map(|(triple, _)| triple.as_str()).collect();
+    module_triples.dedup();
+    if module_triples.len() != modules.len() {
+        bail!("conflicting modules for the same target");
+    }
+    if modules.len() == 1 && targets == [first_triple.clone()] {
+        return Ok(first.clone());
+    }
+    let soong_targets = targets
+        .iter()
+        .map(|triple| Ok((triple.as_str(), SoongTarget::from_triple(triple)?)))
+        .chain(
+            modules
+                .iter()
+                .map(|(triple, _)| Ok((triple.as_str(), SoongTarget::from_triple(triple)?))),
+        )
+        .collect::<Result<BTreeMap<_, _>>>()?;
+
+    let mut merged = BpModule::new(first.module_type.clone());
+    merged.comments = merged_comments(modules.iter().map(|(_, m)| m));
+    let property_comments = merged_property_comments(modules.iter().map(|(_, m)| m));
+    merged.props.raw_block = first.props.raw_block.clone();
+    let keys: BTreeSet<&String> = modules.iter().flat_map(|(_, m)| m.props.map.keys()).collect();
+    for key in keys {
+        let values: Vec<(&str, Option<&BpValue>)> =
+            modules.iter().map(|(triple, m)| (triple.as_str(), m.props.map.get(key))).collect();
+        if values.iter().all(|(_, value)| *value == values[0].1) {
+            merged.props.map.insert(key.clone(), values[0].1.unwrap().clone());
+            continue;
+        }
+        let per_target = if values
+            .iter()
+            .all(|(_, value)| value.is_none() || matches!(value, Some(BpValue::List(_))))
+        {
+            let lists: Vec<(&str, &[BpValue])> = values
+                .iter()
+                .map(|(triple, value)| match value {
+                    Some(BpValue::List(list)) => (*triple, list.as_slice()),
+                    _ => (*triple, [].as_slice()),
+                })
+                .collect();
+            let common: Vec<BpValue> = lists[0]
+                .1
+                .iter()
+                .filter(|v| lists.iter().all(|(_, list)| list.contains(v)))
+                .cloned()
+                .collect();
+            merged.props.set_if_nonempty(key, common.clone());
+            lists
+                .into_iter()
+                .map(|(triple, list)| {
+                    let rest: Vec<BpValue> =
+                        list.iter().filter(|v| !common.contains(v)).cloned().collect();
+                    (triple, (!rest.is_empty()).then_some(BpValue::List(rest)))
+                })
+                .collect()
+        } else {
+            values.into_iter().map(|(triple, value)| (triple, value.cloned())).collect()
+        };
+        set_per_target_values(&mut merged.props, key, &soong_targets, per_target);
+    }
+    for (key, comments) in property_comments {
+        add_property_comments(&mut merged.props, &key, &comments);
+    }
+
+    // Disable the module for targets which it wasn't generated for.
+    if targets.iter().any(|triple| !module_triples.contains(&triple.as_str())) {
+        let enabled = soong_targets
+            .keys()
+            .map(|triple| {
+                let enabled = module_triples.contains(triple);
+                (*triple, (!enabled).then_some(BpValue::Bool(false)))
+            })
+            .collect();
+        set_per_target_values(&mut merged.props, "enabled", &soong_targets, enabled);
+    }
+
+    Ok(merged)
+}
+
+/// Adds the given comments to the property `key` of `props`, or if it isn't set at the top level
+/// then to wherever it is set in the `target` or `arch` blocks.
+fn add_property_comments(props: &mut BpProperties, key: &str, comments: &[String]) {
+    if props.map.contains_key(key) {
+        props.comments.insert(key.to_string(), comments.to_vec());
+        return;
+    }
+    for block in ["target", "arch"] {
+        if let Some(BpValue::Object(block)) = props.map.get_mut(block) {
+            for group in block.map.values_mut() {
+                if let BpValue::Object(group) = group {
+                    if group.map.contains_key(key) {
+                        group.comments.insert(key.to_string(), comments.to_vec());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sets the given property in the appropriate `target` or `arch` blocks of `props`, for the given
+/// values for each target triple. `None` means that the property shouldn't be set for that triple.
+fn set_per_target_values(
+    props: &mut BpProperties,
+    key: &str,
+    soong_targets: &BTreeMap<&str, SoongTarget>,
+    values: Vec<(&str, Option<BpValue>)>,
+) {
+    // Tries to group the values by the given function, returning `None` if different triples in
+    // the same group have different values.
+    let group_by = |group: &dyn Fn(SoongTarget) -> String| {
+        let mut groups: BTreeMap<String, &Option<BpValue>> = BTreeMap::new();
+        for (triple, value) in &values {
+            let group_value = groups.entry(group(soong_targets[triple])).or_insert(value);
+            if *group_value != value {
+                return None;
+            }
+        }
+        Some(groups)
+    };
+    let (block, groups) = if let Some(groups) =
+        group_by(&|t| if t.is_host() { "host".to_string() } else { "android".to_string() })
+    {
+        ("target", groups)
+    } else if let Some(groups) = group_by(&|t| t.arch.to_string()) {
+        ("arch", groups)
+    } else {
+        ("target", group_by(&|t| format!("{}_{}", t.os, t.arch)).unwrap())
+    };
+    for (group, value) in groups {
+        if let Some(value) = value {
+            props.object(block).object(&group).map.insert(key.to_string(), value.clone());
+        }
+    }
+}
+
+/// Convert a `Crate` into `BpModule`s.
+///
+/// If messy business logic is necessary, prefer putting it here.
+fn crate_to_bp_modules(
+    crate_: &Crate,
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    extra_srcs: &[String],
+) -> Result<Vec<BpModule>> {
+    let mut modules = Vec::new();
+    for crate_type in &crate_.types {
+        let host = if package_cfg.device_supported { "" } else { "_host" };
+        let rlib = if package_cfg.force_rlib { "_rlib" } else { "" };
+        let (module_type, module_name) = match crate_type {
+            CrateType::Bin => ("rust_binary".to_string() + host, crate_.name.clone()),
+            CrateType::Example => {
+                let stem = crate_.package_name.clone() + "_example_" + &crate_.name;
+                ("rust_binary".to_string() + host, stem)
+            }
+            CrateType::Lib | CrateType::RLib => {
+                let stem = "lib".to_string() + &crate_.name;
+                ("rust_library".to_string() + host + rlib, stem)
+            }
+            CrateType::DyLib => {
+                let stem = "lib".to_string() + &crate_.name;
+                ("rust_library".to_string() + host + "_dylib", stem + "_dylib")
+            }
+            CrateType::CDyLib => {
+                let stem = "lib".to_string() + &crate_.name;
+                ("rust_ffi".to_string() + host + "_shared", stem + "_shared")
+            }
+            CrateType::StaticLib => {
+                let stem = "lib".to_string() + &crate_.name;
+                ("rust_ffi".to_string() + host + "_static", stem + "_static")
+            }
+            CrateType::ProcMacro => {
+                let stem = "lib".to_string() + &crate_.name;
+                ("rust_proc_macro".to_string(), stem)
+            }
+            CrateType::Test | CrateType::TestNoHarness => {
+                let suffix = crate_.main_src.to_string_lossy().into_owned();
+                let suffix = suffix.replace('/', "_").replace(".rs", "");
+                let stem = crate_.package_name.clone() + "_test_" + &suffix;
+                if crate_.empty_test {
+                    return Ok(Vec::new());
+                }
+                ("rust_test".to_string() + host, stem)
+            }
+            CrateType::Bench => {
+                if !package_cfg.benches {
+                    return Ok(Vec::new());
+                }
+                let suffix = crate_.main_src.to_string_lossy().into_owned();
+                let suffix = suffix.replace('/', "_").replace(".rs", "");
+                let stem = crate_.package_name.clone() + "_bench_" + &suffix;
+                ("rust_benchmark".to_string() + host, stem)
+            }
+        };
+
+        let mut m = BpModule::new(module_type.clone());
+        if cfg.max_rustc_warnings.is_some() && !crate_.warnings.is_empty() {
+            m.comments.push("// has rustc warnings".to_string());
+        }
+        let Some(module_name) = override_module_name(
+            &module_name,
+            &cfg.module_blocklist,
+            &cfg.module_name_overrides,
+            &RENAME_MAP,
+        ) else {
+            continue;
+        };
+        if matches!(
+            crate_type,
+            CrateType::Lib
+                | CrateType::RLib
+                | CrateType::DyLib
+                | CrateType::CDyLib
+                | CrateType::StaticLib
+        ) && !module_name.starts_with(&format!("lib{}", crate_.name))
+        {
+            bail!("Module name must start with lib{} but was {}", crate_.name, module_name);
+        }
+        m.props.set("name", module_name.clone());
+
+        if let Some(defaults) = &cfg.global_defaults {
+            m.props.set("defaults", vec![defaults.clone()]);
+        }
+
+        if package_cfg.host_supported
+            && package_cfg.device_supported
+            && module_type != "rust_proc_macro"
+        {
+            m.props.set("host_supported", true);
+        }
+
+        if module_type != "rust_proc_macro" {
+            if package_cfg.host_supported && !package_cfg.host_cross_supported {
+                m.props.set("host_cross_supported", false);
+            } else if crate_.externs.iter().any(|extern_dep| extern_dep.name == "proc_macro2") {
+                // proc_macro2 is host_cross_supported: false.
+                // If there's a dependency on it, then we shouldn't build for HostCross.
+                m.props.set("host_cross_supported", false);
+            } else if crate_.package_name == "proc-macro2" {
+                m.props.set("host_cross_supported", false);
+            }
+        }
+
+        if !crate_type.is_test() && package_cfg.host_supported && package_cfg.host_first_multilib {
+            m.props.set("compile_multilib", "first");
+        }
+        if crate_type.is_c_library() {
+            m.props.set_if_nonempty("include_dirs", package_cfg.exported_c_header_dir.clone());
+        }
+
+        m.props.set("crate_name", crate_.name.clone());
+        m.props.set("cargo_env_compat", true);
+
+        if let Some(version) = &crate_.version {
+            m.props.set("cargo_pkg_version", version.clone());
+        }
+
+        if crate_type.is_test() {
+            m.props.set("test_suites", vec!["general-tests"]);
+            m.props.set("auto_gen_config", true);
+            if package_cfg.host_supported && crate_type != &CrateType::Bench {
+                m.props.object("test_options").set("unit_test", !package_cfg.no_presubmit);
+            }
+            if crate_type == &CrateType::TestNoHarness {
+                m.props.set("test_harness", false);
+            }
+        }
+
+        m.props.set("crate_root", crate_.main_src.clone());
+        m.props.set_if_nonempty("srcs", extra_srcs.to_owned());
+
+        m.props.set("edition", crate_.edition.clone());
+        m.props.set_if_nonempty("features", crate_.features.clone());
+        let cfgs: Vec<String> = crate_
+            .cfgs
+            .clone()
+            .into_iter()
+            .filter(|crate_cfg| !cfg.cfg_blocklist.contains(crate_cfg))
+            .collect();
+        // Any cfgs which weren't passed to cargo must have come from the build script.
+        let build_script_cfgs: Vec<&str> = cfgs
+            .iter()
+            .filter(|crate_cfg| !cfg.extra_cfg.contains(crate_cfg))
+            .map(String::as_str)
+            .collect();
+        if !build_script_cfgs.is_empty() {
+            m.props
+                .add_comment("cfgs", &format!("From build.rs: {}", build_script_cfgs.join(", ")));
+        }
+        m.props.set_if_nonempty("cfgs", cfgs);
+
+        let mut flags = Vec::new();
+        if !crate_.cap_lints.is_empty() {
+            flags.push(crate_.cap_lints.clone());
+        }
+        flags.extend(crate_.codegens.iter().map(|codegen| format!("-C {}", codegen)));
+        m.props.set_if_nonempty("flags", flags);
+
+        let mut rust_libs = Vec::new();
+        let mut proc_macro_libs = Vec::new();
+        let mut aliases = Vec::new();
+        for extern_dep in &crate_.externs {
+            match extern_dep.extern_type {
+                ExternType::Rust => rust_libs.push(extern_dep.lib_name.clone()),
+                ExternType::ProcMacro => proc_macro_libs.push(extern_dep.lib_name.clone()),
+            }
+            if extern_dep.name != extern_dep.lib_name {
+                aliases.push(format!("{}:{}", extern_dep.lib_name, extern_dep.name));
+            }
+        }
+
+        // Add "lib" prefix and apply name overrides.
+        let process_lib_deps = |libs: Vec<String>| -> Vec<String> {
+            let mut result = Vec::new();
+            for x in libs {
+                let module_name = "lib".to_string() + x.as_str();
+                if let Some(module_name) = override_module_name(
+                    &module_name,
+                    &package_cfg.dep_blocklist,
+                    &cfg.module_name_overrides,
+                    &RENAME_MAP,
+                ) {
+                    result.push(module_name);
+                }
+            }
+            result.sort();
+            result.dedup();
+            result
+        };
+        m.props.set_if_nonempty("rustlibs", process_lib_deps(rust_libs));
+        m.props.set_if_nonempty("proc_macros", process_lib_deps(proc_macro_libs));
+        let (whole_static_libs, static_libs) = process_lib_deps(crate_.static_libs.clone())
+            .into_iter()
+            .partition(|static_lib| package_cfg.whole_static_libs.contains(static_lib));
+        m.props.set_if_nonempty("static_libs", static_libs);
+        m.props.set_if_nonempty("whole_static_libs", whole_static_libs);
+        m.props.set_if_nonempty("shared_libs", process_lib_deps(crate_.shared_libs.clone()));
+        m.props.set_if_nonempty("aliases", aliases);
+
+        if package_cfg.device_supported {
+            if !crate_type.is_test() {
+                if cfg.native_bridge_supported {
+                    m.props.set("native_bridge_supported", true);
+                }
+                if cfg.product_available {
+                    m.props.set("product_available", true);
+                }
+                if cfg.ramdisk_available {
+                    m.props.set("ramdisk_available", true);
+                }
+                if cfg.recovery_available {
+                    m.props.set("recovery_available", true);
+                }
+                if cfg.vendor_available {
+                    m.props.set("vendor_available", true);
+                }
+                if cfg.vendor_ramdisk_available {
+                    m.props.set("vendor_ramdisk_available", true);
+                }
+            }
+            if crate_type.is_library() {
+                m.props.set_if_nonempty("apex_available", cfg.apex_available.clone());
+                if let Some(min_sdk_version) = &cfg.min_sdk_version {
+                    m.props.set("min_sdk_version", min_sdk_version.clone());
+                }
+            }
+        }
+        if crate_type.is_test() {
+            if let Some(data) =
+                package_cfg.test_data.get(crate_.main_src.to_string_lossy().as_ref())
+            {
+                m.props.set("data", data.clone());
+            }
+        } else if package_cfg.no_std {
+            m.props.set("prefer_rlib", true);
+            m.props.set("no_stdlibs", true);
+            let mut stdlibs = vec!["libcompiler_builtins.rust_sysroot", "libcore.rust_sysroot"];
+            if package_cfg.alloc {
+                stdlibs.push("liballoc.rust_sysroot");
+            }
+            stdlibs.sort();
+            m.props.set("stdlibs", stdlibs);
+        }
+
+        if let Some(visibility) = cfg.module_visibility.get(&module_name) {
+            m.props.set("visibility", visibility.clone());
+        }
+
+        if let Some(path) = &package_cfg.add_module_block {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {path:?}"))?;
+            m.props.raw_block = Some(content);
+        }
+
+        modules.push(m);
+    }
+
+    for cc_lib in &crate_.cc_libs {
+        modules.extend(cc_library_to_bp_module(cc_lib, cfg, package_cfg));
+    }
+    Ok(modules)
+}
+
+/// Convert a C library built by a build script into a `cc_library_static` module, or `None` if it
+/// is blocked by the config.
+///
+/// The module name matches the name used for the library in the `static_libs` of the Rust modules
+/// which link against it.
+fn cc_library_to_bp_module(
+    cc_lib: &CcLibrary,
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+) -> Option<BpModule> {
+    let module_name = override_module_name(
+        &format!("lib{}", cc_lib.name),
+        &cfg.module_blocklist,
+        &cfg.module_name_overrides,
+        &RENAME_MAP,
+    )?;
+    let module_type =
+        if package_cfg.device_supported { "cc_library_static" } else { "cc_library_host_static" };
+    let mut m = BpModule::new(module_type.to_string());
+    m.props.set("name", module_name.clone());
+    if package_cfg.host_supported && package_cfg.device_supported {
+        m.props.set("host_supported", true);
+    }
+    m.props.set_if_nonempty("srcs", cc_lib.srcs.clone());
+    m.props.set_if_nonempty("local_include_dirs", cc_lib.include_dirs.clone());
+    m.props.set_if_nonempty(
+        "cflags",
+        cc_lib
+            .defines
+            .iter()
+            .map(|define| format!("-D{define}"))
+            .chain(cc_lib.flags.iter().cloned())
+            .collect(),
+    );
+    if package_cfg.device_supported {
+        if cfg.product_available {
+            m.props.set("product_available", true);
+        }
+        if cfg.vendor_available {
+            m.props.set("vendor_available", true);
+        }
+        m.props.set_if_nonempty("apex_available", cfg.apex_available.clone());
+        if let Some(min_sdk_version) = &cfg.min_sdk_version {
+            m.props.set("min_sdk_version", min_sdk_version.clone());
+        }
+    }
+    if let Some(visibility) = cfg.module_visibility.get(&module_name) {
+        m.props.set("visibility", visibility.clone());
+    }
+    Some(m)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo::Extern;
+    use crate::config::Config;
+    use crate::tests::testdata_directories;
+    use crate::{group_by_package, out_dir_name};
+    use googletest::matchers::eq;
+    use googletest::prelude::assert_that;
+    use std::env::{current_dir, set_current_dir};
+    use std::fs::{write, File};
+
+    #[test]
+    fn generate_bp() {
+        for testdata_directory_path in testdata_directories() {
+            let cfg = Config::from_json_str(
+                &read_to_string(testdata_directory_path.join("cargo_embargo.json"))
+                    .expect("Failed to open cargo_embargo.json"),
+                None,
+            )
+            .unwrap();
+            let mut crates: Vec<Vec<Crate>> = serde_json::from_reader(
+                File::open(testdata_directory_path.join("crates.json"))
+                    .expect("Failed to open crates.json"),
+            )
+            .unwrap();
+            // Look for license files in the testdata directory rather than the original package.
+            let package_dir = testdata_directory_path.canonicalize().unwrap();
+            for c in crates.iter_mut().flatten() {
+                c.package_dir = package_dir.clone();
+            }
+            let expected_output =
+                read_to_string(testdata_directory_path.join("expected_Android.bp")).unwrap();
+
+            let old_current_dir = current_dir().unwrap();
+            set_current_dir(&testdata_directory_path).unwrap();
+
+            let module_by_package = group_by_package(crates);
+            assert_eq!(module_by_package.len(), 1);
+            let crates = module_by_package.into_values().next().unwrap();
+
+            let package_name = &crates[0][0].package_name;
+            let def = PackageConfig::default();
+            let package_cfg = cfg.package.get(package_name).unwrap_or(&def);
+            let mut output = generate_android_bp_package_header(
+                generate_license_modules(package_name, package_cfg, &crates)
+                    .unwrap()
+                    .map(|l| l.modules),
+                "",
+            )
+            .unwrap();
+            for (variant_index, variant_cfg) in cfg.variants.iter().enumerate() {
+                let variant_crates = &crates[variant_index];
+                let package_name = &variant_crates[0].package_name;
+                let def = PackageVariantConfig::default();
+                let package_variant_cfg = variant_cfg.package.get(package_name).unwrap_or(&def);
+
+                output += &generate_android_bp(
+                    variant_cfg,
+                    package_variant_cfg,
+                    package_name,
+                    variant_crates,
+                    &Vec::new(),
+                    &out_dir_name(variant_index),
+                )
+                .unwrap();
+            }
+
+            assert_that!(output, eq(expected_output));
+
+            set_current_dir(old_current_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn crate_to_bp_empty() {
+        let c = Crate {
+            name: "name".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![],
+            ..Default::default()
+        };
+        let cfg = VariantConfig { ..Default::default() };
+        let package_cfg = PackageVariantConfig { ..Default::default() };
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+
+        assert_eq!(modules, vec![]);
+    }
+
+    #[test]
+    fn crate_to_bp_minimal() {
+        let c = Crate {
+            name: "name".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            ..Default::default()
+        };
+        let cfg = VariantConfig { ..Default::default() };
+        let package_cfg = PackageVariantConfig { ..Default::default() };
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+
+        assert_eq!(
+            modules,
+            vec![BpModule {
+                module_type: "rust_library".to_string(),
+                props: BpProperties {
+                    map: [
+                        (
+                            "apex_available".to_string(),
+                            BpValue::List(vec![
+                                BpValue::String("//apex_available:platform".to_string()),
+                                BpValue::String("//apex_available:anyapex".to_string()),
+                            ])
+                        ),
+                        ("cargo_env_compat".to_string(), BpValue::Bool(true)),
+                        ("crate_name".to_string(), BpValue::String("name".to_string())),
+                        ("edition".to_string(), BpValue::String("2021".to_string())),
+                        ("host_supported".to_string(), BpValue::Bool(true)),
+                        ("name".to_string(), BpValue::String("libname".to_string())),
+                        ("product_available".to_string(), BpValue::Bool(true)),
+                        ("crate_root".to_string(), BpValue::String("".to_string())),
+                        ("vendor_available".to_string(), BpValue::Bool(true)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                    raw_block: None,
+                    ..BpProperties::new()
+                },
+                comments: Vec::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn crate_to_bp_rename() {
+        let c = Crate {
+            name: "ash".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            ..Default::default()
+        };
+        let cfg = VariantConfig { ..Default::default() };
+        let package_cfg = PackageVariantConfig { ..Default::default() };
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+
+        assert_eq!(
+            modules,
+            vec![BpModule {
+                module_type: "rust_library".to_string(),
+                props: BpProperties {
+                    map: [
+                        (
+                            "apex_available".to_string(),
+                            BpValue::List(vec![
+                                BpValue::String("//apex_available:platform".to_string()),
+                                BpValue::String("//apex_available:anyapex".to_string()),
+                            ])
+                        ),
+                        ("cargo_env_compat".to_string(), BpValue::Bool(true)),
+                        ("crate_name".to_string(), BpValue::String("ash".to_string())),
+                        ("edition".to_string(), BpValue::String("2021".to_string())),
+                        ("host_supported".to_string(), BpValue::Bool(true)),
+                        ("name".to_string(), BpValue::String("libash_rust".to_string())),
+                        ("product_available".to_string(), BpValue::Bool(true)),
+                        ("crate_root".to_string(), BpValue::String("".to_string())),
+                        ("vendor_available".to_string(), BpValue::Bool(true)),
+                    ]
+                    .into_iter()
+                    .collect(),
+                    raw_block: None,
+                    ..BpProperties::new()
+                },
+                comments: Vec::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn crate_to_bp_test_no_harness() {
+        let c = Crate {
+            name: "name".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::TestNoHarness],
+            main_src: "tests/custom.rs".into(),
+            ..Default::default()
+        };
+        let cfg = VariantConfig { ..Default::default() };
+        let package_cfg = PackageVariantConfig { ..Default::default() };
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].module_type, "rust_test");
+        assert_eq!(modules[0].props.get_string("name"), Some("package_name_test_tests_custom"));
+        assert_eq!(modules[0].props.map.get("test_harness"), Some(&BpValue::Bool(false)));
+    }
+
+    #[test]
+    fn crate_to_bp_bench() {
+        let c = Crate {
+            name: "name".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Bench],
+            main_src: "benches/speed.rs".into(),
+            ..Default::default()
+        };
+        let cfg = VariantConfig { ..Default::default() };
+
+        let package_cfg = PackageVariantConfig { ..Default::default() };
+        assert_eq!(crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap(), vec![]);
+
+        let package_cfg = PackageVariantConfig { benches: true, ..Default::default() };
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].module_type, "rust_benchmark");
+        assert_eq!(modules[0].props.get_string("name"), Some("package_name_bench_benches_speed"));
+        assert_eq!(modules[0].props.map.get("test_options"), None);
+    }
+
+    #[test]
+    fn crate_to_bp_example() {
+        let c = Crate {
+            name: "echo_server".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Example],
+            main_src: "examples/echo_server.rs".into(),
+            ..Default::default()
+        };
+        let cfg = VariantConfig { ..Default::default() };
+        let package_cfg = PackageVariantConfig { device_supported: false, ..Default::default() };
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].module_type, "rust_binary_host");
+        assert_eq!(modules[0].props.get_string("name"), Some("package_name_example_echo_server"));
+        assert_eq!(modules[0].props.get_string("crate_name"), Some("echo_server"));
+    }
+
+    #[test]
+    fn crate_to_bp_rustc_warnings() {
+        let c = Crate {
+            name: "name".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            main_src: "src/lib.rs".into(),
+            warnings: vec!["unused variable: `x` (src/lib.rs:1:5)".to_string()],
+            ..Default::default()
+        };
+        let package_cfg = PackageVariantConfig { ..Default::default() };
+
+        // Modules are only marked if the variant limits its warnings.
+        let modules =
+            crate_to_bp_modules(&c, &VariantConfig::default(), &package_cfg, &[]).unwrap();
+        assert_eq!(modules.len(), 1);
+        assert!(modules[0].comments.is_empty());
+
+        let cfg = VariantConfig { max_rustc_warnings: Some(1), ..Default::default() };
+        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].comments, vec!["// has rustc warnings".to_string()]);
+    }
+
+    #[test]
+    fn generate_android_bp_multiple_targets() {
+        let extern_dep = |name: &str| Extern {
+            name: name.to_string(),
+            lib_name: name.to_string(),
+            raw_name: name.to_string(),
+            extern_type: ExternType::Rust,
+        };
+        let crate_for = |target: &str, externs: &[&str]| Crate {
+            name: "name".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            target: Some(target.to_string()),
+            externs: externs.iter().map(|name| extern_dep(name)).collect(),
+            ..Default::default()
+        };
+        let cfg = VariantConfig {
+            targets: vec![
+                "aarch64-linux-android".to_string(),
+                "x86_64-linux-android".to_string(),
+                "x86_64-unknown-linux-gnu".to_string(),
+                "x86_64-unknown-linux-musl".to_string(),
+            ],
+            ..Default::default()
+        };
+        let package_cfg = PackageVariantConfig::default();
+
+        let crates = [
+            crate_for("aarch64-linux-android", &["libc", "android_only"]),
+            crate_for("x86_64-linux-android", &["libc", "android_only"]),
+            crate_for("x86_64-unknown-linux-gnu", &["libc"]),
+        ];
+        let bp =
+            generate_android_bp(&cfg, &package_cfg, "package_name", &crates, &[], "out").unwrap();
+        assert_eq!(
+            bp,
+            r#"rust_library {
+    name: "libname",
+    host_supported: true,
+    crate_name: "name",
+    cargo_env_compat: true,
+    crate_root: "",
+    edition: "2021",
+    rustlibs: ["liblibc"],
+    target: {
+        android: {
+            rustlibs: ["libandroid_only"],
+        },
+        linux_musl_x86_64: {
+            enabled: false,
+        },
+    },
+    apex_available: [
+        "//apex_available:platform",
+        "//apex_available:anyapex",
+    ],
+    product_available: true,
+    vendor_available: true,
+}
+
+"#
+        );
+    }
+
+    #[test]
+    fn generate_android_bp_multiple_targets_build_script_cfgs() {
+        let crate_for = |target: &str, cfgs: &[&str]| Crate {
+            name: "name".to_string(),
+            package_name: "package_name".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            target: Some(target.to_string()),
+            cfgs: cfgs.iter().map(|cfg| cfg.to_string()).collect(),
+            ..Default::default()
+        };
+        let cfg = VariantConfig {
+            targets: vec![
+                "aarch64-linux-android".to_string(),
+                "x86_64-unknown-linux-gnu".to_string(),
+            ],
+            ..Default::default()
+        };
+        let package_cfg = PackageVariantConfig::default();
+
+        let crates = [
+            crate_for("aarch64-linux-android", &["has_atomics"]),
+            crate_for("x86_64-unknown-linux-gnu", &[]),
+        ];
+        let bp =
+            generate_android_bp(&cfg, &package_cfg, "package_name", &crates, &[], "out").unwrap();
+        assert_eq!(
+            bp,
+            r#"rust_library {
+    name: "libname",
+    host_supported: true,
+    crate_name: "name",
+    cargo_env_compat: true,
+    crate_root: "",
+    edition: "2021",
+    target: {
+        android: {
+            // From build.rs: has_atomics
+            cfgs: ["has_atomics"],
+        },
+    },
+    apex_available: [
+        "//apex_available:platform",
+        "//apex_available:anyapex",
+    ],
+    product_available: true,
+    vendor_available: true,
+}
+
+"#
+        );
+    }
+
+    #[test]
+    fn copy_out_genrule_per_variant() {
+        let cfg = VariantConfig::default();
+        let package_cfg = PackageVariantConfig { copy_out: true, ..Default::default() };
+        let out_files = [PathBuf::from("/tmp/target.tmp/debug/build/foo-1234/out/generated.rs")];
+
+        let bp = generate_android_bp(&cfg, &package_cfg, "foo", &[], &out_files, &out_dir_name(1))
+            .unwrap();
+        assert_eq!(
+            bp,
+            r#"genrule {
+    name: "copy_foo_build_out_1",
+    srcs: ["out_1/*"],
+    cmd: "cp $(in) $(genDir)",
+    out: ["generated.rs"],
+}
+
+"#
+        );
+    }
+
+    #[test]
+    fn generate_license_modules_per_license() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        write(dir.path().join("LICENSE-APACHE"), "")?;
+        write(dir.path().join("LICENSE-MIT"), "")?;
+        write(dir.path().join("COPYING"), "")?;
+        let c = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            package_dir: dir.path().to_owned(),
+            license: Some("MIT/Apache-2.0".to_string()),
+            ..Default::default()
+        };
+        let crates = vec![vec![
+            c.clone(),
+            Crate {
+                name: "bar".to_string(),
+                license: Some("(Apache-2.0 OR MIT) AND Unicode-DFS-2016".to_string()),
+                ..c.clone()
+            },
+            Crate { name: "baz".to_string(), license: Some("Apache-2.0 OR MIT".to_string()), ..c },
+        ]];
+
+        let license_modules =
+            generate_license_modules("foo", &PackageConfig::default(), &crates)?.unwrap();
+
+        let mut output = String::new();
+        for m in &license_modules.modules {
+            m.write(&mut output)?;
+        }
+        assert_eq!(
+            output,
+            r#"package {
+    default_applicable_licenses: ["external_rust_crates_foo_Apache-2.0_license"],
+    default_team: "trendy_team_android_rust",
+}
+license {
+    name: "external_rust_crates_foo_Apache-2.0_license",
+    visibility: [":__subpackages__"],
+    license_kinds: ["SPDX-license-identifier-Apache-2.0"],
+    license_text: ["LICENSE-APACHE"],
+}
+license {
+    name: "external_rust_crates_foo_Apache-2.0_Unicode-DFS-2016_license",
+    visibility: [":__subpackages__"],
+    license_kinds: [
+        "SPDX-license-identifier-Apache-2.0",
+        "SPDX-license-identifier-Unicode-DFS-2016",
+    ],
+    license_text: [
+        "LICENSE-APACHE",
+        "COPYING",
+    ],
+}
+"#
+        );
+
+        // Only the modules of the crate with different licenses get their own.
+        let mut file = bp::parser::parse(
+            r#"rust_library {
+    name: "libfoo",
+    crate_name: "foo",
+}
+rust_library {
+    name: "libbar",
+    crate_name: "bar",
+}
+genrule {
+    name: "copy_foo_build_out",
+}
+"#,
+        )?;
+        set_crate_licenses(&mut file, &license_modules.crate_licenses);
+        let licenses: Vec<Option<&BpValue>> =
+            file.modules().map(|m| m.props.map.get("licenses")).collect();
+        assert_eq!(
+            licenses,
+            vec![
+                None,
+                Some(&BpValue::List(vec![BpValue::String(
+                    "external_rust_crates_foo_Apache-2.0_Unicode-DFS-2016_license".to_string()
+                )])),
+                None,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn generate_license_modules_missing_license_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        write(dir.path().join("LICENSE-APACHE"), "")?;
+        let c = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            package_dir: dir.path().to_owned(),
+            license: Some("MIT".to_string()),
+            ..Default::default()
+        };
+
+        assert!(
+            generate_license_modules("foo", &PackageConfig::default(), &[vec![c.clone()]]).is_err()
+        );
+
+        let package_cfg =
+            PackageConfig { license_text: Some("LICENSE.txt".to_string()), ..Default::default() };
+        let license_modules = generate_license_modules("foo", &package_cfg, &[vec![c]])?.unwrap();
+        assert_eq!(
+            license_modules.modules[1].props.map.get("license_text"),
+            Some(&BpValue::List(vec![BpValue::String("LICENSE.txt".to_string())]))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn preserve_hand_written_definitions() {
+        let existing = bp::parser::parse(
+            r#"// This file is generated by cargo_embargo.
+// Do not modify this file after the first "rust_*" or "genrule" module
+// because the changes will be overridden on upgrade.
+
+license {
+    name: "foo_license",
+}
+
+rust_library {
+    name: "libfoo",
+    srcs: ["src/lib.rs"],
+}
+
+rust_test {
+    name: "foo_test_removed",
+}
+
+// Hand-written.
+cc_library_static {
+    name: "libfoo_ffi",
+}
+"#,
+        )
+        .unwrap();
+        let generated = bp::parser::parse(
+            r#"rust_library {
+    name: "libfoo",
+}
+"#,
+        )
+        .unwrap();
+
+        let preserved = preserved_definitions(&existing, &generated);
+        assert_eq!(
+            preserved.header,
+            existing.definitions[1..2].to_vec(),
+            "only the license module should be in the header"
+        );
+        assert_eq!(preserved.trailing, existing.definitions[4..].to_vec());
+
+        let mut extra = Vec::new();
+        let existing_module = existing.modules().nth(1).unwrap();
+        find_extra_properties(
+            &existing_module.props,
+            &generated.modules().next().unwrap().props,
+            "",
+            &mut extra,
+        );
+        assert_eq!(extra, vec!["srcs".to_string()]);
+    }
+
+    #[test]
+    fn merge_select_variants_features() {
+        let module = |features: Vec<&str>, rustlibs: Vec<&str>| {
+            let mut m = BpModule::new("rust_library".to_string());
+            m.props.set("name", "libfoo");
+            m.props.set_if_nonempty("features", features);
+            m.props.set("rustlibs", rustlibs);
+            m
+        };
+        let std = SelectValue::Bool(true);
+        let variants = vec![
+            (None, vec![module(vec![], vec!["libbar"])]),
+            (Some(&std), vec![module(vec!["std"], vec!["libbar", "libstd_only"])]),
+        ];
+
+        let modules = merge_select_variants(
+            &SelectCondition::ReleaseFlag("RELEASE_FOO_STD".to_string()),
+            &variants,
+        )
+        .unwrap();
+        let mut output = String::new();
+        for m in modules {
+            m.write(&mut output).unwrap();
+        }
+        assert_eq!(
+            output,
+            r#"rust_library {
+    name: "libfoo",
+    features: select(release_flag("RELEASE_FOO_STD"), {
+        true: ["std"],
+        default: [],
+    }),
+    rustlibs: ["libbar"] + select(release_flag("RELEASE_FOO_STD"), {
+        true: ["libstd_only"],
+        default: [],
+    }),
+}
+"#
+        );
+
+        let mut other = module(vec![], vec!["libbar"]);
+        other.props.set("edition", "2018");
+        let variants = vec![(None, vec![module(vec![], vec![])]), (Some(&std), vec![other])];
+        assert!(merge_select_variants(&SelectCondition::ReleaseFlag("F".to_string()), &variants)
+            .is_err());
+    }
+
+    #[test]
+    fn generate_android_bp_select_copy_out() {
+        let package_cfg = PackageVariantConfig { copy_out: true, ..Default::default() };
+        let std = SelectValue::Bool(true);
+        let variants: Vec<_> = [(None, vec![]), (Some(&std), vec!["std".to_string()])]
+            .into_iter()
+            .enumerate()
+            .map(|(variant_index, (value, features))| {
+                let cfg = VariantConfig { features: Some(features.clone()), ..Default::default() };
+                let crates = [Crate {
+                    name: "foo".to_string(),
+                    package_name: "foo".to_string(),
+                    edition: "2021".to_string(),
+                    types: vec![CrateType::Lib],
+                    main_src: "src/lib.rs".into(),
+                    features,
+                    ..Default::default()
+                }];
+                let out_files = [PathBuf::from(format!(
+                    "/tmp/variant_{variant_index}/target.tmp/debug/build/foo-1234/out/generated.rs"
+                ))];
+                let modules = generate_android_bp_modules(
+                    &cfg,
+                    &package_cfg,
+                    "foo",
+                    &crates,
+                    &out_files,
+                    &out_dir_name(variant_index),
+                )
+                .unwrap();
+                (value, modules)
+            })
+            .collect();
+
+        let modules = merge_select_variants(
+            &SelectCondition::ReleaseFlag("RELEASE_FOO_STD".to_string()),
+            &variants,
+        )
+        .unwrap();
+        let mut output = String::new();
+        for m in modules {
+            m.write(&mut output).unwrap();
+        }
+        assert_eq!(
+            output,
+            r#"genrule {
+    name: "copy_foo_build_out",
+    srcs: ["out/*"],
+    cmd: "cp $(in) $(genDir)",
+    out: ["generated.rs"],
+}
+genrule {
+    name: "copy_foo_build_out_1",
+    srcs: ["out_1/*"],
+    cmd: "cp $(in) $(genDir)",
+    out: ["generated.rs"],
+}
+rust_library {
+    name: "libfoo",
+    host_supported: true,
+    crate_name: "foo",
+    cargo_env_compat: true,
+    crate_root: "src/lib.rs",
+    srcs: select(release_flag("RELEASE_FOO_STD"), {
+        true: [":copy_foo_build_out_1"],
+        default: [":copy_foo_build_out"],
+    }),
+    edition: "2021",
+    features: select(release_flag("RELEASE_FOO_STD"), {
+        true: ["std"],
+        default: [],
+    }),
+    apex_available: [
+        "//apex_available:platform",
+        "//apex_available:anyapex",
+    ],
+    product_available: true,
+    vendor_available: true,
+}
+"#
+        );
+    }
+
+    #[test]
+    fn merge_select_variants_build_script_cfgs() {
+        let module = |cfgs: Vec<&str>| {
+            let mut m = BpModule::new("rust_library".to_string());
+            m.props.set("name", "libfoo");
+            if !cfgs.is_empty() {
+                m.props.add_comment("cfgs", &format!("From build.rs: {}", cfgs.join(", ")));
+            }
+            m.props.set_if_nonempty("cfgs", cfgs);
+            m
+        };
+        let std = SelectValue::Bool(true);
+        let variants =
+            vec![(None, vec![module(vec![])]), (Some(&std), vec![module(vec!["has_std"])])];
+
+        let modules = merge_select_variants(
+            &SelectCondition::ReleaseFlag("RELEASE_FOO_STD".to_string()),
+            &variants,
+        )
+        .unwrap();
+        let mut output = String::new();
+        for m in modules {
+            m.write(&mut output).unwrap();
+        }
+        assert_eq!(
+            output,
+            r#"rust_library {
+    name: "libfoo",
+    // From build.rs: has_std
+    cfgs: select(release_flag("RELEASE_FOO_STD"), {
+        true: ["has_std"],
+        default: [],
+    }),
+}
+"#
+        );
+    }
+
+    #[test]
+    fn soong_target_from_triple() {
+        assert_eq!(
+            SoongTarget::from_triple("aarch64-linux-android").unwrap(),
+            SoongTarget { os: "android", arch: "arm64" }
+        );
+        assert_eq!(
+            SoongTarget::from_triple("armv7-linux-androideabi").unwrap(),
+            SoongTarget { os: "android", arch: "arm" }
+        );
+        assert_eq!(
+            SoongTarget::from_triple("x86_64-unknown-linux-musl").unwrap(),
+            SoongTarget { os: "linux_musl", arch: "x86_64" }
+        );
+        assert!(SoongTarget::from_triple("wasm32-unknown-unknown").is_err());
+    }
+}
@@ -0,0 +1,392 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend to generate `BUILD.bazel` files using the rules from
+//! [rules_rust](https://github.com/bazelbuild/rules_rust).
+//!
+//! Only the crates for the first of a variant's `targets` are used, as per-target differences
+//! aren't supported.
+
+use super::{
+    check_unique_name, first_target_crates, local_libraries, rustc_flags, target_name, Backend,
+    Package, Variant,
+};
+use crate::cargo::{Crate, CrateType, ExternType};
+use crate::config::VariantConfig;
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+use std::path::PathBuf;
+
+/// The value of an attribute of a Bazel rule.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Attr {
+    Bool(bool),
+    String(String),
+    List(Vec<String>),
+    Dict(Vec<(String, String)>),
+    /// A Starlark expression, written as is.
+    Expr(String),
+}
+
+/// A rule in a BUILD file, such as a `rust_library`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Rule {
+    kind: &'static str,
+    name: String,
+    attrs: Vec<(&'static str, Attr)>,
+}
+
+impl Rule {
+    fn new(kind: &'static str, name: String) -> Self {
+        Self { kind, name, attrs: Vec::new() }
+    }
+
+    fn set(&mut self, name: &'static str, value: Attr) {
+        self.attrs.push((name, value));
+    }
+
+    fn set_list_if_nonempty(&mut self, name: &'static str, values: Vec<String>) {
+        if !values.is_empty() {
+            self.set(name, Attr::List(values));
+        }
+    }
+
+    fn write(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(w, "{}(", self.kind)?;
+        writeln!(w, "    name = {},", quote(&self.name))?;
+        for (name, value) in &self.attrs {
+            write!(w, "    {name} = ")?;
+            match value {
+                Attr::Bool(value) => write!(w, "{}", if *value { "True" } else { "False" })?,
+                Attr::String(value) => write!(w, "{}", quote(value))?,
+                Attr::List(values) if values.len() == 1 => write!(w, "[{}]", quote(&values[0]))?,
+                Attr::List(values) => {
+                    writeln!(w, "[")?;
+                    for value in values {
+                        writeln!(w, "        {},", quote(value))?;
+                    }
+                    write!(w, "    ]")?;
+                }
+                Attr::Dict(entries) => {
+                    writeln!(w, "{{")?;
+                    for (key, value) in entries {
+                        writeln!(w, "        {}: {},", quote(key), quote(value))?;
+                    }
+                    write!(w, "    }}")?;
+                }
+                Attr::Expr(expr) => write!(w, "{expr}")?,
+            }
+            writeln!(w, ",")?;
+        }
+        writeln!(w, ")")
+    }
+}
+
+/// Returns the given string as a Starlark string literal.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+/// Generates a `BUILD.bazel` file for a package.
+#[derive(Debug, Default)]
+pub struct BazelBackend {
+    rules: Vec<Rule>,
+}
+
+impl<'a> Backend<'a> for BazelBackend {
+    fn enabled(&self, cfg: &VariantConfig) -> bool {
+        cfg.generate_bazel
+    }
+
+    fn add_variant(&mut self, variant: &Variant<'a>) -> Result<()> {
+        let crates = first_target_crates(variant);
+        let local_libraries = local_libraries(&crates);
+        let variant_start = self.rules.len();
+        for crate_ in crates {
+            for rule in crate_to_rules(crate_, variant, &local_libraries) {
+                let names = self.rules.iter().map(|rule| rule.name.as_str());
+                check_unique_name(names, variant_start, &rule.name, crate_, "Bazel target")?;
+                self.rules.push(rule);
+            }
+        }
+        Ok(())
+    }
+
+    fn build_files(self: Box<Self>, package: &Package) -> Result<Vec<(PathBuf, String)>> {
+        if self.rules.is_empty() {
+            return Ok(Vec::new());
+        }
+        let kinds: BTreeSet<&str> = self.rules.iter().map(|rule| rule.kind).collect();
+        let mut contents = String::new();
+        contents += "# This file is generated by cargo_embargo.\n";
+        contents +=
+            "# Do not modify this file because the changes will be overridden on upgrade.\n";
+        contents += "\n";
+        writeln!(
+            contents,
+            "load(\"@rules_rust//rust:defs.bzl\", {})",
+            kinds.iter().map(|kind| quote(kind)).collect::<Vec<_>>().join(", ")
+        )?;
+        contents += "\n";
+        contents += "package(default_visibility = [\"//visibility:public\"])\n";
+        for rule in &self.rules {
+            contents += "\n";
+            rule.write(&mut contents)?;
+        }
+        Ok(vec![(package.dir.join("BUILD.bazel"), contents)])
+    }
+}
+
+/// Returns the rules_rust rules for the given crate, one for each of its crate types.
+fn crate_to_rules(
+    crate_: &Crate,
+    variant: &Variant,
+    local_libraries: &BTreeSet<&str>,
+) -> Vec<Rule> {
+    let package_cfg = variant.package_cfg;
+    let mut rules = Vec::new();
+    for crate_type in &crate_.types {
+        let Some(name) =
+            target_name(variant, crate_, *crate_type, local_libraries, "BUILD.bazel rule")
+        else {
+            continue;
+        };
+        let kind = match crate_type {
+            CrateType::Lib | CrateType::RLib | CrateType::DyLib => "rust_library",
+            CrateType::CDyLib => "rust_shared_library",
+            CrateType::StaticLib => "rust_static_library",
+            CrateType::ProcMacro => "rust_proc_macro",
+            CrateType::Bin | CrateType::Example => "rust_binary",
+            CrateType::Test | CrateType::TestNoHarness | CrateType::Bench => "rust_test",
+        };
+
+        let mut rule = Rule::new(kind, name);
+        if rule.name != crate_.name {
+            rule.set("crate_name", Attr::String(crate_.name.clone()));
+        }
+        rule.set("srcs", Attr::Expr("glob([\"**/*.rs\"])".to_string()));
+        rule.set("crate_root", Attr::String(crate_.main_src.to_string_lossy().into_owned()));
+        rule.set("edition", Attr::String(crate_.edition.clone()));
+        rule.set_list_if_nonempty("crate_features", crate_.features.clone());
+
+        rule.set_list_if_nonempty("rustc_flags", rustc_flags(crate_, variant.cfg));
+
+        let mut deps = Vec::new();
+        let mut proc_macro_deps = Vec::new();
+        let mut aliases = Vec::new();
+        for extern_dep in &crate_.externs {
+            if package_cfg.dep_blocklist.contains(&format!("lib{}", extern_dep.lib_name)) {
+                continue;
+            }
+            let label = if local_libraries.contains(extern_dep.lib_name.as_str()) {
+                format!(":{}", extern_dep.lib_name)
+            } else {
+                format!("{}{}", variant.cfg.bazel_dep_prefix, extern_dep.raw_name)
+            };
+            if extern_dep.name != extern_dep.lib_name {
+                aliases.push((label.clone(), extern_dep.name.clone()));
+            }
+            match extern_dep.extern_type {
+                ExternType::Rust => deps.push(label),
+                ExternType::ProcMacro => proc_macro_deps.push(label),
+            }
+        }
+        deps.sort();
+        deps.dedup();
+        proc_macro_deps.sort();
+        proc_macro_deps.dedup();
+        aliases.sort();
+        rule.set_list_if_nonempty("deps", deps);
+        rule.set_list_if_nonempty("proc_macro_deps", proc_macro_deps);
+        if !aliases.is_empty() {
+            rule.set("aliases", Attr::Dict(aliases));
+        }
+
+        if package_cfg.copy_out && !variant.out_files.is_empty() {
+            let out_dir = &variant.out_dir;
+            rule.set("compile_data", Attr::Expr(format!("glob([\"{out_dir}/*\"])")));
+            rule.set(
+                "rustc_env",
+                Attr::Expr(format!(
+                    "{{\"OUT_DIR\": \"${{pwd}}/\" + package_name() + \"/{out_dir}\"}}"
+                )),
+            );
+        }
+
+        if crate_type.is_test() {
+            if *crate_type == CrateType::TestNoHarness {
+                rule.set("use_libtest_harness", Attr::Bool(false));
+            }
+            if let Some(data) =
+                package_cfg.test_data.get(crate_.main_src.to_string_lossy().as_ref())
+            {
+                rule.set_list_if_nonempty("data", data.clone());
+            }
+        }
+
+        rules.push(rule);
+    }
+    rules
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo::Extern;
+    use crate::config::{PackageConfig, PackageVariantConfig};
+    use std::path::Path;
+
+    #[test]
+    fn generate_build_bazel() {
+        let extern_dep = |name: &str, raw_name: &str, extern_type| Extern {
+            name: name.to_string(),
+            lib_name: name.to_string(),
+            raw_name: raw_name.to_string(),
+            extern_type,
+        };
+        let library = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            types: vec![CrateType::Lib],
+            features: vec!["default".to_string(), "std".to_string()],
+            cfgs: vec!["has_bar".to_string()],
+            externs: vec![
+                extern_dep("bar_baz", "bar-baz", ExternType::Rust),
+                extern_dep("foo_derive", "foo-derive", ExternType::ProcMacro),
+            ],
+            edition: "2021".to_string(),
+            main_src: "src/lib.rs".into(),
+            ..Default::default()
+        };
+        let test = Crate {
+            name: "integration".to_string(),
+            types: vec![CrateType::Test],
+            externs: vec![extern_dep("foo", "foo", ExternType::Rust)],
+            main_src: "tests/integration.rs".into(),
+            ..library.clone()
+        };
+        let cfg = VariantConfig::default();
+        let package_cfg = PackageVariantConfig::default();
+        let crates = [library, test];
+        let variant = Variant {
+            cfg: &cfg,
+            package_cfg: &package_cfg,
+            package_name: "foo",
+            crates: &crates,
+            out_files: &[],
+            out_dir: "out".to_string(),
+        };
+
+        let mut backend = Box::<BazelBackend>::default();
+        assert!(!backend.enabled(&cfg));
+        backend.add_variant(&variant).unwrap();
+        let package = Package {
+            name: "foo",
+            dir: Path::new("external/rust/crates/foo"),
+            cfg: &PackageConfig::default(),
+            crates: &[],
+            write_rejects: false,
+        };
+        let build_files = backend.build_files(&package).unwrap();
+
+        assert_eq!(
+            build_files,
+            vec![(
+                PathBuf::from("external/rust/crates/foo/BUILD.bazel"),
+                r#"# This file is generated by cargo_embargo.
+# Do not modify this file because the changes will be overridden on upgrade.
+
+load("@rules_rust//rust:defs.bzl", "rust_library", "rust_test")
+
+package(default_visibility = ["//visibility:public"])
+
+rust_library(
+    name = "foo",
+    srcs = glob(["**/*.rs"]),
+    crate_root = "src/lib.rs",
+    edition = "2021",
+    crate_features = [
+        "default",
+        "std",
+    ],
+    rustc_flags = ["--cfg=has_bar"],
+    deps = ["@crates//:bar-baz"],
+    proc_macro_deps = ["@crates//:foo-derive"],
+)
+
+rust_test(
+    name = "foo_test_tests_integration",
+    crate_name = "integration",
+    srcs = glob(["**/*.rs"]),
+    crate_root = "tests/integration.rs",
+    edition = "2021",
+    crate_features = [
+        "default",
+        "std",
+    ],
+    rustc_flags = ["--cfg=has_bar"],
+    deps = [":foo"],
+)
+"#
+                .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn generate_build_bazel_library_and_binary() {
+        let library = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            types: vec![CrateType::Lib],
+            edition: "2021".to_string(),
+            main_src: "src/lib.rs".into(),
+            ..Default::default()
+        };
+        let binary = Crate {
+            types: vec![CrateType::Bin],
+            externs: vec![Extern {
+                name: "foo".to_string(),
+                lib_name: "foo".to_string(),
+                raw_name: "foo".to_string(),
+                extern_type: ExternType::Rust,
+            }],
+            main_src: "src/main.rs".into(),
+            ..library.clone()
+        };
+        let cfg = VariantConfig::default();
+        let package_cfg = PackageVariantConfig::default();
+        let crates = [library, binary];
+        let variant = Variant {
+            cfg: &cfg,
+            package_cfg: &package_cfg,
+            package_name: "foo",
+            crates: &crates,
+            out_files: &[],
+            out_dir: "out".to_string(),
+        };
+
+        let mut backend = BazelBackend::default();
+        backend.add_variant(&variant).unwrap();
+        let names: Vec<&str> = backend.rules.iter().map(|rule| rule.name.as_str()).collect();
+        assert_eq!(names, vec!["foo", "foo_bin"]);
+
+        // The same crates again from another variant conflict.
+        assert_eq!(
+            backend.add_variant(&variant).unwrap_err().to_string(),
+            "Duplicate Bazel target \"foo\" from more than one variant of the package"
+        );
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend to generate `BUILD.gn` files using GN's built-in Rust target types.
+//!
+//! As with the Bazel backend, only the crates for the first of a variant's `targets` are used.
+
+use super::{
+    check_unique_name, first_target_crates, local_libraries, rustc_flags, target_name, Backend,
+    Package, Variant,
+};
+use crate::cargo::{Crate, CrateType};
+use crate::config::VariantConfig;
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+use std::path::PathBuf;
+
+/// The value of a variable in a GN target.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Value {
+    Bool(bool),
+    String(String),
+    List(Vec<String>),
+    /// A scope of string variables, such as `aliased_deps`.
+    Scope(Vec<(String, String)>),
+    /// A GN expression, written as is.
+    Expr(String),
+}
+
+/// A target in a BUILD.gn file, such as a `rust_library`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Target {
+    kind: &'static str,
+    name: String,
+    vars: Vec<(&'static str, Value)>,
+}
+
+impl Target {
+    fn new(kind: &'static str, name: String) -> Self {
+        Self { kind, name, vars: Vec::new() }
+    }
+
+    fn set(&mut self, name: &'static str, value: Value) {
+        self.vars.push((name, value));
+    }
+
+    fn set_list_if_nonempty(&mut self, name: &'static str, values: Vec<String>) {
+        if !values.is_empty() {
+            self.set(name, Value::List(values));
+        }
+    }
+
+    /// Writes the target in the style of `gn format`.
+    fn write(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(w, "{}({}) {{", self.kind, quote(&self.name))?;
+        for (name, value) in &self.vars {
+            write!(w, "  {name} = ")?;
+            match value {
+                Value::Bool(value) => writeln!(w, "{value}")?,
+                Value::String(value) => writeln!(w, "{}", quote(value))?,
+                Value::List(values) if values.len() == 1 => {
+                    writeln!(w, "[ {} ]", quote(&values[0]))?
+                }
+                Value::List(values) => {
+                    writeln!(w, "[")?;
+                    for value in values {
+                        writeln!(w, "    {},", quote(value))?;
+                    }
+                    writeln!(w, "  ]")?;
+                }
+                Value::Scope(entries) => {
+                    writeln!(w, "{{")?;
+                    for (key, value) in entries {
+                        writeln!(w, "    {key} = {}", quote(value))?;
+                    }
+                    writeln!(w, "  }}")?;
+                }
+                Value::Expr(expr) => writeln!(w, "{expr}")?,
+            }
+        }
+        writeln!(w, "}}")
+    }
+}
+
+/// Returns the given string as a GN string literal.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$"))
+}
+
+/// Generates a `BUILD.gn` file for a package.
+#[derive(Debug, Default)]
+pub struct GnBackend {
+    targets: Vec<Target>,
+}
+
+impl<'a> Backend<'a> for GnBackend {
+    fn enabled(&self, cfg: &VariantConfig) -> bool {
+        cfg.generate_gn
+    }
+
+    fn add_variant(&mut self, variant: &Variant<'a>) -> Result<()> {
+        let crates = first_target_crates(variant);
+        let local_libraries = local_libraries(&crates);
+        let variant_start = self.targets.len();
+        for crate_ in crates {
+            for target in crate_to_targets(crate_, variant, &local_libraries) {
+                let names = self.targets.iter().map(|target| target.name.as_str());
+                check_unique_name(names, variant_start, &target.name, crate_, "GN target")?;
+                self.targets.push(target);
+            }
+        }
+        Ok(())
+    }
+
+    fn build_files(self: Box<Self>, package: &Package) -> Result<Vec<(PathBuf, String)>> {
+        if self.targets.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut contents = String::new();
+        contents += "# This file is generated by cargo_embargo.\n";
+        contents +=
+            "# Do not modify this file because the changes will be overridden on upgrade.\n";
+        for target in &self.targets {
+            contents += "\n";
+            target.write(&mut contents)?;
+        }
+        Ok(vec![(package.dir.join("BUILD.gn"), contents)])
+    }
+}
+
+/// Returns the GN targets for the given crate, one for each of its crate types.
+fn crate_to_targets(
+    crate_: &Crate,
+    variant: &Variant,
+    local_libraries: &BTreeSet<&str>,
+) -> Vec<Target> {
+    let package_cfg = variant.package_cfg;
+    let mut targets = Vec::new();
+    for crate_type in &crate_.types {
+        let Some(name) =
+            target_name(variant, crate_, *crate_type, local_libraries, "BUILD.gn target")
+        else {
+            continue;
+        };
+        let kind = match crate_type {
+            CrateType::Lib | CrateType::RLib | CrateType::DyLib => "rust_library",
+            CrateType::CDyLib => "shared_library",
+            CrateType::StaticLib => "static_library",
+            CrateType::ProcMacro => "rust_proc_macro",
+            CrateType::Bin
+            | CrateType::Example
+            | CrateType::Test
+            | CrateType::TestNoHarness
+            | CrateType::Bench => "executable",
+        };
+
+        let mut target = Target::new(kind, name);
+        target.set("crate_name", Value::String(crate_.name.clone()));
+        match crate_type {
+            CrateType::CDyLib => target.set("crate_type", Value::String("cdylib".to_string())),
+            CrateType::StaticLib => {
+                target.set("crate_type", Value::String("staticlib".to_string()))
+            }
+            _ => {}
+        }
+        let crate_root = crate_.main_src.to_string_lossy().into_owned();
+        target.set("crate_root", Value::String(crate_root.clone()));
+        target.set("sources", Value::List(vec![crate_root]));
+        if crate_type.is_test() {
+            target.set("testonly", Value::Bool(true));
+        }
+
+        let mut rustflags = vec![format!("--edition={}", crate_.edition)];
+        if *crate_type == CrateType::Test {
+            rustflags.push("--test".to_string());
+        }
+        rustflags
+            .extend(crate_.features.iter().map(|feature| format!("--cfg=feature=\"{feature}\"")));
+        rustflags.extend(rustc_flags(crate_, variant.cfg));
+        target.set_list_if_nonempty("rustflags", rustflags);
+
+        let mut deps = Vec::new();
+        let mut aliased_deps = Vec::new();
+        for extern_dep in &crate_.externs {
+            if package_cfg.dep_blocklist.contains(&format!("lib{}", extern_dep.lib_name)) {
+                continue;
+            }
+            let label =
+                if let Some(label) = variant.cfg.gn_label_overrides.get(&extern_dep.lib_name) {
+                    label.clone()
+                } else if local_libraries.contains(extern_dep.lib_name.as_str()) {
+                    format!(":{}", extern_dep.lib_name)
+                } else {
+                    format!("../{}:{}", extern_dep.raw_name, extern_dep.lib_name)
+                };
+            if extern_dep.name != extern_dep.lib_name {
+                aliased_deps.push((extern_dep.name.clone(), label.clone()));
+            }
+            deps.push(label);
+        }
+        deps.sort();
+        deps.dedup();
+        aliased_deps.sort();
+        target.set_list_if_nonempty("deps", deps);
+        if !aliased_deps.is_empty() {
+            target.set("aliased_deps", Value::Scope(aliased_deps));
+        }
+
+        if package_cfg.copy_out && !variant.out_files.is_empty() {
+            let out_dir = &variant.out_dir;
+            let mut inputs: Vec<String> = variant
+                .out_files
+                .iter()
+                .map(|f| format!("{out_dir}/{}", f.file_name().unwrap().to_string_lossy()))
+                .collect();
+            inputs.sort();
+            target.set("inputs", Value::List(inputs));
+            target.set(
+                "rustenv",
+                Value::Expr(format!(
+                    "[ \"OUT_DIR=\" + rebase_path({}, root_build_dir) ]",
+                    quote(out_dir)
+                )),
+            );
+        }
+
+        targets.push(target);
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo::{Extern, ExternType};
+    use crate::config::{PackageConfig, PackageVariantConfig};
+    use std::path::Path;
+
+    #[test]
+    fn generate_build_gn() {
+        let extern_dep = |name: &str, lib_name: &str, raw_name: &str, extern_type| Extern {
+            name: name.to_string(),
+            lib_name: lib_name.to_string(),
+            raw_name: raw_name.to_string(),
+            extern_type,
+        };
+        let library = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            types: vec![CrateType::Lib],
+            features: vec!["std".to_string()],
+            cfgs: vec!["has_bar".to_string()],
+            externs: vec![
+                extern_dep("bar", "bar_baz", "bar-baz", ExternType::Rust),
+                extern_dep("foo_derive", "foo_derive", "foo-derive", ExternType::ProcMacro),
+                extern_dep("libc", "libc", "libc", ExternType::Rust),
+            ],
+            edition: "2021".to_string(),
+            main_src: "src/lib.rs".into(),
+            ..Default::default()
+        };
+        let test = Crate {
+            name: "integration".to_string(),
+            types: vec![CrateType::Test],
+            features: vec![],
+            cfgs: vec![],
+            externs: vec![extern_dep("foo", "foo", "foo", ExternType::Rust)],
+            main_src: "tests/integration.rs".into(),
+            ..library.clone()
+        };
+        let cfg = VariantConfig {
+            generate_gn: true,
+            gn_label_overrides: [("libc".to_string(), "//third_party/libc".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let package_cfg = PackageVariantConfig::default();
+        let crates = [library, test];
+        let variant = Variant {
+            cfg: &cfg,
+            package_cfg: &package_cfg,
+            package_name: "foo",
+            crates: &crates,
+            out_files: &[],
+            out_dir: "out".to_string(),
+        };
+
+        let mut backend = Box::<GnBackend>::default();
+        assert!(backend.enabled(&cfg));
+        backend.add_variant(&variant).unwrap();
+        let package = Package {
+            name: "foo",
+            dir: Path::new("external/rust/crates/foo"),
+            cfg: &PackageConfig::default(),
+            crates: &[],
+            write_rejects: false,
+        };
+        let build_files = backend.build_files(&package).unwrap();
+
+        assert_eq!(
+            build_files,
+            vec![(
+                PathBuf::from("external/rust/crates/foo/BUILD.gn"),
+                r#"# This file is generated by cargo_embargo.
+# Do not modify this file because the changes will be overridden on upgrade.
+
+rust_library("foo") {
+  crate_name = "foo"
+  crate_root = "src/lib.rs"
+  sources = [ "src/lib.rs" ]
+  rustflags = [
+    "--edition=2021",
+    "--cfg=feature=\"std\"",
+    "--cfg=has_bar",
+  ]
+  deps = [
+    "../bar-baz:bar_baz",
+    "../foo-derive:foo_derive",
+    "//third_party/libc",
+  ]
+  aliased_deps = {
+    bar = "../bar-baz:bar_baz"
+  }
+}
+
+executable("foo_test_tests_integration") {
+  crate_name = "integration"
+  crate_root = "tests/integration.rs"
+  sources = [ "tests/integration.rs" ]
+  testonly = true
+  rustflags = [
+    "--edition=2021",
+    "--test",
+  ]
+  deps = [ ":foo" ]
+}
+"#
+                .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn generate_build_gn_library_and_binary() {
+        let library = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            types: vec![CrateType::Lib],
+            edition: "2021".to_string(),
+            main_src: "src/lib.rs".into(),
+            ..Default::default()
+        };
+        let binary = Crate {
+            types: vec![CrateType::Bin],
+            externs: vec![Extern {
+                name: "foo".to_string(),
+                lib_name: "foo".to_string(),
+                raw_name: "foo".to_string(),
+                extern_type: ExternType::Rust,
+            }],
+            main_src: "src/main.rs".into(),
+            ..library.clone()
+        };
+        let cfg = VariantConfig::default();
+        let package_cfg = PackageVariantConfig::default();
+        let crates = [library, binary];
+        let variant = Variant {
+            cfg: &cfg,
+            package_cfg: &package_cfg,
+            package_name: "foo",
+            crates: &crates,
+            out_files: &[],
+            out_dir: "out".to_string(),
+        };
+
+        let mut backend = GnBackend::default();
+        backend.add_variant(&variant).unwrap();
+        let names: Vec<&str> = backend.targets.iter().map(|target| target.name.as_str()).collect();
+        assert_eq!(names, vec!["foo", "foo_bin"]);
+        let mut binary = String::new();
+        backend.targets[1].write(&mut binary).unwrap();
+        assert_eq!(
+            binary,
+            r#"executable("foo_bin") {
+  crate_name = "foo"
+  crate_root = "src/main.rs"
+  sources = [ "src/main.rs" ]
+  rustflags = [ "--edition=2021" ]
+  deps = [ ":foo" ]
+}
+"#
+        );
+
+        // The same crates again from another variant conflict.
+        assert_eq!(
+            backend.add_variant(&variant).unwrap_err().to_string(),
+            "Duplicate GN target \"foo\" from more than one variant of the package"
+        );
+    }
+}
@@ -0,0 +1,526 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Backend to generate Trusty `rules.mk` files.
+
+use super::{override_module_name, patch_contents, read_license_header, Backend, Package, Variant};
+use crate::cargo::{Crate, CrateType};
+use crate::config::{PackageVariantConfig, VariantConfig};
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+/// This map tracks Rust crates that have special rules.mk modules that were not
+/// generated automatically by this script. Examples include compiler builtins
+/// and other foundational libraries. It also tracks the location of rules.mk
+/// build files for crates that are not under external/rust/crates.
+pub static RULESMK_RENAME_MAP: LazyLock<BTreeMap<&str, &str>> = LazyLock::new(|| {
+    [
+        ("liballoc", "trusty/user/base/lib/liballoc-rust"),
+        ("libcompiler_builtins", "trusty/user/base/lib/libcompiler_builtins-rust"),
+        ("libcore", "trusty/user/base/lib/libcore-rust"),
+        ("libhashbrown", "trusty/user/base/lib/libhashbrown-rust"),
+        ("libpanic_abort", "trusty/user/base/lib/libpanic_abort-rust"),
+        ("libstd", "trusty/user/base/lib/libstd-rust"),
+        ("libstd_detect", "trusty/user/base/lib/libstd_detect-rust"),
+        ("libunwind", "trusty/user/base/lib/libunwind-rust"),
+    ]
+    .into_iter()
+    .collect()
+});
+
+/// Generates Trusty rules.mk files.
+#[derive(Default)]
+pub struct RulesMkBackend {
+    /// The contents of each rules.mk, keyed by its directory relative to the package.
+    mk_contents: BTreeMap<PathBuf, String>,
+}
+
+impl<'a> Backend<'a> for RulesMkBackend {
+    fn enabled(&self, cfg: &VariantConfig) -> bool {
+        cfg.generate_rulesmk
+    }
+
+    fn add_variant(&mut self, variant: &Variant<'a>) -> Result<()> {
+        for (sub_dir, contents) in generate_rules_mk(
+            variant.cfg,
+            variant.package_cfg,
+            variant.package_name,
+            variant.crates,
+            variant.out_files,
+            &variant.out_dir,
+        )? {
+            *self.mk_contents.entry(sub_dir).or_default() += &contents;
+        }
+        Ok(())
+    }
+
+    fn build_files(self: Box<Self>, package: &Package) -> Result<Vec<(PathBuf, String)>> {
+        let Package { dir: package_dir, cfg: package_cfg, write_rejects, .. } = *package;
+        let mut build_files = Vec::new();
+        for (sub_dir, contents) in self.mk_contents {
+            let output_path = package_dir.join(&sub_dir).join("rules.mk");
+            let mk_contents = "# This file is generated by cargo_embargo.\n".to_owned()
+                + "# Do not modify this file after the LOCAL_DIR line\n"
+                + "# because the changes will be overridden on upgrade.\n"
+                + "# Content before the first line starting with LOCAL_DIR is preserved.\n"
+                + read_license_header(&output_path)?.trim()
+                + "\n"
+                + &contents;
+            // The patch only applies to the package's own rules.mk.
+            let mk_contents = match package_cfg.rulesmk_patch.as_deref() {
+                Some(patch) if sub_dir.as_os_str().is_empty() => {
+                    let reject_path = write_rejects.then(|| package_dir.join("rules.mk.rej"));
+                    patch_contents(&mk_contents, "rules.mk", patch, reject_path.as_deref())?
+                }
+                _ => mk_contents,
+            };
+            build_files.push((output_path, mk_contents));
+        }
+        Ok(build_files)
+    }
+}
+
+/// Generates Trusty rules.mk files for the given set of crates.
+///
+/// Returns the contents of each rules.mk along with its directory relative to the package, which
+/// is empty for the package's main module.
+fn generate_rules_mk(
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    package_name: &str,
+    crates: &[Crate],
+    out_files: &[PathBuf],
+    out_dir: &str,
+) -> Result<Vec<(PathBuf, String)>> {
+    let out_files = if package_cfg.copy_out && !out_files.is_empty() {
+        out_files.iter().map(|f| f.file_name().unwrap().to_str().unwrap().to_string()).collect()
+    } else {
+        vec![]
+    };
+
+    let mut libraries = Vec::new();
+    let mut binaries = Vec::new();
+    let mut tests = Vec::new();
+    for c in crates {
+        if c.types.iter().any(|t| matches!(t, CrateType::Example | CrateType::Bench)) {
+            eprintln!(
+                "WARNING: skipped generation of rules.mk for {:?} crate: {}",
+                c.types, c.name
+            );
+        } else if c.types.contains(&CrateType::TestNoHarness) {
+            eprintln!(
+                "WARNING: skipped generation of rules.mk for test without harness: {}",
+                c.name
+            );
+        } else if c.types.contains(&CrateType::Test) {
+            if !c.empty_test {
+                tests.push(c);
+            }
+        } else if c.types.contains(&CrateType::Bin) {
+            binaries.push(c);
+        } else {
+            libraries.push(c);
+        }
+    }
+
+    // The main module goes in the package's own rules.mk. It is the first library if there is one,
+    // along with its unit tests, or otherwise a binary or a test. Any other crates each get a
+    // rules.mk in a sub-directory of the package, and depend on each other through FIND_CRATE like
+    // crates from other packages.
+    let mut modules = Vec::new();
+    for library in &libraries {
+        let unit_tests = tests.iter().position(|test| test.main_src == library.main_src);
+        let unit_tests = unit_tests.map(|i| tests.remove(i));
+        modules.push((PathBuf::from(&library.name), *library, unit_tests));
+    }
+    for binary in &binaries {
+        modules.push((PathBuf::from(&binary.name), *binary, None));
+    }
+    for test in &tests {
+        let suffix = test.main_src.to_string_lossy().replace('/', "_").replace(".rs", "");
+        modules.push((PathBuf::from(format!("test_{suffix}")), *test, None));
+    }
+    if modules.is_empty() {
+        bail!("Found no crates for package {package_name} to generate rules.mk for");
+    }
+    modules[0].0 = PathBuf::new();
+
+    let mut rules_mk_files: Vec<(PathBuf, String)> = Vec::new();
+    for (sub_dir, crate_, unit_tests) in modules {
+        if rules_mk_files.iter().any(|(dir, _)| *dir == sub_dir) {
+            bail!(
+                "Crates of package {package_name} would generate more than one rules.mk in \
+                 {sub_dir:?}"
+            );
+        }
+        if crate_.types.contains(&CrateType::Bin) {
+            let manifest_path = crate_.package_dir.join(&sub_dir).join("manifest.json");
+            if !manifest_path.exists() {
+                eprintln!(
+                    "WARNING: trusted app {} needs a manifest, but {manifest_path:?} doesn't \
+                     exist. It must be added for the rules.mk to build.",
+                    crate_.name
+                );
+            }
+        }
+        let package_dir =
+            if sub_dir.as_os_str().is_empty() { "$(LOCAL_DIR)" } else { "$(LOCAL_DIR)/.." };
+        let contents = crate_to_rulesmk(
+            crate_,
+            unit_tests,
+            cfg,
+            package_cfg,
+            package_dir,
+            &out_files,
+            out_dir,
+        )
+        .with_context(|| {
+            format!(
+                "failed to generate rules.mk for crate \"{}\" with package name \"{}\"",
+                crate_.name, crate_.package_name
+            )
+        })?;
+        rules_mk_files.push((sub_dir, contents));
+    }
+    Ok(rules_mk_files)
+}
+
+/// Convert a `Crate` into a rules.mk file.
+///
+/// If messy business logic is necessary, prefer putting it here.
+///
+/// `crate_` may be a library, a binary which is built as a trusted app, or a test. If `unit_tests`
+/// is given then it is the test crate for the library's unit tests, which are also built.
+/// `package_dir` is the path of the package directory in make syntax, which is `$(LOCAL_DIR)`
+/// unless the rules.mk is in a sub-directory of the package.
+fn crate_to_rulesmk(
+    crate_: &Crate,
+    unit_tests: Option<&Crate>,
+    cfg: &VariantConfig,
+    package_cfg: &PackageVariantConfig,
+    package_dir: &str,
+    out_files: &[String],
+    out_dir: &str,
+) -> Result<String> {
+    let mut contents = String::new();
+
+    contents += "LOCAL_DIR := $(GET_LOCAL_DIR)\n";
+    contents += "MODULE := $(LOCAL_DIR)\n";
+    contents += &format!("MODULE_CRATE_NAME := {}\n", crate_.name);
+
+    let is_binary = crate_.types.contains(&CrateType::Bin);
+    let is_test = crate_.types.contains(&CrateType::Test);
+    if !is_binary && !is_test && !crate_.types.is_empty() {
+        contents += "MODULE_RUST_CRATE_TYPES :=";
+        for crate_type in &crate_.types {
+            contents += match crate_type {
+                CrateType::Lib => " rlib",
+                CrateType::StaticLib => " staticlib",
+                CrateType::ProcMacro => " proc-macro",
+                _ => bail!("Cannot generate rules.mk for crate type {crate_type:?}"),
+            };
+        }
+        contents += "\n";
+    }
+
+    contents += &format!("MODULE_SRCS := {package_dir}/{}\n", crate_.main_src.display());
+    if is_binary {
+        contents += "MANIFEST := $(LOCAL_DIR)/manifest.json\n";
+    }
+
+    if !out_files.is_empty() {
+        contents += &format!("OUT_FILES := {}\n", out_files.join(" "));
+        contents += "BUILD_OUT_FILES := $(addprefix $(call TOBUILDDIR,$(MODULE))/,$(OUT_FILES))\n";
+        contents += &format!(
+            "$(BUILD_OUT_FILES): $(call TOBUILDDIR,$(MODULE))/% : {package_dir}/{out_dir}/%\n"
+        );
+        contents += "\t@echo copying $^ to $@\n";
+        contents += "\t@$(MKDIR)\n";
+        contents += "\t@cp $^ $@\n\n";
+        contents += "MODULE_RUST_ENV += OUT_DIR=$(call TOBUILDDIR,$(MODULE))\n\n";
+        contents += "MODULE_SRCDEPS += $(BUILD_OUT_FILES)\n\n";
+        contents += "OUT_FILES :=\n";
+        contents += "BUILD_OUT_FILES :=\n";
+        contents += "\n";
+    }
+
+    // crate dependencies without lib- prefix. Since paths to trusty modules may
+    // contain hyphens, we generate the module path using the raw name output by
+    // cargo metadata or cargo build.
+    let mut library_deps: Vec<_> = crate_.externs.iter().map(|dep| dep.raw_name.clone()).collect();
+    if package_cfg.no_std {
+        contents += "MODULE_ADD_IMPLICIT_DEPS := false\n";
+        library_deps.push("compiler_builtins".to_string());
+        library_deps.push("core".to_string());
+        if package_cfg.alloc {
+            library_deps.push("alloc".to_string());
+        }
+    }
+
+    contents += &format!("MODULE_RUST_EDITION := {}\n", crate_.edition);
+
+    let mut flags = Vec::new();
+    if !crate_.cap_lints.is_empty() {
+        flags.push(crate_.cap_lints.clone());
+    }
+    flags.extend(crate_.codegens.iter().map(|codegen| format!("-C {}", codegen)));
+    flags.extend(crate_.features.iter().map(|feat| format!("--cfg 'feature=\"{feat}\"'")));
+    flags.extend(
+        crate_
+            .cfgs
+            .iter()
+            .filter(|crate_cfg| !cfg.cfg_blocklist.contains(crate_cfg))
+            .map(|cfg| format!("--cfg '{cfg}'")),
+    );
+    if !flags.is_empty() {
+        contents += "MODULE_RUSTFLAGS += \\\n\t";
+        contents += &flags.join(" \\\n\t");
+        contents += "\n\n";
+    }
+
+    let mut library_deps: Vec<String> = library_deps
+        .into_iter()
+        .flat_map(|dep| {
+            override_module_name(
+                &format!("lib{dep}"),
+                &package_cfg.dep_blocklist,
+                &cfg.module_name_overrides,
+                &RULESMK_RENAME_MAP,
+            )
+        })
+        .map(|dep| {
+            // Rewrite dependency name so it is passed to the FIND_CRATE macro
+            // which will expand to the module path when building Trusty.
+            if let Some(dep) = dep.strip_prefix("lib") {
+                format!("$(call FIND_CRATE,{dep})")
+            } else {
+                dep
+            }
+        })
+        .collect();
+    library_deps.sort();
+    library_deps.dedup();
+    contents += "MODULE_LIBRARY_DEPS := \\\n\t";
+    contents += &library_deps.join(" \\\n\t");
+    contents += "\n\n";
+
+    if let Some(unit_tests) = unit_tests {
+        let test_only_deps: Vec<&str> = unit_tests
+            .externs
+            .iter()
+            .filter(|dep| !crate_.externs.iter().any(|lib_dep| lib_dep.raw_name == dep.raw_name))
+            .map(|dep| dep.raw_name.as_str())
+            .collect();
+        if !test_only_deps.is_empty() {
+            eprintln!(
+                "WARNING: unit tests of {} also depend on {}, which must be added to rules.mk with \
+                 a patch",
+                crate_.name,
+                test_only_deps.join(", ")
+            );
+        }
+    }
+    if is_test || unit_tests.is_some() {
+        contents += "MODULE_RUST_TESTS := true\n\n";
+    }
+
+    if is_binary {
+        contents += "include make/trusted_app.mk\n";
+    } else {
+        contents += "include make/library.mk\n";
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo::{Extern, ExternType};
+    use crate::out_dir_name;
+
+    #[test]
+    fn generate_rules_mk_with_unit_tests() {
+        let library = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            main_src: "src/lib.rs".into(),
+            ..Default::default()
+        };
+        let unit_tests = Crate { types: vec![CrateType::Test], ..library.clone() };
+        let cfg = VariantConfig::default();
+        let package_cfg = PackageVariantConfig::default();
+
+        let mk_files = generate_rules_mk(
+            &cfg,
+            &package_cfg,
+            "foo",
+            &[library, unit_tests],
+            &[],
+            &out_dir_name(0),
+        )
+        .unwrap();
+        assert_eq!(
+            mk_files,
+            vec![(
+                PathBuf::new(),
+                "LOCAL_DIR := $(GET_LOCAL_DIR)
+MODULE := $(LOCAL_DIR)
+MODULE_CRATE_NAME := foo
+MODULE_RUST_CRATE_TYPES := rlib
+MODULE_SRCS := $(LOCAL_DIR)/src/lib.rs
+MODULE_RUST_EDITION := 2021
+MODULE_LIBRARY_DEPS := \\
+\t
+
+MODULE_RUST_TESTS := true
+
+include make/library.mk
+"
+                .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn generate_rules_mk_binary() {
+        let binary = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Bin],
+            main_src: "src/main.rs".into(),
+            ..Default::default()
+        };
+        let cfg = VariantConfig::default();
+        let package_cfg = PackageVariantConfig::default();
+
+        let mk_files =
+            generate_rules_mk(&cfg, &package_cfg, "foo", &[binary], &[], &out_dir_name(0)).unwrap();
+        assert_eq!(
+            mk_files,
+            vec![(
+                PathBuf::new(),
+                "LOCAL_DIR := $(GET_LOCAL_DIR)
+MODULE := $(LOCAL_DIR)
+MODULE_CRATE_NAME := foo
+MODULE_SRCS := $(LOCAL_DIR)/src/main.rs
+MANIFEST := $(LOCAL_DIR)/manifest.json
+MODULE_RUST_EDITION := 2021
+MODULE_LIBRARY_DEPS := \\
+\t
+
+include make/trusted_app.mk
+"
+                .to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn generate_rules_mk_multiple_crates() {
+        let library = Crate {
+            name: "foo".to_string(),
+            package_name: "foo".to_string(),
+            edition: "2021".to_string(),
+            types: vec![CrateType::Lib],
+            main_src: "src/lib.rs".into(),
+            ..Default::default()
+        };
+        let binary = Crate {
+            name: "foo_tool".to_string(),
+            types: vec![CrateType::Bin],
+            main_src: "src/bin/foo_tool.rs".into(),
+            externs: vec![Extern {
+                name: "foo".to_string(),
+                lib_name: "foo".to_string(),
+                raw_name: "foo".to_string(),
+                extern_type: ExternType::Rust,
+            }],
+            ..library.clone()
+        };
+        let integration_test = Crate {
+            name: "integration".to_string(),
+            types: vec![CrateType::Test],
+            main_src: "tests/integration.rs".into(),
+            ..library.clone()
+        };
+        let cfg = VariantConfig::default();
+        let package_cfg = PackageVariantConfig::default();
+
+        let mk_files = generate_rules_mk(
+            &cfg,
+            &package_cfg,
+            "foo",
+            &[binary, integration_test, library],
+            &[],
+            &out_dir_name(0),
+        )
+        .unwrap();
+        assert_eq!(
+            mk_files,
+            vec![
+                (
+                    PathBuf::new(),
+                    "LOCAL_DIR := $(GET_LOCAL_DIR)
+MODULE := $(LOCAL_DIR)
+MODULE_CRATE_NAME := foo
+MODULE_RUST_CRATE_TYPES := rlib
+MODULE_SRCS := $(LOCAL_DIR)/src/lib.rs
+MODULE_RUST_EDITION := 2021
+MODULE_LIBRARY_DEPS := \\
+\t
+
+include make/library.mk
+"
+                    .to_string()
+                ),
+                (
+                    PathBuf::from("foo_tool"),
+                    "LOCAL_DIR := $(GET_LOCAL_DIR)
+MODULE := $(LOCAL_DIR)
+MODULE_CRATE_NAME := foo_tool
+MODULE_SRCS := $(LOCAL_DIR)/../src/bin/foo_tool.rs
+MANIFEST := $(LOCAL_DIR)/manifest.json
+MODULE_RUST_EDITION := 2021
+MODULE_LIBRARY_DEPS := \\
+\t$(call FIND_CRATE,foo)
+
+include make/trusted_app.mk
+"
+                    .to_string()
+                ),
+                (
+                    PathBuf::from("test_tests_integration"),
+                    "LOCAL_DIR := $(GET_LOCAL_DIR)
+MODULE := $(LOCAL_DIR)
+MODULE_CRATE_NAME := integration
+MODULE_SRCS := $(LOCAL_DIR)/../tests/integration.rs
+MODULE_RUST_EDITION := 2021
+MODULE_LIBRARY_DEPS := \\
+\t
+
+MODULE_RUST_TESTS := true
+
+include make/library.mk
+"
+                    .to_string()
+                ),
+            ]
+        );
+    }
+}
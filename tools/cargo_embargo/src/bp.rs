@@ -12,15 +12,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod parser;
+
 use anyhow::Result;
 use std::collections::BTreeMap;
 use std::path::PathBuf;
 
+/// Number of spaces to indent each level of nesting by, as `bpfmt` does.
+const INDENT: usize = 4;
+
+/// The contents of a Blueprint file.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BpFile {
+    pub definitions: Vec<BpDefinition>,
+}
+
+/// A top-level definition in a Blueprint file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BpDefinition {
+    Module(BpModule),
+    /// A variable assignment, e.g. `foo = ["a"]`, or `foo += ["b"]` if `append` is true.
+    Assignment {
+        name: String,
+        value: BpValue,
+        append: bool,
+        comments: Vec<String>,
+    },
+    /// Comments which aren't attached to a module or assignment, one element per line.
+    Comment(Vec<String>),
+}
+
 /// Build module.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct BpModule {
     pub module_type: String,
     pub props: BpProperties,
+    /// Comment lines, including the `//`, to write immediately before the module.
+    pub comments: Vec<String>,
 }
 
 /// Properties of a build module, or of a nested object value.
@@ -40,29 +68,155 @@ pub struct BpProperties {
     ///     {
     ///         name: "foo",
     ///         srcs: ["main.rs"],
-    ///         some random text
+    ///         some random text,
     ///     }
+    ///
+    /// The block is re-indented to match the properties, keeping the relative indentation of its
+    /// lines.
     pub raw_block: Option<String>,
+    /// Comment lines to write before each property, keyed by property name.
+    pub comments: BTreeMap<String, Vec<String>>,
+    /// Comments to write at the end of the line of each property, after the comma, keyed by
+    /// property name.
+    pub trailing_comments: BTreeMap<String, String>,
+    /// Comment lines to write after the last property, before the closing brace.
+    pub end_comments: Vec<String>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum BpValue {
     Object(BpProperties),
     Bool(bool),
+    Int(i64),
     String(String),
     List(Vec<BpValue>),
+    /// A reference to a variable defined elsewhere in the file.
+    Variable(String),
+    /// Values joined with `+`.
+    Concat(Vec<BpValue>),
+    Select(BpSelect),
+    /// A comment within a list, as parsed from an existing file. It is written on its own line, or
+    /// at the end of the line of the previous element if `trailing` is true.
+    Comment {
+        text: String,
+        trailing: bool,
+    },
+}
+
+/// A `select()` expression, e.g.
+///
+///     select(release_flag("RELEASE_FOO"), {
+///         true: ["libfoo"],
+///         default: [],
+///     })
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BpSelect {
+    pub conditions: Vec<BpSelectCondition>,
+    /// The patterns of each case, with one pattern per condition, and the value or `None` for
+    /// `unset`.
+    pub cases: Vec<(Vec<BpSelectPattern>, Option<BpValue>)>,
+}
+
+/// A condition which a `select()` switches on, e.g. `soong_config_variable("ns", "var")`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BpSelectCondition {
+    pub function: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BpSelectPattern {
+    String(String),
+    Bool(bool),
+    Default,
+    /// `any`, optionally binding the value to a variable with `any @ name`.
+    Any(Option<String>),
+}
+
+/// Writes the given string as a double-quoted Blueprint string literal, escaping it in the same way
+/// as Go's `strconv.Quote`.
+fn write_quoted(w: &mut impl std::fmt::Write, s: &str) -> Result<()> {
+    w.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => w.write_str("\\\"")?,
+            '\\' => w.write_str("\\\\")?,
+            '\x07' => w.write_str("\\a")?,
+            '\x08' => w.write_str("\\b")?,
+            '\x0c' => w.write_str("\\f")?,
+            '\n' => w.write_str("\\n")?,
+            '\r' => w.write_str("\\r")?,
+            '\t' => w.write_str("\\t")?,
+            '\x0b' => w.write_str("\\v")?,
+            c if c.is_control() && (c as u32) < 0x80 => write!(w, "\\x{:02x}", c as u32)?,
+            c if c.is_control() => write!(w, "\\u{:04x}", c as u32)?,
+            c => w.write_char(c)?,
+        }
+    }
+    w.write_char('"')?;
+    Ok(())
+}
+
+/// Writes the given comment lines, each on its own line indented by `indent` spaces.
+fn write_comments(w: &mut impl std::fmt::Write, comments: &[String], indent: usize) -> Result<()> {
+    for comment in comments {
+        writeln!(w, "{:indent$}{comment}", "")?;
+    }
+    Ok(())
+}
+
+impl BpFile {
+    /// Serialize to Android.bp format, with a blank line after each definition.
+    pub fn write(&self, w: &mut impl std::fmt::Write) -> Result<()> {
+        for definition in &self.definitions {
+            definition.write(w)?;
+            w.write_str("\n")?;
+        }
+        Ok(())
+    }
+
+    /// Returns the modules defined in the file.
+    pub fn modules(&self) -> impl Iterator<Item = &BpModule> {
+        self.definitions.iter().filter_map(|definition| match definition {
+            BpDefinition::Module(m) => Some(m),
+            _ => None,
+        })
+    }
+}
+
+impl BpDefinition {
+    /// Serialize to Android.bp format.
+    pub fn write(&self, w: &mut impl std::fmt::Write) -> Result<()> {
+        match self {
+            BpDefinition::Module(m) => m.write(w),
+            BpDefinition::Assignment { name, value, append, comments } => {
+                write_comments(w, comments, 0)?;
+                write!(w, "{name} {} ", if *append { "+=" } else { "=" })?;
+                value.write(w, 0)?;
+                w.write_str("\n")?;
+                Ok(())
+            }
+            BpDefinition::Comment(comments) => write_comments(w, comments, 0),
+        }
+    }
 }
 
 impl BpModule {
     pub fn new(module_type: String) -> BpModule {
-        BpModule { module_type, props: BpProperties::new() }
+        BpModule { module_type, props: BpProperties::new(), comments: Vec::new() }
     }
 
-    /// Serialize to Android.bp format.
+    /// Returns the module's name, if it has one.
+    pub fn name(&self) -> Option<&str> {
+        self.props.get_string("name")
+    }
+
+    /// Serialize to Android.bp format, formatted in the same way as `bpfmt`.
     pub fn write(&self, w: &mut impl std::fmt::Write) -> Result<()> {
+        write_comments(w, &self.comments, 0)?;
         w.write_str(&self.module_type)?;
         w.write_str(" ")?;
-        self.props.write(w)?;
+        self.props.write(w, 0)?;
         w.write_str("\n")?;
         Ok(())
     }
@@ -70,7 +224,13 @@ impl BpModule {
 
 impl BpProperties {
     pub fn new() -> Self {
-        BpProperties { map: BTreeMap::new(), raw_block: None }
+        BpProperties {
+            map: BTreeMap::new(),
+            raw_block: None,
+            comments: BTreeMap::new(),
+            trailing_comments: BTreeMap::new(),
+            end_comments: Vec::new(),
+        }
     }
 
     pub fn get_string(&self, k: &str) -> Option<&str> {
@@ -90,6 +250,11 @@ impl BpProperties {
         }
     }
 
+    /// Adds a `//` comment line to write before the property `k`.
+    pub fn add_comment(&mut self, k: &str, comment: &str) {
+        self.comments.entry(k.to_string()).or_default().push(format!("// {comment}"));
+    }
+
     pub fn object(&mut self, k: &str) -> &mut BpProperties {
         let v =
             self.map.entry(k.to_string()).or_insert_with(|| BpValue::Object(BpProperties::new()));
@@ -99,8 +264,13 @@ impl BpProperties {
         }
     }
 
-    /// Serialize to Android.bp format.
-    pub fn write(&self, w: &mut impl std::fmt::Write) -> Result<()> {
+    /// Serialize to Android.bp format, with the closing brace indented by `indent` spaces and the
+    /// properties by 4 more.
+    pub fn write(&self, w: &mut impl std::fmt::Write, indent: usize) -> Result<()> {
+        if self.map.is_empty() && self.raw_block.is_none() && self.end_comments.is_empty() {
+            w.write_str("{}")?;
+            return Ok(());
+        }
         w.write_str("{\n")?;
         // Sort stuff to match what cargo2android.py's output order.
         let canonical_order = &[
@@ -117,10 +287,12 @@ impl BpProperties {
             "test_suites",
             "auto_gen_config",
             "test_options",
+            "test_harness",
             "edition",
             "features",
             "cfgs",
             "flags",
+            "cflags",
             "rustlibs",
             "proc_macros",
             "static_libs",
@@ -132,6 +304,7 @@ impl BpProperties {
             "ld_flags",
             "compile_multilib",
             "include_dirs",
+            "local_include_dirs",
             "apex_available",
             "prefer_rlib",
             "no_stdlibs",
@@ -150,43 +323,179 @@ impl BpProperties {
             let i = canonical_order.iter().position(|x| k == x).unwrap_or(canonical_order.len());
             (i, (*k).clone())
         });
+        let inner_indent = indent + INDENT;
         for (k, v) in props {
-            w.write_str(k)?;
-            w.write_str(": ")?;
-            v.write(w)?;
-            w.write_str(",\n")?;
+            if let Some(comments) = self.comments.get(k) {
+                write_comments(w, comments, inner_indent)?;
+            }
+            write!(w, "{:inner_indent$}{k}: ", "")?;
+            v.write(w, inner_indent)?;
+            w.write_str(",")?;
+            if let Some(comment) = self.trailing_comments.get(k) {
+                write!(w, " {comment}")?;
+            }
+            w.write_str("\n")?;
         }
         if let Some(raw_block) = &self.raw_block {
-            w.write_str(raw_block)?;
+            // The raw block isn't parsed, so keep its own relative indentation.
+            let raw_block = raw_block.trim_end().trim_end_matches(',');
+            let lines: Vec<&str> = raw_block.lines().skip_while(|l| l.trim().is_empty()).collect();
+            let base_indent = lines
+                .iter()
+                .filter(|l| !l.trim().is_empty())
+                .map(|l| l.len() - l.trim_start().len())
+                .min()
+                .unwrap_or(0);
+            for (i, line) in lines.iter().enumerate() {
+                if i > 0 {
+                    w.write_str("\n")?;
+                }
+                if !line.trim().is_empty() {
+                    write!(w, "{:inner_indent$}{}", "", line[base_indent..].trim_end())?;
+                }
+            }
             w.write_str(",\n")?;
         }
-        w.write_str("}")?;
+        write_comments(w, &self.end_comments, inner_indent)?;
+        write!(w, "{:indent$}}}", "")?;
         Ok(())
     }
 }
 
 impl BpValue {
-    /// Serialize to Android.bp format.
-    pub fn write(&self, w: &mut impl std::fmt::Write) -> Result<()> {
+    /// Serialize to Android.bp format, assuming that the current line is indented by `indent`
+    /// spaces.
+    ///
+    /// Like `bpfmt`, lists with more than one element or containing objects are split over several
+    /// lines, one element per line, with a trailing comma.
+    pub fn write(&self, w: &mut impl std::fmt::Write, indent: usize) -> Result<()> {
         match self {
-            BpValue::Object(p) => p.write(w)?,
+            BpValue::Object(p) => p.write(w, indent)?,
             BpValue::Bool(b) => write!(w, "{b}")?,
-            BpValue::String(s) => write!(w, "\"{s}\"")?,
+            BpValue::Int(i) => write!(w, "{i}")?,
+            BpValue::String(s) => write_quoted(w, s)?,
             BpValue::List(vs) => {
-                w.write_str("[")?;
+                if vs.len() > 1
+                    || vs.iter().any(|v| matches!(v, BpValue::Object(_) | BpValue::Comment { .. }))
+                {
+                    let inner_indent = indent + INDENT;
+                    w.write_str("[")?;
+                    for v in vs {
+                        match v {
+                            BpValue::Comment { text, trailing: true } => write!(w, " {text}")?,
+                            BpValue::Comment { text, trailing: false } => {
+                                write!(w, "\n{:inner_indent$}{text}", "")?
+                            }
+                            v => {
+                                write!(w, "\n{:inner_indent$}", "")?;
+                                v.write(w, inner_indent)?;
+                                w.write_str(",")?;
+                            }
+                        }
+                    }
+                    write!(w, "\n{:indent$}]", "")?;
+                } else {
+                    w.write_str("[")?;
+                    for v in vs {
+                        v.write(w, indent)?;
+                    }
+                    w.write_str("]")?;
+                }
+            }
+            BpValue::Variable(name) => w.write_str(name)?,
+            BpValue::Concat(vs) => {
                 for (i, v) in vs.iter().enumerate() {
-                    v.write(w)?;
-                    if i != vs.len() - 1 {
+                    if i > 0 {
+                        w.write_str(" + ")?;
+                    }
+                    v.write(w, indent)?;
+                }
+            }
+            BpValue::Select(select) => select.write(w, indent)?,
+            BpValue::Comment { text, .. } => w.write_str(text)?,
+        }
+        Ok(())
+    }
+}
+
+impl BpSelect {
+    /// Serialize to Android.bp format, assuming that the current line is indented by `indent`
+    /// spaces.
+    pub fn write(&self, w: &mut impl std::fmt::Write, indent: usize) -> Result<()> {
+        w.write_str("select(")?;
+        if let [condition] = &self.conditions[..] {
+            condition.write(w)?;
+        } else {
+            w.write_str("(")?;
+            for (i, condition) in self.conditions.iter().enumerate() {
+                if i > 0 {
+                    w.write_str(", ")?;
+                }
+                condition.write(w)?;
+            }
+            w.write_str(")")?;
+        }
+        w.write_str(", {\n")?;
+        let inner_indent = indent + INDENT;
+        for (patterns, value) in &self.cases {
+            write!(w, "{:inner_indent$}", "")?;
+            if let [pattern] = &patterns[..] {
+                pattern.write(w)?;
+            } else {
+                w.write_str("(")?;
+                for (i, pattern) in patterns.iter().enumerate() {
+                    if i > 0 {
                         w.write_str(", ")?;
                     }
+                    pattern.write(w)?;
                 }
-                w.write_str("]")?;
+                w.write_str(")")?;
+            }
+            w.write_str(": ")?;
+            match value {
+                Some(value) => value.write(w, inner_indent)?,
+                None => w.write_str("unset")?,
             }
+            w.write_str(",\n")?;
+        }
+        write!(w, "{:indent$}}})", "")?;
+        Ok(())
+    }
+}
+
+impl BpSelectCondition {
+    fn write(&self, w: &mut impl std::fmt::Write) -> Result<()> {
+        write!(w, "{}(", self.function)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                w.write_str(", ")?;
+            }
+            write_quoted(w, arg)?;
+        }
+        w.write_str(")")?;
+        Ok(())
+    }
+}
+
+impl BpSelectPattern {
+    fn write(&self, w: &mut impl std::fmt::Write) -> Result<()> {
+        match self {
+            BpSelectPattern::String(s) => write_quoted(w, s)?,
+            BpSelectPattern::Bool(b) => write!(w, "{b}")?,
+            BpSelectPattern::Default => w.write_str("default")?,
+            BpSelectPattern::Any(None) => w.write_str("any")?,
+            BpSelectPattern::Any(Some(name)) => write!(w, "any @ {name}")?,
         }
         Ok(())
     }
 }
 
+impl From<i64> for BpValue {
+    fn from(x: i64) -> Self {
+        BpValue::Int(x)
+    }
+}
+
 impl From<bool> for BpValue {
     fn from(x: bool) -> Self {
         BpValue::Bool(x)
@@ -216,3 +525,77 @@ impl<T: Into<BpValue>> From<Vec<T>> for BpValue {
         BpValue::List(x.into_iter().map(|x| x.into()).collect())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_formatted() {
+        let mut m = BpModule::new("rust_test".to_string());
+        m.props.set("name", "foo");
+        m.props.set("srcs", vec!["a.rs", "b.rs"]);
+        m.props.set("rustlibs", vec!["libbar"]);
+        m.props.set("data", Vec::<String>::new());
+        m.props.object("test_options").set("unit_test", true);
+        m.props.object("target").object("android").set("rustlibs", vec!["libbaz"]);
+        m.props.raw_block =
+            Some("  // Hand-written.\n  arch: {\n      arm64: {},\n  },\n".to_string());
+
+        let mut output = String::new();
+        m.write(&mut output).unwrap();
+        assert_eq!(
+            output,
+            r#"rust_test {
+    name: "foo",
+    srcs: [
+        "a.rs",
+        "b.rs",
+    ],
+    test_options: {
+        unit_test: true,
+    },
+    rustlibs: ["libbar"],
+    target: {
+        android: {
+            rustlibs: ["libbaz"],
+        },
+    },
+    data: [],
+    // Hand-written.
+    arch: {
+        arm64: {},
+    },
+}
+"#
+        );
+    }
+
+    #[test]
+    fn write_escaped_strings_and_comments() {
+        let mut m = BpModule::new("rust_library".to_string());
+        m.comments.push("// Generated from foo.".to_string());
+        m.props.set("name", "lib\"quoted\"");
+        m.props.set("cfgs", vec!["path=\"C:\\dir\"", "multi\nline\x01é"]);
+        m.props.set("count", 42);
+        m.props.add_comment("cfgs", "From build.rs.");
+
+        let mut output = String::new();
+        m.write(&mut output).unwrap();
+        assert_eq!(
+            output,
+            r#"// Generated from foo.
+rust_library {
+    name: "lib\"quoted\"",
+    // From build.rs.
+    cfgs: [
+        "path=\"C:\\dir\"",
+        "multi\nline\x01é",
+    ],
+    count: 42,
+}
+"#
+        );
+        assert_eq!(parser::parse(&output).unwrap().definitions, vec![BpDefinition::Module(m)]);
+    }
+}
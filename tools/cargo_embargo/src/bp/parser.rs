@@ -0,0 +1,706 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parser for Blueprint files, such as existing `Android.bp` files.
+//!
+//! Comments before a module, assignment or property are kept and attached to it, as are comments
+//! at the end of an object and comments within a list. A comment on the same line as a property or
+//! list element, after its comma, is attached to that property or element. Comments anywhere else,
+//! such as within a `select()`, are dropped.
+
+use super::{
+    BpDefinition, BpFile, BpModule, BpProperties, BpSelect, BpSelectCondition, BpSelectPattern,
+    BpValue,
+};
+use anyhow::{bail, Context, Result};
+
+/// Parses the contents of a Blueprint file.
+pub fn parse(contents: &str) -> Result<BpFile> {
+    Parser::new(tokenize(contents)?).parse_file()
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TokenKind {
+    Ident(String),
+    String(String),
+    Int(String),
+    /// A single punctuation character, or `+=`.
+    Punct(&'static str),
+    Eof,
+}
+
+/// A token, along with the comments before it.
+#[derive(Clone, Debug)]
+struct Token {
+    kind: TokenKind,
+    line: usize,
+    column: usize,
+    /// Groups of consecutive comment lines before the token, each with the line on which the
+    /// group ends.
+    comments: Vec<(Vec<String>, usize)>,
+    /// A comment after the token on the same line, if the token is a comma.
+    trailing_comment: Option<String>,
+}
+
+impl Token {
+    /// Returns all the comments before the token, one element per line.
+    fn all_comments(&self) -> Vec<String> {
+        self.comments.iter().flat_map(|(lines, _)| lines.iter().cloned()).collect()
+    }
+}
+
+const PUNCTUATION: &[&str] = &["{", "}", "[", "]", "(", ")", ":", ",", "=", "+", "@"];
+
+/// Splits the contents of a Blueprint file into tokens.
+fn tokenize(contents: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = contents.chars().collect();
+    let mut tokens = Vec::new();
+    let mut comments: Vec<(Vec<String>, usize)> = Vec::new();
+    let (mut i, mut line, mut line_start) = (0, 1, 0);
+    loop {
+        let column = i - line_start + 1;
+        let Some(&c) = chars.get(i) else {
+            tokens.push(Token {
+                kind: TokenKind::Eof,
+                line,
+                column,
+                comments,
+                trailing_comment: None,
+            });
+            return Ok(tokens);
+        };
+        let start = i;
+        let kind = match c {
+            '\n' => {
+                i += 1;
+                line += 1;
+                line_start = i;
+                continue;
+            }
+            c if c.is_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '/' if chars.get(i + 1) == Some(&'/') => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+                let comment = chars[start..i].iter().collect::<String>().trim_end().to_string();
+                if let Some(previous) = trailing_comment_token(&mut tokens, &comments, line, line) {
+                    previous.trailing_comment = Some(comment);
+                } else {
+                    add_comment(&mut comments, comment, line, line);
+                }
+                continue;
+            }
+            '/' if chars.get(i + 1) == Some(&'*') => {
+                let start_line = line;
+                i += 2;
+                while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                    if chars[i] == '\n' {
+                        line += 1;
+                        line_start = i + 1;
+                    }
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    bail!("{start_line}:{column}: unterminated comment");
+                }
+                i += 2;
+                let comment = chars[start..i].iter().collect();
+                if let Some(previous) =
+                    trailing_comment_token(&mut tokens, &comments, start_line, line)
+                {
+                    previous.trailing_comment = Some(comment);
+                } else {
+                    add_comment(&mut comments, comment, start_line, line);
+                }
+                continue;
+            }
+            '"' => {
+                let (value, end) = parse_string(&chars, i)
+                    .with_context(|| format!("{line}:{column}: invalid string"))?;
+                i = end;
+                TokenKind::String(value)
+            }
+            '`' => {
+                i += 1;
+                while i < chars.len() && chars[i] != '`' {
+                    if chars[i] == '\n' {
+                        line += 1;
+                        line_start = i + 1;
+                    }
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    bail!("{line}:{column}: unterminated raw string");
+                }
+                i += 1;
+                TokenKind::String(chars[start + 1..i - 1].iter().collect())
+            }
+            c if c.is_ascii_digit()
+                || (c == '-' && chars.get(i + 1).is_some_and(char::is_ascii_digit)) =>
+            {
+                i += 1;
+                while chars.get(i).is_some_and(char::is_ascii_digit) {
+                    i += 1;
+                }
+                TokenKind::Int(chars[start..i].iter().collect())
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                while chars.get(i).is_some_and(|&c| c.is_alphanumeric() || c == '_') {
+                    i += 1;
+                }
+                TokenKind::Ident(chars[start..i].iter().collect())
+            }
+            '+' if chars.get(i + 1) == Some(&'=') => {
+                i += 2;
+                TokenKind::Punct("+=")
+            }
+            c => {
+                let Some(punct) = PUNCTUATION.iter().find(|p| p.starts_with(c)) else {
+                    bail!("{line}:{column}: unexpected character {c:?}");
+                };
+                i += 1;
+                TokenKind::Punct(punct)
+            }
+        };
+        tokens.push(Token {
+            kind,
+            line,
+            column,
+            comments: std::mem::take(&mut comments),
+            trailing_comment: None,
+        });
+    }
+}
+
+/// Returns the previous token if a comment spanning the given lines is a trailing comment for it,
+/// i.e. it is a comma on the same line with no other comment after it yet.
+fn trailing_comment_token<'a>(
+    tokens: &'a mut [Token],
+    comments: &[(Vec<String>, usize)],
+    start_line: usize,
+    end_line: usize,
+) -> Option<&'a mut Token> {
+    let previous = tokens.last_mut()?;
+    (comments.is_empty()
+        && start_line == end_line
+        && previous.line == start_line
+        && previous.kind == TokenKind::Punct(",")
+        && previous.trailing_comment.is_none())
+    .then_some(previous)
+}
+
+/// Adds a comment spanning the given lines, either to the last group of comments if it directly
+/// follows it or else as a new group.
+fn add_comment(
+    comments: &mut Vec<(Vec<String>, usize)>,
+    comment: String,
+    start_line: usize,
+    end_line: usize,
+) {
+    match comments.last_mut() {
+        Some((lines, last_line)) if *last_line + 1 >= start_line => {
+            lines.push(comment);
+            *last_line = end_line;
+        }
+        _ => comments.push((vec![comment], end_line)),
+    }
+}
+
+/// Parses a double-quoted string starting at `chars[start]`, with Go escape sequences, returning
+/// the unescaped value and the index after the closing quote.
+fn parse_string(chars: &[char], start: usize) -> Result<(String, usize)> {
+    let mut value = String::new();
+    let mut i = start + 1;
+    loop {
+        match chars.get(i) {
+            None | Some('\n') => bail!("unterminated string"),
+            Some('"') => return Ok((value, i + 1)),
+            Some('\\') => {
+                let escape = *chars.get(i + 1).context("unterminated string")?;
+                i += 2;
+                let hex_digits = match escape {
+                    'x' => 2,
+                    'u' => 4,
+                    'U' => 8,
+                    _ => 0,
+                };
+                match escape {
+                    'a' => value.push('\x07'),
+                    'b' => value.push('\x08'),
+                    'f' => value.push('\x0c'),
+                    'n' => value.push('\n'),
+                    'r' => value.push('\r'),
+                    't' => value.push('\t'),
+                    'v' => value.push('\x0b'),
+                    '\\' | '\'' | '"' => value.push(escape),
+                    '0'..='7' => {
+                        let digits: String =
+                            chars[i - 1..(i + 2).min(chars.len())].iter().collect();
+                        let code = u8::from_str_radix(&digits, 8)
+                            .with_context(|| format!("invalid octal escape \\{digits}"))?;
+                        value.push(code as char);
+                        i += 2;
+                    }
+                    'x' | 'u' | 'U' => {
+                        let digits: String =
+                            chars[i..(i + hex_digits).min(chars.len())].iter().collect();
+                        let c = u32::from_str_radix(&digits, 16)
+                            .ok()
+                            .filter(|_| digits.len() == hex_digits)
+                            .and_then(char::from_u32)
+                            .with_context(|| format!("invalid escape \\{escape}{digits}"))?;
+                        value.push(c);
+                        i += hex_digits;
+                    }
+                    _ => bail!("invalid escape \\{escape}"),
+                }
+            }
+            Some(&c) => {
+                value.push(c);
+                i += 1;
+            }
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Self {
+        Parser { tokens, position: 0 }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        if token.kind != TokenKind::Eof {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, token: &Token, message: &str) -> Result<T> {
+        bail!("{}:{}: {message}, found {:?}", token.line, token.column, token.kind)
+    }
+
+    fn is_punct(&self, punct: &'static str) -> bool {
+        self.peek().kind == TokenKind::Punct(punct)
+    }
+
+    /// Returns the trailing comment of the last token consumed, if there is one.
+    fn previous_trailing_comment(&self) -> Option<String> {
+        self.tokens[..self.position].last()?.trailing_comment.clone()
+    }
+
+    /// Consumes the next token if it is the given punctuation, returning whether it was.
+    fn accept(&mut self, punct: &'static str) -> bool {
+        let accepted = self.is_punct(punct);
+        if accepted {
+            self.next();
+        }
+        accepted
+    }
+
+    fn expect(&mut self, punct: &'static str) -> Result<Token> {
+        let token = self.next();
+        if token.kind != TokenKind::Punct(punct) {
+            return self.error(&token, &format!("expected {punct:?}"));
+        }
+        Ok(token)
+    }
+
+    fn expect_ident(&mut self) -> Result<String> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Ident(ident) => Ok(ident),
+            _ => self.error(&token, "expected identifier"),
+        }
+    }
+
+    fn expect_string(&mut self) -> Result<String> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::String(s) => Ok(s),
+            _ => self.error(&token, "expected string"),
+        }
+    }
+
+    fn parse_file(&mut self) -> Result<BpFile> {
+        let mut definitions = Vec::new();
+        loop {
+            let token = self.peek().clone();
+            // Comments separated from the next definition by a blank line stand on their own.
+            let mut comments = token.comments.clone();
+            let attached = match comments.last() {
+                Some((lines, end_line))
+                    if token.kind != TokenKind::Eof && end_line + 1 == token.line =>
+                {
+                    let lines = lines.clone();
+                    comments.pop();
+                    lines
+                }
+                _ => Vec::new(),
+            };
+            definitions.extend(comments.into_iter().map(|(lines, _)| BpDefinition::Comment(lines)));
+            if token.kind == TokenKind::Eof {
+                return Ok(BpFile { definitions });
+            }
+
+            let name = self.expect_ident()?;
+            let next = self.next();
+            definitions.push(match next.kind {
+                TokenKind::Punct("{") => {
+                    let props = self.parse_properties()?;
+                    BpDefinition::Module(BpModule { module_type: name, props, comments: attached })
+                }
+                TokenKind::Punct(op @ ("=" | "+=")) => {
+                    let value = self.parse_expression()?;
+                    BpDefinition::Assignment { name, value, append: op == "+=", comments: attached }
+                }
+                _ => return self.error(&next, "expected \"{\", \"=\" or \"+=\""),
+            });
+        }
+    }
+
+    /// Parses the properties of a module or object, after the opening brace.
+    fn parse_properties(&mut self) -> Result<BpProperties> {
+        let mut props = BpProperties::new();
+        loop {
+            let token = self.peek().clone();
+            if token.kind == TokenKind::Punct("}") {
+                self.next();
+                props.end_comments = token.all_comments();
+                return Ok(props);
+            }
+            let name = self.expect_ident()?;
+            self.expect(":")?;
+            let value = self.parse_expression()?;
+            if !token.comments.is_empty() {
+                props.comments.insert(name.clone(), token.all_comments());
+            }
+            if props.map.insert(name.clone(), value).is_some() {
+                return self.error(&token, &format!("duplicate property {name:?}"));
+            }
+            if self.accept(",") {
+                if let Some(comment) = self.previous_trailing_comment() {
+                    props.trailing_comments.insert(name, comment);
+                }
+            } else if !self.is_punct("}") {
+                let token = self.next();
+                return self.error(&token, "expected \",\" or \"}\"");
+            }
+        }
+    }
+
+    /// Parses a value, or several values joined with `+`.
+    fn parse_expression(&mut self) -> Result<BpValue> {
+        let mut values = vec![self.parse_operand()?];
+        while self.accept("+") {
+            values.push(self.parse_operand()?);
+        }
+        Ok(if values.len() == 1 { values.pop().unwrap() } else { BpValue::Concat(values) })
+    }
+
+    fn parse_operand(&mut self) -> Result<BpValue> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::String(s) => Ok(BpValue::String(s)),
+            TokenKind::Ident(ident) if ident == "true" => Ok(BpValue::Bool(true)),
+            TokenKind::Ident(ident) if ident == "false" => Ok(BpValue::Bool(false)),
+            TokenKind::Ident(ident) if ident == "select" && self.is_punct("(") => {
+                Ok(BpValue::Select(self.parse_select()?))
+            }
+            TokenKind::Ident(ident) => Ok(BpValue::Variable(ident)),
+            TokenKind::Punct("{") => Ok(BpValue::Object(self.parse_properties()?)),
+            TokenKind::Punct("[") => {
+                let mut values = Vec::new();
+                loop {
+                    let comments = self.peek().all_comments();
+                    values.extend(
+                        comments.into_iter().map(|text| BpValue::Comment { text, trailing: false }),
+                    );
+                    if self.accept("]") {
+                        break;
+                    }
+                    values.push(self.parse_expression()?);
+                    if self.accept(",") {
+                        if let Some(text) = self.previous_trailing_comment() {
+                            values.push(BpValue::Comment { text, trailing: true });
+                        }
+                    } else if !self.is_punct("]") {
+                        let token = self.next();
+                        return self.error(&token, "expected \",\" or \"]\"");
+                    }
+                }
+                Ok(BpValue::List(values))
+            }
+            TokenKind::Int(ref i) => match i.parse() {
+                Ok(i) => Ok(BpValue::Int(i)),
+                Err(_) => self.error(&token, "integer out of range"),
+            },
+            _ => self.error(&token, "expected value"),
+        }
+    }
+
+    /// Parses a `select` expression, after the `select` keyword.
+    fn parse_select(&mut self) -> Result<BpSelect> {
+        self.expect("(")?;
+        let mut conditions = Vec::new();
+        if self.accept("(") {
+            while !self.accept(")") {
+                conditions.push(self.parse_select_condition()?);
+                if !self.accept(",") && !self.is_punct(")") {
+                    let token = self.next();
+                    return self.error(&token, "expected \",\" or \")\"");
+                }
+            }
+        } else {
+            conditions.push(self.parse_select_condition()?);
+        }
+        self.expect(",")?;
+        self.expect("{")?;
+        let mut cases = Vec::new();
+        while !self.accept("}") {
+            let patterns = if self.accept("(") {
+                let mut patterns = Vec::new();
+                while !self.accept(")") {
+                    patterns.push(self.parse_select_pattern()?);
+                    if !self.accept(",") && !self.is_punct(")") {
+                        let token = self.next();
+                        return self.error(&token, "expected \",\" or \")\"");
+                    }
+                }
+                patterns
+            } else {
+                vec![self.parse_select_pattern()?]
+            };
+            if patterns.len() != conditions.len() {
+                let token = self.peek().clone();
+                return self.error(
+                    &token,
+                    &format!("expected {} patterns, got {}", conditions.len(), patterns.len()),
+                );
+            }
+            self.expect(":")?;
+            let value = if self.peek().kind == TokenKind::Ident("unset".to_string()) {
+                self.next();
+                None
+            } else {
+                Some(self.parse_expression()?)
+            };
+            cases.push((patterns, value));
+            if !self.accept(",") && !self.is_punct("}") {
+                let token = self.next();
+                return self.error(&token, "expected \",\" or \"}\"");
+            }
+        }
+        self.accept(",");
+        self.expect(")")?;
+        Ok(BpSelect { conditions, cases })
+    }
+
+    fn parse_select_condition(&mut self) -> Result<BpSelectCondition> {
+        let function = self.expect_ident()?;
+        self.expect("(")?;
+        let mut args = Vec::new();
+        while !self.accept(")") {
+            args.push(self.expect_string()?);
+            if !self.accept(",") && !self.is_punct(")") {
+                let token = self.next();
+                return self.error(&token, "expected \",\" or \")\"");
+            }
+        }
+        Ok(BpSelectCondition { function, args })
+    }
+
+    fn parse_select_pattern(&mut self) -> Result<BpSelectPattern> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::String(s) => Ok(BpSelectPattern::String(s.clone())),
+            TokenKind::Ident(ident) => match ident.as_str() {
+                "true" => Ok(BpSelectPattern::Bool(true)),
+                "false" => Ok(BpSelectPattern::Bool(false)),
+                "default" => Ok(BpSelectPattern::Default),
+                "any" if self.accept("@") => Ok(BpSelectPattern::Any(Some(self.expect_ident()?))),
+                "any" => Ok(BpSelectPattern::Any(None)),
+                _ => self.error(&token, "expected select pattern"),
+            },
+            _ => self.error(&token, "expected select pattern"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::testdata_directories;
+    use std::fs::read_to_string;
+
+    #[test]
+    fn parse_module() {
+        let contents = r#"// Module comment.
+rust_library {
+    name: "libfoo", // Trailing comment.
+    srcs: [
+        // First source.
+        "src/lib.rs",
+        "src/a\tb\x41é.rs", /* Second source. */
+        // End of sources.
+    ],
+    target: {
+        android: {
+            enabled: false,
+        },
+    },
+    /* Block comment. */
+}
+"#;
+        let file = parse(contents).unwrap();
+
+        let mut expected = BpModule::new("rust_library".to_string());
+        expected.comments = vec!["// Module comment.".to_string()];
+        expected.props.set("name", "libfoo");
+        let comment = |text: &str, trailing| BpValue::Comment { text: text.to_string(), trailing };
+        expected.props.set(
+            "srcs",
+            BpValue::List(vec![
+                comment("// First source.", false),
+                BpValue::String("src/lib.rs".to_string()),
+                BpValue::String("src/a\tbAé.rs".to_string()),
+                comment("/* Second source. */", true),
+                comment("// End of sources.", false),
+            ]),
+        );
+        expected.props.object("target").object("android").set("enabled", false);
+        expected
+            .props
+            .trailing_comments
+            .insert("name".to_string(), "// Trailing comment.".to_string());
+        expected.props.end_comments = vec!["/* Block comment. */".to_string()];
+        assert_eq!(file.definitions, vec![BpDefinition::Module(expected)]);
+
+        let mut output = String::new();
+        file.write(&mut output).unwrap();
+        // The escape sequence isn't kept, and a blank line is written after each module.
+        assert_eq!(output, contents.replace("\\x41", "A") + "\n");
+    }
+
+    #[test]
+    fn parse_variables_and_select() {
+        let file = parse(
+            r#"// Header.
+
+common_srcs = ["a.rs"]
+common_srcs += [`b.rs`]
+
+rust_test {
+    srcs: common_srcs + ["c.rs"],
+    rustlibs: select((release_flag("RELEASE_FOO"), arch()), {
+        (true, "arm64"): ["libfoo"],
+        (default, any @ a): unset,
+    }),
+}
+"#,
+        )
+        .unwrap();
+
+        let mut module = BpModule::new("rust_test".to_string());
+        module.props.set(
+            "srcs",
+            BpValue::Concat(vec![
+                BpValue::Variable("common_srcs".to_string()),
+                BpValue::List(vec![BpValue::String("c.rs".to_string())]),
+            ]),
+        );
+        module.props.set(
+            "rustlibs",
+            BpValue::Select(BpSelect {
+                conditions: vec![
+                    BpSelectCondition {
+                        function: "release_flag".to_string(),
+                        args: vec!["RELEASE_FOO".to_string()],
+                    },
+                    BpSelectCondition { function: "arch".to_string(), args: vec![] },
+                ],
+                cases: vec![
+                    (
+                        vec![
+                            BpSelectPattern::Bool(true),
+                            BpSelectPattern::String("arm64".to_string()),
+                        ],
+                        Some(vec!["libfoo"].into()),
+                    ),
+                    (
+                        vec![BpSelectPattern::Default, BpSelectPattern::Any(Some("a".to_string()))],
+                        None,
+                    ),
+                ],
+            }),
+        );
+        assert_eq!(
+            file.definitions,
+            vec![
+                BpDefinition::Comment(vec!["// Header.".to_string()]),
+                BpDefinition::Assignment {
+                    name: "common_srcs".to_string(),
+                    value: vec!["a.rs"].into(),
+                    append: false,
+                    comments: vec![],
+                },
+                BpDefinition::Assignment {
+                    name: "common_srcs".to_string(),
+                    value: vec!["b.rs"].into(),
+                    append: true,
+                    comments: vec![],
+                },
+                BpDefinition::Module(module),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            parse("rust_library {\n    name: \"foo\"\n    srcs: [],\n}\n").unwrap_err().to_string(),
+            "3:5: expected \",\" or \"}\", found Ident(\"srcs\")"
+        );
+        assert_eq!(parse("foo = \"unterminated\n").unwrap_err().to_string(), "1:7: invalid string");
+        assert_eq!(
+            parse("x = 99999999999999999999\n").unwrap_err().to_string(),
+            "1:5: integer out of range, found Int(\"99999999999999999999\")"
+        );
+        assert!(parse("rust_library {\n    name: \"foo\",\n").is_err());
+    }
+
+    #[test]
+    fn round_trip_testdata() {
+        for testdata_directory_path in testdata_directories() {
+            let contents = read_to_string(testdata_directory_path.join("expected_Android.bp"))
+                .expect("Failed to open expected_Android.bp");
+            let file = parse(&contents).unwrap();
+            let mut output = String::new();
+            file.write(&mut output).unwrap();
+            assert_eq!(output, contents, "{testdata_directory_path:?}");
+        }
+    }
+}
@@ -0,0 +1,386 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A cache of cargo output across runs of cargo_embargo, keyed by a hash of everything which might
+//! affect it.
+
+use crate::cargo::build_script::BUILD_SCRIPT_OUTPUT_FILE;
+use crate::config::VariantConfig;
+use crate::CargoOutput;
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Value};
+use std::env;
+use std::fs::{create_dir_all, read, read_dir, read_to_string, rename, write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Files which cargo_embargo itself writes in a package, which don't affect the cargo output.
+const GENERATED_FILES: &[&str] = &[
+    "Android.bp",
+    "rules.mk",
+    "BUILD.bazel",
+    "BUILD.gn",
+    "cargo_embargo.json",
+    BUILD_SCRIPT_OUTPUT_FILE,
+];
+
+/// The version of the cargo commands which cargo_embargo runs and the format in which it saves their
+/// output. This must be increased whenever either changes, so that entries written by older
+/// versions of cargo_embargo aren't reused.
+const CACHE_FORMAT_VERSION: u32 = 3;
+
+/// A 64-bit FNV-1a hasher. This is used rather than `DefaultHasher` as its output must be stable
+/// across builds of cargo_embargo.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Writes the given bytes preceded by their length, so that consecutive fields can't be
+    /// confused with each other.
+    fn write_field(&mut self, bytes: &[u8]) {
+        self.write(&bytes.len().to_le_bytes());
+        self.write(bytes);
+    }
+}
+
+/// Returns the directory in which to cache cargo output, under `$XDG_CACHE_HOME` or `~/.cache`.
+pub fn cache_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".cache")))?;
+    Some(base.join("cargo_embargo"))
+}
+
+/// Returns the versions of cargo and rustc on the `PATH`.
+pub fn toolchain_version() -> Result<String> {
+    let mut version = String::new();
+    for tool in ["cargo", "rustc"] {
+        let output = Command::new(tool)
+            .arg("-vV")
+            .output()
+            .with_context(|| format!("failed to run {tool} -vV"))?;
+        version += &String::from_utf8_lossy(&output.stdout);
+    }
+    Ok(version)
+}
+
+/// Returns the environment variables which may affect cargo and rustc, as sorted `NAME=value`
+/// lines.
+pub fn cargo_environment() -> String {
+    let mut variables: Vec<_> = env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value)))
+        .filter(|(name, _)| name.starts_with("CARGO_") || name.starts_with("RUST"))
+        .map(|(name, value)| format!("{name}={}\n", value.to_string_lossy()))
+        .collect();
+    variables.sort();
+    variables.concat()
+}
+
+/// Returns a hash of the package or workspace in `dir`, for use in cache keys.
+///
+/// This covers the paths and contents of all files except those which cargo_embargo generates, as
+/// build scripts and macros such as `include_str!` may read any of them. Hidden directories other
+/// than `.cargo`, `target` directories and `exclude_dir` are skipped.
+///
+/// Cargo config files in the parent directories of `dir` and in `$CARGO_HOME` are also covered, as
+/// cargo reads them too.
+pub fn hash_source_tree(dir: &Path, exclude_dir: &Path) -> Result<u64> {
+    let mut hasher = Fnv1a::new();
+    hash_dir(&mut hasher, dir, Path::new(""), exclude_dir)?;
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".cargo")));
+    let config_dirs = dir.ancestors().skip(1).map(|ancestor| ancestor.join(".cargo"));
+    for config_dir in config_dirs.chain(cargo_home) {
+        for name in ["config", "config.toml"] {
+            let path = config_dir.join(name);
+            if path.is_file() {
+                hasher.write_field(path.to_string_lossy().as_bytes());
+                hasher
+                    .write_field(&read(&path).with_context(|| format!("failed to read {path:?}"))?);
+            }
+        }
+    }
+    Ok(hasher.0)
+}
+
+/// Returns the path dependencies of the packages in `dir` which are outside it, and so aren't
+/// covered by [`hash_source_tree`], according to `cargo metadata`.
+pub fn external_path_dependencies(dir: &Path) -> Result<Vec<PathBuf>> {
+    let output = Command::new("cargo")
+        .args(["metadata", "-q", "--no-deps", "--format-version", "1"])
+        .current_dir(dir)
+        .output()
+        .context("failed to run cargo metadata")?;
+    if !output.status.success() {
+        bail!("cargo metadata failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    let metadata: Value =
+        serde_json::from_slice(&output.stdout).context("failed to parse cargo metadata")?;
+    let dir = dir.canonicalize().with_context(|| format!("failed to canonicalize {dir:?}"))?;
+    let mut dependencies = Vec::new();
+    for package in metadata["packages"].as_array().into_iter().flatten() {
+        for dependency in package["dependencies"].as_array().into_iter().flatten() {
+            let Some(path) = dependency["path"].as_str() else {
+                continue;
+            };
+            let path = Path::new(path).canonicalize().unwrap_or_else(|_| PathBuf::from(path));
+            if !path.starts_with(&dir) && !dependencies.contains(&path) {
+                dependencies.push(path);
+            }
+        }
+    }
+    Ok(dependencies)
+}
+
+fn hash_dir(hasher: &mut Fnv1a, root: &Path, relative: &Path, exclude_dir: &Path) -> Result<()> {
+    let dir = root.join(relative);
+    let mut entries = read_dir(&dir)
+        .with_context(|| format!("failed to list {dir:?}"))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to list {dir:?}"))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let Some(name_str) = name.to_str() else {
+            continue;
+        };
+        let path = entry.path();
+        let relative_path = relative.join(&name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if (name_str.starts_with('.') && name_str != ".cargo")
+                || name_str == "target"
+                || path == exclude_dir
+            {
+                continue;
+            }
+            hash_dir(hasher, root, &relative_path, exclude_dir)?;
+        } else if !GENERATED_FILES.contains(&name_str) && !name_str.ends_with(".rej") {
+            hasher.write_field(relative_path.to_string_lossy().as_bytes());
+            hasher.write_field(&read(&path).with_context(|| format!("failed to read {path:?}"))?);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the cache key for the cargo output of the given variant, or `None` if its output
+/// mustn't be cached.
+///
+/// Variants which copy build script outputs can't be cached, as the outputs are taken from the
+/// target directory rather than the cargo output.
+pub fn cache_key(
+    cfg: &VariantConfig,
+    source_tree_hash: u64,
+    toolchain_version: &str,
+    environment: &str,
+) -> Option<String> {
+    if cfg.package.values().any(|package_cfg| package_cfg.copy_out) {
+        return None;
+    }
+    // Only the options which affect which cargo commands are run.
+    let packages: serde_json::Map<_, _> = cfg
+        .package
+        .iter()
+        .map(|(name, package_cfg)| {
+            (
+                name.clone(),
+                json!({ "benches": package_cfg.benches, "examples": package_cfg.examples }),
+            )
+        })
+        .collect();
+    let options = json!({
+        "extra_cfg": cfg.extra_cfg,
+        "features": cfg.features,
+        "package": packages,
+        "run_cargo": cfg.run_cargo,
+        "targets": cfg.targets,
+        "tests": cfg.tests,
+        "workspace": cfg.workspace,
+        "workspace_excludes": cfg.workspace_excludes,
+    });
+
+    let mut hasher = Fnv1a::new();
+    hasher.write_field(&CACHE_FORMAT_VERSION.to_le_bytes());
+    hasher.write_field(&source_tree_hash.to_le_bytes());
+    hasher.write_field(toolchain_version.as_bytes());
+    hasher.write_field(environment.as_bytes());
+    hasher.write_field(options.to_string().as_bytes());
+    Some(format!("{:016x}", hasher.0))
+}
+
+/// Reads the cached cargo output with the given key from `cache_dir`, if there is one.
+pub fn read_cached(cache_dir: &Path, key: &str) -> Option<CargoOutput> {
+    let entry_dir = cache_dir.join(key);
+    Some(CargoOutput {
+        cargo_metadata: read_to_string(entry_dir.join("cargo.metadata")).ok()?,
+        cargo_out: read_to_string(entry_dir.join("cargo.out")).ok()?,
+    })
+}
+
+/// Writes the given cargo output to `cache_dir` with the given key.
+///
+/// The files are written to a temporary directory first and then moved into place, so that a
+/// concurrent run never sees a partial entry.
+pub fn write_cached(cache_dir: &Path, key: &str, cargo_output: &CargoOutput) -> Result<()> {
+    let entry_dir = cache_dir.join(key);
+    if entry_dir.exists() {
+        return Ok(());
+    }
+    create_dir_all(cache_dir).with_context(|| format!("failed to create {cache_dir:?}"))?;
+    let temp_dir = tempfile::tempdir_in(cache_dir)
+        .with_context(|| format!("failed to create temporary directory in {cache_dir:?}"))?;
+    write(temp_dir.path().join("cargo.metadata"), &cargo_output.cargo_metadata)?;
+    write(temp_dir.path().join("cargo.out"), &cargo_output.cargo_out)?;
+    // If the rename succeeds then the temporary directory no longer exists, so dropping it does
+    // nothing. Otherwise it is removed when dropped.
+    if let Err(e) = rename(temp_dir.path(), &entry_dir) {
+        // Another run may have added the same entry in the meantime.
+        if !entry_dir.exists() {
+            return Err(e).with_context(|| format!("failed to move cache entry to {entry_dir:?}"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PackageVariantConfig;
+
+    #[test]
+    fn fnv1a() {
+        let mut hasher = Fnv1a::new();
+        hasher.write(b"");
+        assert_eq!(hasher.0, 0xcbf29ce484222325);
+        hasher.write(b"a");
+        assert_eq!(hasher.0, 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn cache_key_depends_on_relevant_options() {
+        let cfg = VariantConfig::default();
+        let key = cache_key(&cfg, 42, "cargo 1.0", "").unwrap();
+
+        assert_eq!(cache_key(&cfg, 42, "cargo 1.0", ""), Some(key.clone()));
+        assert_ne!(cache_key(&cfg, 43, "cargo 1.0", ""), Some(key.clone()));
+        assert_ne!(cache_key(&cfg, 42, "cargo 1.1", ""), Some(key.clone()));
+        assert_ne!(
+            cache_key(&cfg, 42, "cargo 1.0", "RUSTFLAGS=-Copt-level=3\n"),
+            Some(key.clone())
+        );
+        let with_tests = VariantConfig { tests: true, ..Default::default() };
+        assert_ne!(cache_key(&with_tests, 42, "cargo 1.0", ""), Some(key.clone()));
+        let with_apex = VariantConfig { apex_available: vec![], ..Default::default() };
+        assert_eq!(cache_key(&with_apex, 42, "cargo 1.0", ""), Some(key));
+
+        let with_copy_out = VariantConfig {
+            package: [(
+                "foo".to_string(),
+                PackageVariantConfig { copy_out: true, ..Default::default() },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        assert_eq!(cache_key(&with_copy_out, 42, "cargo 1.0", ""), None);
+    }
+
+    #[test]
+    fn hash_source_tree_ignores_generated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let intermediates_dir = dir.path().join("intermediates");
+        create_dir_all(dir.path().join("src")).unwrap();
+        write(dir.path().join("Cargo.toml"), "[package]\nname = \"foo\"\n").unwrap();
+        write(dir.path().join("src/lib.rs"), "").unwrap();
+        let hash = hash_source_tree(dir.path(), &intermediates_dir).unwrap();
+
+        write(dir.path().join("Android.bp"), "rust_library {}\n").unwrap();
+        create_dir_all(intermediates_dir.join("variant_0")).unwrap();
+        write(intermediates_dir.join("variant_0/cargo.out"), "").unwrap();
+        create_dir_all(dir.path().join("target/debug")).unwrap();
+        write(dir.path().join("target/debug/foo"), "").unwrap();
+        assert_eq!(hash_source_tree(dir.path(), &intermediates_dir).unwrap(), hash);
+
+        create_dir_all(dir.path().join(".git")).unwrap();
+        write(dir.path().join(".git/index"), "").unwrap();
+        assert_eq!(hash_source_tree(dir.path(), &intermediates_dir).unwrap(), hash);
+
+        write(dir.path().join("src/lib.rs"), "pub fn foo() {}\n").unwrap();
+        let hash = hash_source_tree(dir.path(), &intermediates_dir).unwrap();
+        // Any file may be compiled by the build script or included in a crate.
+        write(dir.path().join("src/foo.c"), "").unwrap();
+        let with_c = hash_source_tree(dir.path(), &intermediates_dir).unwrap();
+        assert_ne!(with_c, hash);
+        write(dir.path().join("src/foo.c"), "int foo;\n").unwrap();
+        assert_ne!(hash_source_tree(dir.path(), &intermediates_dir).unwrap(), with_c);
+        create_dir_all(dir.path().join(".cargo")).unwrap();
+        write(dir.path().join(".cargo/config"), "[build]\n").unwrap();
+        let with_config = hash_source_tree(dir.path(), &intermediates_dir).unwrap();
+        assert_ne!(with_config, hash);
+        write(dir.path().join(".cargo/config"), "[build]\nrustflags = [\"-Dwarnings\"]\n").unwrap();
+        assert_ne!(hash_source_tree(dir.path(), &intermediates_dir).unwrap(), with_config);
+    }
+
+    #[test]
+    fn find_external_path_dependencies() {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, dependencies) in [
+            ("foo", "bar = { path = \"bar\" }\nbaz = { path = \"../baz\" }\n"),
+            ("foo/bar", ""),
+            ("baz", ""),
+        ] {
+            let package_dir = dir.path().join(name);
+            create_dir_all(package_dir.join("src")).unwrap();
+            let package_name = name.rsplit('/').next().unwrap();
+            write(
+                package_dir.join("Cargo.toml"),
+                format!("[package]\nname = \"{package_name}\"\n\n[dependencies]\n{dependencies}"),
+            )
+            .unwrap();
+            write(package_dir.join("src/lib.rs"), "").unwrap();
+        }
+
+        assert_eq!(
+            external_path_dependencies(&dir.path().join("foo")).unwrap(),
+            vec![dir.path().join("baz").canonicalize().unwrap()]
+        );
+        assert_eq!(
+            external_path_dependencies(&dir.path().join("baz")).unwrap(),
+            Vec::<PathBuf>::new()
+        );
+    }
+
+    #[test]
+    fn read_written_cache_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cargo_output =
+            CargoOutput { cargo_metadata: "{}".to_string(), cargo_out: "Running".to_string() };
+
+        assert_eq!(read_cached(dir.path(), "0123"), None);
+        write_cached(dir.path(), "0123", &cargo_output).unwrap();
+        assert_eq!(read_cached(dir.path(), "0123"), Some(cargo_output));
+    }
+}
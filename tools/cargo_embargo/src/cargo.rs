@@ -14,8 +14,11 @@
 
 //! Types and functions for parsing the output of cargo.
 
+pub mod build_script;
 pub mod cargo_out;
+pub mod cfg;
 pub mod metadata;
+pub mod unit_graph;
 
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
@@ -37,6 +40,10 @@ pub enum CrateType {
     Test,
     // "--cfg test" without --test. (Assume it is a test with the harness disabled.
     TestNoHarness,
+    // A `[[bench]]` target, with or without the harness.
+    Bench,
+    // An `[[example]]` binary target.
+    Example,
 }
 
 impl CrateType {
@@ -60,9 +67,9 @@ impl CrateType {
         matches!(self, Self::Lib | Self::RLib | Self::DyLib | Self::CDyLib | Self::StaticLib)
     }
 
-    /// Returns whether the crate type is a kind of test.
+    /// Returns whether the crate type is a kind of test, including benchmarks.
     pub fn is_test(self) -> bool {
-        matches!(self, Self::Test | Self::TestNoHarness)
+        matches!(self, Self::Test | Self::TestNoHarness | Self::Bench)
     }
 
     /// Returns whether the crate type is a kind of C ABI library.
@@ -97,6 +104,36 @@ pub struct Crate {
     pub license_file: Option<String>,
     /// Whether it is a test crate which doesn't actually contain any tests or benchmarks.
     pub empty_test: bool,
+    /// Static C libraries built by the package's build script which this crate links against.
+    #[serde(default)]
+    pub cc_libs: Vec<CcLibrary>,
+    /// Warnings which rustc printed when building the crate, summarised to one line each.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+impl Crate {
+    /// Returns whether the crate is the same as `other` other than its rustc warnings, which are
+    /// only known when cargo is run, and may differ between builds of the same crate.
+    pub fn eq_ignoring_warnings(&self, other: &Crate) -> bool {
+        Crate { warnings: Vec::new(), ..self.clone() }
+            == Crate { warnings: Vec::new(), ..other.clone() }
+    }
+}
+
+/// A static C library built by a build script, e.g. using the `cc` crate.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CcLibrary {
+    /// Name of the library, without the `lib` prefix or `.a` suffix.
+    pub name: String,
+    /// Source files, relative to the package directory.
+    pub srcs: Vec<PathBuf>,
+    /// Include directories, relative to the package directory.
+    pub include_dirs: Vec<PathBuf>,
+    /// Preprocessor definitions, in the form passed to `-D`.
+    pub defines: Vec<String>,
+    /// Other compiler flags.
+    pub flags: Vec<String>,
 }
 
 /// A dependency of a Rust crate.
@@ -0,0 +1,178 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Recording the output of build scripts, so that it can be used without running cargo.
+
+use super::Crate;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Name of the file in a package directory to which its build script output is recorded.
+pub const BUILD_SCRIPT_OUTPUT_FILE: &str = "cargo_embargo_build_script.json";
+
+/// The instructions printed by a package's build script which affect how its crates are built.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct BuildScriptDirectives {
+    /// From `cargo:rustc-cfg`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cfgs: Vec<String>,
+    /// From `cargo:rustc-link-lib=static=...`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub static_libs: Vec<String>,
+    /// From `cargo:rustc-link-lib`, for other kinds of library.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_libs: Vec<String>,
+    /// From `cargo:rustc-env`. These are recorded for reference, but never replayed, as the generated
+    /// build files have no way to set arbitrary environment variables for rustc.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl BuildScriptDirectives {
+    /// Parses the `output` file which cargo writes for a build script run.
+    pub fn parse(output: &str) -> Self {
+        let mut directives = Self::default();
+        for line in output.lines() {
+            let Some(directive) =
+                line.strip_prefix("cargo::").or_else(|| line.strip_prefix("cargo:"))
+            else {
+                continue;
+            };
+            let Some((key, value)) = directive.split_once('=') else {
+                continue;
+            };
+            match key {
+                "rustc-cfg" => directives.cfgs.push(value.to_string()),
+                "rustc-link-lib" => match value.split_once('=') {
+                    Some((kind, lib)) if kind.starts_with("static") => {
+                        directives.static_libs.push(lib.to_string())
+                    }
+                    Some((_, lib)) => directives.shared_libs.push(lib.to_string()),
+                    None => directives.shared_libs.push(value.to_string()),
+                },
+                "rustc-env" => {
+                    if let Some((name, value)) = value.split_once('=') {
+                        directives.env.insert(name.to_string(), value.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        directives
+    }
+
+    /// Reads the directives recorded for the package in `package_dir`, if there are any.
+    pub fn read(package_dir: &Path) -> Result<Option<Self>> {
+        let path = package_dir.join(BUILD_SCRIPT_OUTPUT_FILE);
+        match read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .with_context(|| format!("failed to parse {path:?}"))
+                .map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read {path:?}")),
+        }
+    }
+
+    /// Records the directives for the package in `package_dir`.
+    pub fn write(&self, package_dir: &Path) -> Result<()> {
+        let path = package_dir.join(BUILD_SCRIPT_OUTPUT_FILE);
+        write(&path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("failed to write {path:?}"))
+    }
+
+    /// Applies the directives to a crate of the package, as cargo would when building it.
+    pub fn apply(&self, crate_: &mut Crate) {
+        crate_.cfgs.extend(self.cfgs.iter().cloned());
+        crate_.cfgs.sort();
+        crate_.cfgs.dedup();
+        crate_.static_libs.extend(self.static_libs.iter().cloned());
+        crate_.shared_libs.extend(self.shared_libs.iter().cloned());
+    }
+}
+
+/// Finds the build script outputs in the given target directory, and returns the directives from
+/// them for each package name.
+///
+/// A package may have several outputs, e.g. if it is built for several targets.
+pub fn find_build_script_directives(
+    target_dir: &Path,
+) -> Result<BTreeMap<String, Vec<BuildScriptDirectives>>> {
+    let output_glob = target_dir.join("**/build/*/output");
+    let output_glob = output_glob
+        .to_str()
+        .ok_or_else(|| anyhow!("failed to convert target dir path {target_dir:?} to string"))?;
+    let mut directives: BTreeMap<String, Vec<BuildScriptDirectives>> = BTreeMap::new();
+    for path in glob::glob(output_glob)? {
+        let path: PathBuf = path?;
+        // Example: target.tmp/x86_64-unknown-linux-gnu/debug/build/metrics-d2dd799cebf1888d/output
+        let package_name = || -> Option<_> {
+            let dir_name = path.parent()?.file_name()?.to_str()?;
+            Some(dir_name.rsplit_once('-')?.0.to_string())
+        }()
+        .ok_or_else(|| anyhow!("failed to parse build script output path {path:?}"))?;
+        let output = read_to_string(&path).with_context(|| format!("failed to read {path:?}"))?;
+        directives.entry(package_name).or_default().push(BuildScriptDirectives::parse(&output));
+    }
+    Ok(directives)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_output() {
+        let directives = BuildScriptDirectives::parse(
+            "cargo:rerun-if-changed=build.rs
+cargo:rustc-cfg=has_foo
+cargo::rustc-cfg=version=\"1.2\"
+cargo:rustc-check-cfg=cfg(has_foo)
+cargo:rustc-link-lib=static=bar
+cargo:rustc-link-lib=dylib=baz
+cargo:rustc-link-lib=m
+cargo:rustc-env=FOO_VERSION=1.2
+some other output
+",
+        );
+        assert_eq!(
+            directives,
+            BuildScriptDirectives {
+                cfgs: vec!["has_foo".to_string(), "version=\"1.2\"".to_string()],
+                static_libs: vec!["bar".to_string()],
+                shared_libs: vec!["baz".to_string(), "m".to_string()],
+                env: [("FOO_VERSION".to_string(), "1.2".to_string())].into_iter().collect(),
+            }
+        );
+    }
+
+    #[test]
+    fn write_and_read() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert_eq!(BuildScriptDirectives::read(dir.path())?, None);
+
+        let directives =
+            BuildScriptDirectives { cfgs: vec!["has_foo".to_string()], ..Default::default() };
+        directives.write(dir.path())?;
+        assert_eq!(
+            read_to_string(dir.path().join(BUILD_SCRIPT_OUTPUT_FILE))?,
+            "{\n  \"cfgs\": [\n    \"has_foo\"\n  ]\n}\n"
+        );
+        assert_eq!(BuildScriptDirectives::read(dir.path())?, Some(directives));
+        Ok(())
+    }
+}
@@ -12,8 +12,9 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::metadata::WorkspaceMetadata;
-use super::{Crate, CrateType, Extern, ExternType};
+use super::metadata::{targets_without_harness, PackageMetadata, TargetKind, WorkspaceMetadata};
+use super::unit_graph::{Artifact, BuildScriptOutput, Message, Unit, UnitGraph};
+use super::{CcLibrary, Crate, CrateType, Extern, ExternType};
 use crate::CargoOutput;
 use anyhow::anyhow;
 use anyhow::bail;
@@ -21,62 +22,334 @@ use anyhow::Context;
 use anyhow::Result;
 use log::debug;
 use regex::Regex;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
-use std::path::Path;
 use std::path::PathBuf;
+use std::path::{Component, Path};
 use std::sync::LazyLock;
 
 /// Reads the given `cargo.out` and `cargo.metadata` files, and generates a list of crates based on
-/// the rustc invocations.
+/// the unit graph and JSON messages from cargo if they are present, or otherwise the rustc
+/// invocations.
+///
+/// `extra_cfg` is the `cfg` flags which were passed to rustc through `RUSTFLAGS`, which aren't
+/// included in the unit graph.
 ///
 /// Ignores crates outside the current directory and build script crates.
-pub fn parse_cargo_out(cargo_output: &CargoOutput) -> Result<Vec<Crate>> {
+pub fn parse_cargo_out(cargo_output: &CargoOutput, extra_cfg: &[String]) -> Result<Vec<Crate>> {
     let metadata = serde_json::from_str(&cargo_output.cargo_metadata)
         .context("failed to parse cargo metadata")?;
     parse_cargo_out_str(
         &cargo_output.cargo_out,
         &metadata,
+        extra_cfg,
         env::current_dir().unwrap().canonicalize().unwrap(),
     )
 }
 
 /// Parses the given `cargo.out` and `cargo.metadata` file contents and generates a list of crates
-/// based on the rustc invocations.
+/// based on the unit graph or the rustc invocations.
 ///
 /// Ignores crates outside `base_directory` and build script crates.
 fn parse_cargo_out_str(
     cargo_out: &str,
     metadata: &WorkspaceMetadata,
+    extra_cfg: &[String],
     base_directory: impl AsRef<Path>,
 ) -> Result<Vec<Crate>> {
     let cargo_out = CargoOut::parse(cargo_out).context("failed to parse cargo.out")?;
     debug!("Parsed cargo output: {:?}", cargo_out);
 
-    assert!(cargo_out.cc_invocations.is_empty(), "cc not supported yet");
-    assert!(cargo_out.ar_invocations.is_empty(), "ar not supported yet");
+    let all_crates = if cargo_out.unit_graphs.is_empty() {
+        crates_from_rustc_invocations(&cargo_out, metadata)?
+    } else {
+        crates_from_unit_graphs(&cargo_out, metadata, extra_cfg)?
+    };
+    let mut crates: Vec<Crate> = Vec::new();
+    for c in all_crates {
+        // Ignore build.rs crates.
+        if c.name.starts_with("build_script_") {
+            continue;
+        }
+        // Ignore crates outside the base directory.
+        if !c.package_dir.starts_with(&base_directory) {
+            continue;
+        }
+        // The same crate may be built by several cargo commands.
+        if let Some(existing) = crates.iter_mut().find(|existing| existing.eq_ignoring_warnings(&c))
+        {
+            existing.warnings.extend(c.warnings);
+            dedup_in_order(&mut existing.warnings);
+        } else {
+            crates.push(c);
+        }
+    }
 
+    // Attach the C libraries built by each package's build script to the crates which link them.
+    for c in &mut crates {
+        c.cc_libs = cc_libraries_from_invocations(
+            cargo_out.cc_invocations.get(&c.package_name).map_or(&[], Vec::as_slice),
+            cargo_out.ar_invocations.get(&c.package_name).map_or(&[], Vec::as_slice),
+            &c.package_dir,
+            &c.static_libs,
+        )
+        .with_context(|| format!("failed to process cc invocations for {}", c.package_name))?;
+    }
+    Ok(crates)
+}
+
+/// Returns the crates built by the rustc invocations in the given cargo output.
+fn crates_from_rustc_invocations(
+    cargo_out: &CargoOut,
+    metadata: &WorkspaceMetadata,
+) -> Result<Vec<Crate>> {
     let mut raw_names = BTreeMap::new();
     for rustc in cargo_out.rustc_invocations.iter() {
         raw_name_from_rustc_invocation(rustc, &mut raw_names)
     }
 
+    cargo_out
+        .rustc_invocations
+        .iter()
+        .map(|rustc| {
+            Crate::from_rustc_invocation(rustc, metadata, cargo_out, &raw_names)
+                .with_context(|| format!("failed to process rustc invocation: {rustc}"))
+        })
+        .collect()
+}
+
+/// Returns the crates built by the units in the unit graphs in the given cargo output.
+fn crates_from_unit_graphs(
+    cargo_out: &CargoOut,
+    metadata: &WorkspaceMetadata,
+    extra_cfg: &[String],
+) -> Result<Vec<Crate>> {
     let mut crates = Vec::new();
-    for rustc in cargo_out.rustc_invocations.iter() {
-        let c = Crate::from_rustc_invocation(rustc, metadata, &cargo_out.tests, &raw_names)
-            .with_context(|| format!("failed to process rustc invocation: {rustc}"))?;
-        // Ignore build.rs crates.
-        if c.name.starts_with("build_script_") {
+    for unit_graph in &cargo_out.unit_graphs {
+        for unit in &unit_graph.units {
+            if unit.is_build_script() || !matches!(unit.mode.as_str(), "build" | "test" | "bench") {
+                continue;
+            }
+            crates.push(
+                Crate::from_unit(unit, unit_graph, cargo_out, metadata, extra_cfg).with_context(
+                    || format!("failed to process unit {} of {}", unit.target.name, unit.pkg_id),
+                )?,
+            );
+        }
+    }
+    Ok(crates)
+}
+
+/// A single `cc` invocation which compiles one source file to an object file.
+///
+/// Paths are as given to `cc`, which may be outside the package.
+#[derive(Debug, Default)]
+struct CcCompile<'a> {
+    src: Option<&'a str>,
+    include_dirs: Vec<&'a str>,
+    defines: Vec<String>,
+    flags: Vec<String>,
+}
+
+/// Compiler options whose value is given as a separate argument after them.
+const CC_OPTIONS_WITH_VALUE: [&str; 14] = [
+    "-o",
+    "-I",
+    "-D",
+    "-U",
+    "-x",
+    "-include",
+    "-imacros",
+    "-isystem",
+    "-iquote",
+    "-idirafter",
+    "-MF",
+    "-MT",
+    "-MQ",
+    "-Xclang",
+];
+
+/// Compiler flags which Soong sets itself, so should not be copied to `cflags`.
+fn is_ignored_cc_flag(flag: &str) -> bool {
+    flag.starts_with("-O")
+        || flag.starts_with("-g")
+        || flag.starts_with("-m")
+        || flag.starts_with("--target=")
+        || [
+            "-ffunction-sections",
+            "-fdata-sections",
+            "-fPIC",
+            "-fno-omit-frame-pointer",
+            "-Wall",
+            "-Wextra",
+        ]
+        .contains(&flag)
+}
+
+/// Returns the given path from a build script invocation relative to `package_dir`, or `None` if
+/// it is outside the package (e.g. in `OUT_DIR`).
+///
+/// Relative paths are assumed to be relative to the package directory already, as that is the
+/// working directory for build scripts.
+fn relative_to_package(path: &str, package_dir: &Path) -> Option<PathBuf> {
+    let path = Path::new(path);
+    let relative = if path.is_absolute() {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        path.strip_prefix(package_dir).ok()?.to_owned()
+    } else {
+        path.to_owned()
+    };
+    Some(relative.components().filter(|c| *c != Component::CurDir).collect())
+}
+
+/// Returns the output file of a `cc` invocation, or `None` if it has no `-o` argument.
+fn cc_output<'a>(args: &[&'a str]) -> Option<&'a str> {
+    let index = args.iter().position(|arg| *arg == "-o")?;
+    args.get(index + 1).copied()
+}
+
+/// Parses the arguments of a `cc` invocation.
+fn parse_cc_invocation<'a>(args: &[&'a str]) -> Result<CcCompile<'a>> {
+    let mut out = CcCompile::default();
+    let mut arg_iter = args.iter().copied();
+    while let Some(arg) = arg_iter.next() {
+        if CC_OPTIONS_WITH_VALUE.contains(&arg) {
+            let value = arg_iter.next().with_context(|| format!("missing {arg} argument"))?;
+            match arg {
+                "-I" | "-isystem" | "-iquote" | "-idirafter" => out.include_dirs.push(value),
+                "-D" => out.defines.push(value.to_string()),
+                "-U" | "-x" | "-Xclang" => out.flags.extend([arg.to_string(), value.to_string()]),
+                "-include" | "-imacros" => {
+                    eprintln!("WARNING: ignoring {arg} {value} in cc invocation");
+                }
+                // The output and dependency files are up to the build system.
+                _ => {}
+            }
             continue;
         }
-        // Ignore crates outside the base directory.
-        if !c.package_dir.starts_with(&base_directory) {
+        match arg {
+            // Soong compiles each source file separately anyway.
+            "-c" => {}
+            _ if arg.starts_with("-I") => out.include_dirs.push(&arg[2..]),
+            _ if arg.starts_with("-D") => out.defines.push(arg[2..].to_string()),
+            _ if is_ignored_cc_flag(arg) => {}
+            _ if arg.starts_with('-') => out.flags.push(arg.to_string()),
+            _ => {
+                if let Some(src) = out.src {
+                    bail!("more than one source file in cc invocation: {src} and {arg}");
+                }
+                out.src = Some(arg);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Given the `cc` and `ar` invocations from a package's build script, returns those of the static
+/// libraries which they build whose names are in `static_libs`.
+///
+/// Only the `cc` invocations for the members of these libraries are parsed. Others, such as those
+/// without an output file which are probing for compiler features, are ignored. Libraries which are
+/// built from sources outside the package, e.g. generated by the build script in `OUT_DIR`, are
+/// skipped with a warning.
+fn cc_libraries_from_invocations(
+    cc_invocations: &[String],
+    ar_invocations: &[String],
+    package_dir: &Path,
+    static_libs: &[String],
+) -> Result<Vec<CcLibrary>> {
+    let mut compiles = BTreeMap::new();
+    for cc in cc_invocations {
+        let args = args_from_rustc_invocation(cc);
+        if let Some(output) = cc_output(&args) {
+            compiles.insert(output, args);
+        }
+    }
+
+    // The members of each archive, in the order in which the archives are first created.
+    let mut archives: Vec<(&str, Vec<&str>)> = Vec::new();
+    for ar in ar_invocations {
+        let args = args_from_rustc_invocation(ar);
+        // Only `ar` operations which add members are interesting, e.g. `cq` or `crs`, not `s`.
+        let [operation, archive, members @ ..] = &args[..] else {
+            continue;
+        };
+        if !operation.contains('q') && !operation.contains('r') {
             continue;
         }
-        crates.push(c);
+        let Some(name) = Path::new(*archive)
+            .file_name()
+            .and_then(|f| f.to_str())
+            .and_then(|f| f.strip_prefix("lib"))
+            .and_then(|f| f.strip_suffix(".a"))
+        else {
+            bail!("unexpected archive name {archive:?}");
+        };
+        if !static_libs.iter().any(|lib| lib == name) {
+            continue;
+        }
+        if let Some((_, archive_members)) = archives.iter_mut().find(|(n, _)| *n == name) {
+            archive_members.extend(members);
+        } else {
+            archives.push((name, members.to_vec()));
+        }
     }
-    crates.dedup();
-    Ok(crates)
+
+    let mut libs = Vec::new();
+    for (name, members) in archives {
+        if let Some(lib) = cc_library(name, &members, &compiles, package_dir)? {
+            libs.push(lib);
+        }
+    }
+    Ok(libs)
+}
+
+/// Returns the static library with the given name built from the given object files, or `None` if
+/// any of their sources are outside the package.
+///
+/// `compiles` are the arguments of the `cc` invocations, keyed by their output file.
+fn cc_library(
+    name: &str,
+    members: &[&str],
+    compiles: &BTreeMap<&str, Vec<&str>>,
+    package_dir: &Path,
+) -> Result<Option<CcLibrary>> {
+    let mut lib = CcLibrary { name: name.to_string(), ..Default::default() };
+    for member in members {
+        let Some(args) = compiles.get(member) else {
+            bail!("no cc invocation found for {member:?} in lib{name}.a");
+        };
+        let compile = parse_cc_invocation(args)?;
+        let src = compile.src.with_context(|| format!("no source file for {member:?}"))?;
+        let Some(src) = relative_to_package(src, package_dir) else {
+            eprintln!(
+                "WARNING: skipping static library {name} as its source file {src} is outside the \
+                 package, e.g. generated by the build script"
+            );
+            return Ok(None);
+        };
+        lib.srcs.push(src);
+        for dir in compile.include_dirs {
+            if let Some(dir) = relative_to_package(dir, package_dir) {
+                lib.include_dirs.push(dir);
+            } else {
+                eprintln!("WARNING: ignoring include directory outside package: {dir}");
+            }
+        }
+        lib.defines.extend(compile.defines);
+        lib.flags.extend(compile.flags);
+    }
+    dedup_in_order(&mut lib.srcs);
+    dedup_in_order(&mut lib.include_dirs);
+    dedup_in_order(&mut lib.defines);
+    dedup_in_order(&mut lib.flags);
+    Ok(Some(lib))
+}
+
+/// Removes duplicate elements from the given vector, keeping the first occurrence of each.
+fn dedup_in_order<T: Clone + Ord>(items: &mut Vec<T>) {
+    let mut seen = BTreeSet::new();
+    items.retain(|item| seen.insert(item.clone()));
 }
 
 fn args_from_rustc_invocation(rustc: &str) -> Vec<&str> {
@@ -108,6 +381,17 @@ fn args_from_rustc_invocation(rustc: &str) -> Vec<&str> {
     args
 }
 
+/// Returns the name of the crate as it appears in the path to its main source file, which may use
+/// hyphens rather than underscores, or the crate name itself if it doesn't appear.
+fn raw_name_from_src_path(crate_name: &str, src_path: &str) -> String {
+    let snake_case_path = src_path.replace('-', "_");
+    if let Some(idx) = snake_case_path.rfind(crate_name) {
+        src_path[idx..idx + crate_name.len()].to_string()
+    } else {
+        crate_name.to_string()
+    }
+}
+
 /// Parse out the path name for a crate from a rustc invocation
 fn raw_name_from_rustc_invocation(rustc: &str, raw_names: &mut BTreeMap<String, String>) {
     let mut crate_name = String::new();
@@ -120,12 +404,9 @@ fn raw_name_from_rustc_invocation(rustc: &str, raw_names: &mut BTreeMap<String,
             "--crate-name" => crate_name = arg_iter.next().unwrap().to_string(),
             _ if arg.ends_with(".rs") => {
                 assert_ne!(crate_name, "", "--crate-name option should precede input");
-                let snake_case_arg = arg.replace('-', "_");
-                if let Some(idx) = snake_case_arg.rfind(&crate_name) {
-                    let raw_name = arg[idx..idx + crate_name.len()].to_string();
-                    if crate_name != raw_name {
-                        raw_names.insert(crate_name, raw_name);
-                    }
+                let raw_name = raw_name_from_src_path(&crate_name, arg);
+                if crate_name != raw_name {
+                    raw_names.insert(crate_name, raw_name);
                 }
                 break;
             }
@@ -146,14 +427,18 @@ struct TestContents {
 struct CargoOut {
     rustc_invocations: Vec<String>,
 
-    // package name => cmd args
-    cc_invocations: BTreeMap<String, String>,
-    ar_invocations: BTreeMap<String, String>,
+    // From `cargo build --unit-graph` and `cargo build --message-format=json`.
+    unit_graphs: Vec<UnitGraph>,
+    artifacts: Vec<Artifact>,
+    build_script_outputs: Vec<BuildScriptOutput>,
 
-    // lines starting with "warning: ".
-    // line number => line
-    warning_lines: BTreeMap<usize, String>,
-    warning_files: Vec<String>,
+    // package name => cmd args of each invocation
+    cc_invocations: BTreeMap<String, Vec<String>>,
+    ar_invocations: BTreeMap<String, Vec<String>>,
+
+    // (package ID or name, unit description) => warnings from rustc
+    // The package is identified by its ID in JSON messages, or by name in plain text output.
+    warnings: BTreeMap<(String, String), Vec<String>>,
 
     // output filename => test filename => whether it contains any tests or benchmarks
     tests: BTreeMap<String, BTreeMap<PathBuf, TestContents>>,
@@ -162,6 +447,44 @@ struct CargoOut {
     test_errors: Vec<String>,
 }
 
+/// Returns the commands run by the build scripts which were executed according to the given
+/// `cargo build --message-format=json` output, read from the `output` files that cargo saves next
+/// to their `OUT_DIR`s.
+///
+/// Each line is prefixed with the package name and version, as `cargo build -vv` would print it
+/// without `--message-format=json`, so that the `cc` and `ar` invocations can be parsed from it.
+pub fn build_script_logs(json_output: &str) -> Result<String> {
+    let mut logs = String::new();
+    for line in json_output.lines().filter(|line| line.starts_with("{\"reason\":")) {
+        let Ok(Message::BuildScriptExecuted(output)) = serde_json::from_str(line) else {
+            continue;
+        };
+        let Some(output_path) = output.out_dir.parent().map(|dir| dir.join("output")) else {
+            continue;
+        };
+        let contents = std::fs::read_to_string(&output_path)
+            .with_context(|| format!("failed to read build script output {output_path:?}"))?;
+        let (name, version) = package_name_and_version(&output.package_id);
+        for command in contents.lines().filter(|line| line.starts_with("running")) {
+            logs += &format!("[{name} {version}] {command}\n");
+        }
+    }
+    Ok(logs)
+}
+
+/// Returns the package name and version from the given cargo package ID, either in the old
+/// `name version (source)` format or the newer `source#name@version` or `source#version` format.
+fn package_name_and_version(package_id: &str) -> (&str, &str) {
+    if let Some((source, fragment)) = package_id.rsplit_once('#') {
+        fragment.split_once('@').unwrap_or_else(|| {
+            (source.trim_end_matches('/').rsplit('/').next().unwrap_or_default(), fragment)
+        })
+    } else {
+        let mut parts = package_id.split(' ');
+        (parts.next().unwrap_or_default(), parts.next().unwrap_or_default())
+    }
+}
+
 fn match1(regex: &Regex, s: &str) -> Option<String> {
     regex.captures(s).and_then(|x| x.get(1)).map(|x| x.as_str().to_string())
 }
@@ -181,10 +504,79 @@ impl CargoOut {
         let mut result = CargoOut::default();
         let mut in_tests = false;
         let mut cur_test_key = None;
+        // Warnings which haven't yet been attributed to a unit.
+        let mut pending_json_warnings: BTreeMap<(String, PathBuf), Vec<String>> = BTreeMap::new();
+        let mut pending_warnings: Vec<String> = Vec::new();
+        let mut last_warning_line = None;
         let mut lines_iter = contents.lines().enumerate();
         while let Some((n, line)) = lines_iter.next() {
-            if line.starts_with("warning: ") {
-                result.warning_lines.insert(n, line.to_string());
+            // JSON output from `cargo build --unit-graph`.
+            if line.starts_with("{\"version\":") {
+                result
+                    .unit_graphs
+                    .push(serde_json::from_str(line).context("failed to parse cargo unit graph")?);
+                continue;
+            }
+            // JSON messages from `cargo build --message-format=json`.
+            if line.starts_with("{\"reason\":") {
+                match serde_json::from_str(line)
+                    .with_context(|| format!("failed to parse cargo message: {line}"))?
+                {
+                    Message::CompilerArtifact(artifact) => {
+                        let key = (artifact.package_id.clone(), artifact.target.src_path.clone());
+                        if let Some(warnings) = pending_json_warnings.remove(&key) {
+                            let description = unit_description(
+                                &artifact.target.kind,
+                                &artifact.target.name,
+                                artifact.profile.test,
+                            );
+                            result
+                                .warnings
+                                .entry((artifact.package_id.clone(), description))
+                                .or_default()
+                                .extend(warnings);
+                        }
+                        result.artifacts.push(artifact)
+                    }
+                    Message::CompilerMessage(message) => {
+                        if let Some(warning) = message.message.warning_summary() {
+                            pending_json_warnings
+                                .entry((message.package_id, message.target.src_path))
+                                .or_default()
+                                .push(warning);
+                        }
+                    }
+                    Message::BuildScriptExecuted(output) => {
+                        result.build_script_outputs.push(output)
+                    }
+                    Message::Other => {}
+                }
+                continue;
+            }
+
+            // Cargo prints a summary after the warnings from each unit, e.g.
+            // warning: `foo` (lib test) generated 2 warnings (1 duplicate)
+            static WARNING_SUMMARY_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+                Regex::new(r"^warning: `(.+)` \((.+)\) generated (\d+) warnings?(?: \((\d+) duplicates?\))?")
+                    .unwrap()
+            });
+            if let Some(captures) = WARNING_SUMMARY_REGEX.captures(line) {
+                let count = |i| captures.get(i).map_or(0, |m| m.as_str().parse::<usize>().unwrap());
+                // Duplicate warnings aren't printed again.
+                let printed = count(3).saturating_sub(count(4));
+                let warnings =
+                    pending_warnings.split_off(pending_warnings.len().saturating_sub(printed));
+                pending_warnings.clear();
+                result
+                    .warnings
+                    .entry((captures[1].to_string(), captures[2].to_string()))
+                    .or_default()
+                    .extend(warnings);
+                continue;
+            }
+            if let Some(warning) = line.strip_prefix("warning: ") {
+                pending_warnings.push(warning.to_string());
+                last_warning_line = Some(n);
                 continue;
             }
 
@@ -225,24 +617,30 @@ impl CargoOut {
                 }
                 continue;
             }
-            // Cargo -vv output of a "cc" or "ar" command; all in one line.
+            // Cargo -vv output of a "cc" or "ar" command; all in one line. The command may be
+            // preceded by environment variables, e.g. `ZERO_AR_DATE="1" "ar" "cq" ...`.
             static CC_AR_VV_REGEX: LazyLock<Regex> = LazyLock::new(|| {
-                Regex::new(r#"^\[([^ ]*)[^\]]*\] running:? "(?:/[^\s]*/)?(cc|ar)" (.*)$"#).unwrap()
+                Regex::new(
+                    r#"^\[([^ ]*)[^\]]*\] running:? (?:\w+="[^"]*" )*"(?:/[^\s]*/)?(cc|ar)" (.*)$"#,
+                )
+                .unwrap()
             });
             if let Some((pkg, cmd, args)) = match3(&CC_AR_VV_REGEX, line) {
                 match cmd.as_str() {
-                    "ar" => result.ar_invocations.insert(pkg, args),
-                    "cc" => result.cc_invocations.insert(pkg, args),
+                    "ar" => result.ar_invocations.entry(pkg).or_default().push(args),
+                    "cc" => result.cc_invocations.entry(pkg).or_default().push(args),
                     _ => unreachable!(),
-                };
+                }
                 continue;
             }
             // Rustc output of file location path pattern for a warning message.
             static WARNING_FILE_REGEX: LazyLock<Regex> =
-                LazyLock::new(|| Regex::new(r"^ *--> ([^:]*):[0-9]+").unwrap());
-            if result.warning_lines.contains_key(&n.saturating_sub(1)) {
-                if let Some(fpath) = match1(&WARNING_FILE_REGEX, line) {
-                    result.warning_files.push(fpath);
+                LazyLock::new(|| Regex::new(r"^ *--> ([^:]*:[0-9]+:[0-9]+)").unwrap());
+            if last_warning_line.is_some_and(|warning_line| warning_line + 1 == n) {
+                if let Some(location) = match1(&WARNING_FILE_REGEX, line) {
+                    if let Some(warning) = pending_warnings.last_mut() {
+                        *warning += &format!(" ({location})");
+                    }
                     continue;
                 }
             }
@@ -284,8 +682,6 @@ impl CargoOut {
             }
         }
 
-        // self.find_warning_owners()
-
         Ok(result)
     }
 }
@@ -294,7 +690,7 @@ impl Crate {
     fn from_rustc_invocation(
         rustc: &str,
         metadata: &WorkspaceMetadata,
-        tests: &BTreeMap<String, BTreeMap<PathBuf, TestContents>>,
+        cargo_out: &CargoOut,
         raw_names: &BTreeMap<String, String>,
     ) -> Result<Crate> {
         let mut out = Crate::default();
@@ -480,16 +876,289 @@ impl Crate {
         out.license.clone_from(&package_metadata.license);
         out.license_file.clone_from(&package_metadata.license_file);
 
+        // Benchmarks are built either with --test or with '--cfg test', and examples with
+        // `--crate-type bin`, so they can only be told apart from tests and binaries by their
+        // target kind.
+        let src_path = out.package_dir.join(&out.main_src);
+        let target_metadata = package_metadata
+            .targets
+            .iter()
+            .find(|target| target.src_path.canonicalize().is_ok_and(|p| p == src_path));
+        let target_kinds = target_metadata.map(|target| target.kind.as_slice()).unwrap_or_default();
+        if target_kinds.contains(&TargetKind::Bench) && out.types.iter().any(|t| t.is_test()) {
+            out.types = vec![CrateType::Bench];
+        } else if target_kinds.contains(&TargetKind::Example) && out.types == [CrateType::Bin] {
+            out.types = vec![CrateType::Example];
+        }
+
         let output_filename = out.name.clone() + &extra_filename;
-        if let Some(test_contents) = tests.get(&output_filename).and_then(|m| m.get(&out.main_src))
+        if let Some(test_contents) =
+            cargo_out.tests.get(&output_filename).and_then(|m| m.get(&out.main_src))
         {
             out.empty_test = !test_contents.tests && !test_contents.benchmarks;
         }
 
+        if let Some(target) = target_metadata {
+            let is_test = out.types.iter().any(|t| t.is_test());
+            let description = unit_description(&target.kind, &target.name, is_test);
+            out.warnings = find_warnings(&cargo_out.warnings, package_metadata, &description);
+        }
+
         Ok(out)
     }
 }
 
+impl Crate {
+    /// Makes a crate from a unit in the given unit graph, along with the JSON messages from the
+    /// build.
+    fn from_unit(
+        unit: &Unit,
+        unit_graph: &UnitGraph,
+        cargo_out: &CargoOut,
+        metadata: &WorkspaceMetadata,
+        extra_cfg: &[String],
+    ) -> Result<Crate> {
+        let package_metadata = metadata
+            .packages
+            .iter()
+            .find(|p| p.id == unit.pkg_id)
+            .ok_or_else(|| anyhow!("can't find metadata for package {:?}", unit.pkg_id))?;
+        let (package_dir, main_src) = split_src_path(&unit.target.src_path)?;
+        let mut out = Crate {
+            name: unit.target.name.replace('-', "_"),
+            package_name: package_metadata.name.clone(),
+            version: Some(package_metadata.version.clone()),
+            target: unit.platform.clone(),
+            features: unit.features.clone(),
+            edition: package_metadata.edition.clone(),
+            package_dir,
+            main_src,
+            license: package_metadata.license.clone(),
+            license_file: package_metadata.license_file.clone(),
+            ..Default::default()
+        };
+
+        out.types = if unit.is_test() {
+            if unit.target.kind.contains(&TargetKind::Bench) {
+                vec![CrateType::Bench]
+            } else if targets_without_harness(Path::new(&package_metadata.manifest_path))?
+                .contains(&out.name)
+            {
+                out.cfgs.push("test".to_string());
+                vec![CrateType::TestNoHarness]
+            } else {
+                vec![CrateType::Test]
+            }
+        } else if unit.target.kind.contains(&TargetKind::Example) {
+            vec![CrateType::Example]
+        } else {
+            unit.target.crate_types.clone()
+        };
+
+        for dependency in &unit.dependencies {
+            let dependency_unit = unit_graph
+                .units
+                .get(dependency.index)
+                .ok_or_else(|| anyhow!("invalid unit index {}", dependency.index))?;
+            // Running the build script is a dependency of every unit in the package.
+            if dependency_unit.is_build_script() {
+                continue;
+            }
+            let lib_name = dependency_unit.target.name.replace('-', "_");
+            // The library of the same package is referred to by its crate name, as when parsing
+            // rustc invocations or metadata.
+            let raw_name = if dependency_unit.pkg_id == unit.pkg_id {
+                lib_name.clone()
+            } else {
+                raw_name_from_src_path(
+                    &lib_name,
+                    &dependency_unit.target.src_path.to_string_lossy(),
+                )
+            };
+            let extern_type = if dependency_unit.target.crate_types.contains(&CrateType::ProcMacro)
+            {
+                ExternType::ProcMacro
+            } else {
+                ExternType::Rust
+            };
+            let extern_dep = Extern {
+                name: dependency.extern_crate_name.clone(),
+                lib_name,
+                raw_name,
+                extern_type,
+            };
+            // A dependency may be built more than once, e.g. with different features for tests.
+            if !out.externs.contains(&extern_dep) {
+                out.externs.push(extern_dep);
+            }
+        }
+
+        if let Some(build_script_output) = find_build_script_output(unit, unit_graph, cargo_out)? {
+            out.cfgs.extend(build_script_output.cfgs.iter().cloned());
+            for lib in &build_script_output.linked_libs {
+                match lib.split_once('=') {
+                    Some((kind, lib)) if kind.starts_with("static") => {
+                        out.static_libs.push(lib.to_string())
+                    }
+                    Some((_, lib)) => out.shared_libs.push(lib.to_string()),
+                    None => out.shared_libs.push(lib.to_string()),
+                }
+            }
+        }
+        out.cfgs.extend(extra_cfg.iter().cloned());
+
+        // These are the `-C` flags which cargo passes for the profile, other than those which
+        // `from_rustc_invocation` ignores.
+        let profile = &unit.profile;
+        if profile.opt_level != "0" {
+            out.codegens.push(format!("opt-level={}", profile.opt_level));
+        }
+        if profile.panic == "abort" && !unit.is_test() {
+            out.codegens.push("panic=abort".to_string());
+        }
+        if profile.debug_assertions != (profile.opt_level == "0") {
+            let value = if profile.debug_assertions { "on" } else { "off" };
+            out.codegens.push(format!("debug-assertions={value}"));
+        }
+        if profile.overflow_checks != profile.debug_assertions {
+            let value = if profile.overflow_checks { "on" } else { "off" };
+            out.codegens.push(format!("overflow-checks={value}"));
+        }
+        if profile.rpath {
+            out.codegens.push("rpath".to_string());
+        }
+
+        let description = unit_description(&unit.target.kind, &unit.target.name, unit.is_test());
+        out.warnings = find_warnings(&cargo_out.warnings, package_metadata, &description);
+
+        // Cargo caps lints for packages which aren't local to the workspace.
+        if !unit.pkg_id.contains("path+file://") {
+            out.cap_lints = "allow".to_string();
+        }
+
+        out.cfgs.sort();
+        out.cfgs.dedup();
+        out.codegens.sort();
+        out.features.sort();
+
+        if unit.is_test() {
+            // Find the test binary, to look up the tests which `cargo test -- --list` found in it.
+            let output_filename = cargo_out
+                .artifacts
+                .iter()
+                .filter(|artifact| {
+                    artifact.package_id == unit.pkg_id
+                        && artifact.target == unit.target
+                        && artifact.profile.test
+                })
+                .filter_map(|artifact| artifact.executable.as_deref())
+                .find(|executable| {
+                    unit.platform.as_ref().is_none_or(|platform| {
+                        executable.components().any(|c| c.as_os_str() == platform.as_str())
+                    })
+                })
+                .and_then(|executable| executable.file_name())
+                .map(|filename| filename.to_string_lossy().into_owned());
+            if let Some(test_contents) = output_filename
+                .and_then(|filename| cargo_out.tests.get(&filename))
+                .and_then(|m| m.get(&out.main_src))
+            {
+                out.empty_test = !test_contents.tests && !test_contents.benchmarks;
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Returns the output of the build script run which the given unit depends on, if it has one.
+///
+/// The output is matched to the `run-custom-build` unit by its package and by the platform in its
+/// `OUT_DIR`, which for the host has no target triple.
+fn find_build_script_output<'a>(
+    unit: &Unit,
+    unit_graph: &UnitGraph,
+    cargo_out: &'a CargoOut,
+) -> Result<Option<&'a BuildScriptOutput>> {
+    let Some(run_unit) = unit
+        .dependencies
+        .iter()
+        .filter_map(|dependency| unit_graph.units.get(dependency.index))
+        .find(|dependency_unit| {
+            dependency_unit.mode == "run-custom-build" && dependency_unit.pkg_id == unit.pkg_id
+        })
+    else {
+        return Ok(None);
+    };
+    let platforms: BTreeSet<&str> =
+        unit_graph.units.iter().filter_map(|unit| unit.platform.as_deref()).collect();
+    let mut outputs = cargo_out.build_script_outputs.iter().filter(|output| {
+        output.package_id == run_unit.pkg_id
+            && match &run_unit.platform {
+                Some(platform) => {
+                    output.out_dir.components().any(|c| c.as_os_str() == platform.as_str())
+                }
+                None => !output
+                    .out_dir
+                    .components()
+                    .any(|c| c.as_os_str().to_str().is_some_and(|c| platforms.contains(c))),
+            }
+    });
+    let platform = run_unit.platform.as_deref().unwrap_or("host");
+    let Some(output) = outputs.next() else {
+        bail!("no output found for build script of {} on {platform}", run_unit.pkg_id);
+    };
+    if outputs.any(|other| other.cfgs != output.cfgs || other.linked_libs != output.linked_libs) {
+        eprintln!(
+            "WARNING: build script of {} on {platform} ran more than once with different output, \
+             using the first",
+            run_unit.pkg_id
+        );
+    }
+    Ok(Some(output))
+}
+
+/// Returns cargo's description of a unit which builds the given target, as used in the summary of
+/// the warnings from it, e.g. "lib", "lib test" or `bin "foo"`.
+fn unit_description(kinds: &[TargetKind], name: &str, test: bool) -> String {
+    let description = if kinds.contains(&TargetKind::Bin) {
+        format!("bin \"{name}\"")
+    } else if kinds.contains(&TargetKind::Test) {
+        format!("test \"{name}\"")
+    } else if kinds.contains(&TargetKind::Bench) {
+        format!("bench \"{name}\"")
+    } else if kinds.contains(&TargetKind::Example) {
+        format!("example \"{name}\"")
+    } else if kinds.contains(&TargetKind::CustomBuild) {
+        "build script".to_string()
+    } else {
+        "lib".to_string()
+    };
+    // Unit tests are built from the same target as the library or binary.
+    if test && !kinds.contains(&TargetKind::Test) && !kinds.contains(&TargetKind::Bench) {
+        description + " test"
+    } else {
+        description
+    }
+}
+
+/// Returns the warnings for the unit with the given description in the given package, whether
+/// they came from JSON messages or plain text output.
+fn find_warnings(
+    warnings: &BTreeMap<(String, String), Vec<String>>,
+    package_metadata: &PackageMetadata,
+    description: &str,
+) -> Vec<String> {
+    let mut found: Vec<String> = [&package_metadata.id, &package_metadata.name]
+        .into_iter()
+        .filter_map(|package| warnings.get(&(package.clone(), description.to_string())))
+        .flatten()
+        .cloned()
+        .collect();
+    dedup_in_order(&mut found);
+    found
+}
+
 /// Given a path to the main source file of some Rust crate, returns the canonical path to the
 /// package directory, and the relative path to the source file within that directory.
 fn split_src_path(src_path: &Path) -> Result<(PathBuf, PathBuf)> {
@@ -525,6 +1194,7 @@ fn find_cargo_toml(src_path: &Path) -> Result<PathBuf> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cargo::metadata::PackageMetadata;
 
     #[test]
     fn parse_args() {
@@ -536,4 +1206,283 @@ mod tests {
             vec!["fo o", " b ar", " baz "]
         );
     }
+
+    #[test]
+    fn parse_cc_ar_invocations() {
+        let cargo_out = CargoOut::parse(
+            r#"[foo 0.1.0] running: "cc" "-x" "c" "-E" "-" "-include" "/out/probe.h"
+[foo 0.1.0] running: "cc" "-O0" "-ffunction-sections" "-fPIC" "-m64" "-I" "include" "-Wall" "-DFOO=1" "-std=c99" "-o" "/out/a.o" "-c" "./src/a.c"
+[foo 0.1.0] running: "cc" "-O0" "-isystem" "src/inc" "-D" "BAR" "-MF" "/out/b.d" "-o" "/out/b.o" "-c" "src/b.c"
+[foo 0.1.0] running: "cc" "-O0" "-I" "/out" "-o" "/out/generated.o" "-c" "/out/generated.c"
+[foo 0.1.0] running: ZERO_AR_DATE="1" "ar" "cq" "/out/libfoo.a" "/out/a.o" "/out/b.o"
+[foo 0.1.0] running: "ar" "s" "/out/libfoo.a"
+[foo 0.1.0] running: "ar" "cq" "/out/libgenerated.a" "/out/generated.o"
+[foo 0.1.0] running: "ar" "cq" "/out/libunused.a" "/out/unused.o"
+"#,
+        )
+        .unwrap();
+        assert_eq!(cargo_out.cc_invocations["foo"].len(), 4);
+        assert_eq!(cargo_out.ar_invocations["foo"].len(), 4);
+
+        // libgenerated is built from a source in OUT_DIR so is skipped, and libunused isn't linked.
+        let libs = cc_libraries_from_invocations(
+            &cargo_out.cc_invocations["foo"],
+            &cargo_out.ar_invocations["foo"],
+            Path::new("/path/to/foo"),
+            &["foo".to_string(), "generated".to_string()],
+        )
+        .unwrap();
+        assert_eq!(
+            libs,
+            vec![CcLibrary {
+                name: "foo".to_string(),
+                srcs: vec!["src/a.c".into(), "src/b.c".into()],
+                include_dirs: vec!["include".into(), "src/inc".into()],
+                defines: vec!["FOO=1".to_string(), "BAR".to_string()],
+                flags: vec!["-std=c99".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn read_build_script_logs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let build_dir = dir.path().join("build/foo-1234");
+        std::fs::create_dir_all(build_dir.join("out"))?;
+        std::fs::write(
+            build_dir.join("output"),
+            "OPT_LEVEL = Some(\"0\")\nrunning: \"cc\" \"-c\" \"src/a.c\"\ncargo:rustc-link-lib=static=a\n",
+        )?;
+        let json_output = format!(
+            r#"{{"reason":"build-script-executed","package_id":"path+file:///src/foo#0.1.0","linked_libs":[],"linked_paths":[],"cfgs":[],"env":[],"out_dir":"{}/out"}}
+{{"reason":"build-finished","success":true}}
+"#,
+            build_dir.display()
+        );
+        assert_eq!(
+            build_script_logs(&json_output)?,
+            "[foo 0.1.0] running: \"cc\" \"-c\" \"src/a.c\"\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_package_ids() {
+        assert_eq!(package_name_and_version("foo 0.1.0 (path+file:///src/foo)"), ("foo", "0.1.0"));
+        assert_eq!(package_name_and_version("path+file:///src/foo#0.1.0"), ("foo", "0.1.0"));
+        assert_eq!(
+            package_name_and_version(
+                "registry+https://github.com/rust-lang/crates.io-index#cc@1.0.83"
+            ),
+            ("cc", "1.0.83")
+        );
+    }
+
+    #[test]
+    fn parse_unit_graph() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let package_dir = dir.path().canonicalize()?.join("foo-bar");
+        std::fs::create_dir_all(package_dir.join("src"))?;
+        std::fs::write(package_dir.join("Cargo.toml"), "[package]\nname = \"foo-bar\"\n")?;
+        std::fs::write(package_dir.join("src/lib.rs"), "")?;
+        std::fs::write(package_dir.join("src/main.rs"), "")?;
+        std::fs::write(package_dir.join("build.rs"), "")?;
+        let dir = package_dir.display();
+        let pkg_id = format!("path+file://{dir}#0.1.0");
+        let target_dir = format!("{dir}/target/x86_64-unknown-linux-gnu/debug");
+        let profile = r#"{"opt_level":"0","debug_assertions":true,"overflow_checks":true,"rpath":false,"panic":"unwind"}"#;
+        let lib = format!(
+            r#"{{"kind":["lib"],"crate_types":["lib"],"name":"foo_bar","src_path":"{dir}/src/lib.rs"}}"#
+        );
+        let bin = format!(
+            r#"{{"kind":["bin"],"crate_types":["bin"],"name":"foo-bar","src_path":"{dir}/src/main.rs"}}"#
+        );
+        let build_script = format!(
+            r#"{{"kind":["custom-build"],"crate_types":["bin"],"name":"build-script-build","src_path":"{dir}/build.rs"}}"#
+        );
+        let unit = |target: &str, mode: &str, dependencies: &str| {
+            format!(
+                r#"{{"pkg_id":"{pkg_id}","target":{target},"profile":{profile},"platform":"x86_64-unknown-linux-gnu","mode":"{mode}","features":["std","default"],"dependencies":[{dependencies}]}}"#
+            )
+        };
+        let units = [
+            unit(&lib, "build", r#"{"index":3,"extern_crate_name":"build_script_build"}"#),
+            unit(
+                &bin,
+                "build",
+                r#"{"index":0,"extern_crate_name":"foo_bar"},{"index":3,"extern_crate_name":"build_script_build"}"#,
+            ),
+            unit(&lib, "test", r#"{"index":3,"extern_crate_name":"build_script_build"}"#),
+            unit(&build_script, "run-custom-build", ""),
+        ];
+        let cargo_out = [
+            format!(r#"{{"version":1,"units":[{}],"roots":[0,1,2]}}"#, units.join(",")),
+            "   Compiling foo-bar v0.1.0".to_string(),
+            format!(
+                r#"{{"reason":"build-script-executed","package_id":"{pkg_id}","linked_libs":["static=baz","m"],"linked_paths":[],"cfgs":["has_foo"],"env":[],"out_dir":"{target_dir}/build/foo-bar-1234/out"}}"#
+            ),
+            format!(
+                r#"{{"reason":"compiler-artifact","package_id":"{pkg_id}","target":{lib},"profile":{{"test":true}},"executable":"{target_dir}/deps/foo_bar-5678"}}"#
+            ),
+            r#"{"reason":"build-finished","success":true}"#.to_string(),
+            format!("     Running unittests src/lib.rs ({target_dir}/deps/foo_bar-5678)"),
+            "0 tests, 0 benchmarks".to_string(),
+        ]
+        .join("\n");
+        let metadata = WorkspaceMetadata {
+            packages: vec![PackageMetadata {
+                name: "foo-bar".to_string(),
+                version: "0.1.0".to_string(),
+                edition: "2021".to_string(),
+                manifest_path: format!("{dir}/Cargo.toml"),
+                id: pkg_id.clone(),
+                ..Default::default()
+            }],
+            workspace_members: vec![pkg_id.clone()],
+        };
+
+        let crates =
+            parse_cargo_out_str(&cargo_out, &metadata, &["extra".to_string()], &package_dir)?;
+
+        let expected_lib = Crate {
+            name: "foo_bar".to_string(),
+            package_name: "foo-bar".to_string(),
+            version: Some("0.1.0".to_string()),
+            types: vec![CrateType::Lib],
+            target: Some("x86_64-unknown-linux-gnu".to_string()),
+            features: vec!["default".to_string(), "std".to_string()],
+            cfgs: vec!["extra".to_string(), "has_foo".to_string()],
+            static_libs: vec!["baz".to_string()],
+            shared_libs: vec!["m".to_string()],
+            edition: "2021".to_string(),
+            package_dir: package_dir.clone(),
+            main_src: "src/lib.rs".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            crates,
+            vec![
+                expected_lib.clone(),
+                Crate {
+                    types: vec![CrateType::Bin],
+                    main_src: "src/main.rs".into(),
+                    externs: vec![Extern {
+                        name: "foo_bar".to_string(),
+                        lib_name: "foo_bar".to_string(),
+                        raw_name: "foo_bar".to_string(),
+                        extern_type: ExternType::Rust,
+                    }],
+                    ..expected_lib.clone()
+                },
+                Crate { types: vec![CrateType::Test], empty_test: true, ..expected_lib },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn find_build_script_output_per_platform() -> Result<()> {
+        let unit = |mode: &str, platform: &str, dependencies: &str| {
+            format!(
+                r#"{{"pkg_id":"foo-id","target":{{"kind":["lib"],"crate_types":["lib"],"name":"foo","src_path":"/foo/src/lib.rs"}},"profile":{{}},"platform":{platform},"mode":"{mode}","features":[],"dependencies":[{dependencies}]}}"#
+            )
+        };
+        let dependency = |index: usize| {
+            format!(r#"{{"index":{index},"extern_crate_name":"build_script_build"}}"#)
+        };
+        let units = [
+            unit("build", r#""aarch64-linux-android""#, &dependency(2)),
+            unit("build", "null", &dependency(3)),
+            unit("run-custom-build", r#""aarch64-linux-android""#, ""),
+            unit("run-custom-build", "null", ""),
+            unit("build", "null", ""),
+        ];
+        let unit_graph: UnitGraph =
+            serde_json::from_str(&format!(r#"{{"units":[{}]}}"#, units.join(",")))?;
+        let output = |cfg: &str, out_dir: &str| BuildScriptOutput {
+            package_id: "foo-id".to_string(),
+            linked_libs: vec![],
+            cfgs: vec![cfg.to_string()],
+            out_dir: out_dir.into(),
+        };
+        let mut cargo_out = CargoOut {
+            build_script_outputs: vec![
+                output("device", "/target/aarch64-linux-android/debug/build/foo-1234/out"),
+                output("host", "/target/debug/build/foo-5678/out"),
+            ],
+            ..Default::default()
+        };
+
+        let cfgs = |unit: &Unit, cargo_out: &CargoOut| -> Result<Option<Vec<String>>> {
+            Ok(find_build_script_output(unit, &unit_graph, cargo_out)?
+                .map(|output| output.cfgs.clone()))
+        };
+        assert_eq!(cfgs(&unit_graph.units[0], &cargo_out)?, Some(vec!["device".to_string()]));
+        assert_eq!(cfgs(&unit_graph.units[1], &cargo_out)?, Some(vec!["host".to_string()]));
+        assert_eq!(cfgs(&unit_graph.units[4], &cargo_out)?, None);
+
+        cargo_out.build_script_outputs.remove(1);
+        assert!(cfgs(&unit_graph.units[1], &cargo_out).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_warnings() -> Result<()> {
+        let lib =
+            r#"{"kind":["lib"],"crate_types":["lib"],"name":"foo","src_path":"/foo/src/lib.rs"}"#;
+        let message = |message: &str, line: usize| {
+            format!(
+                r#"{{"reason":"compiler-message","package_id":"foo-id","target":{lib},"message":{{"message":"{message}","level":"warning","spans":[{{"file_name":"src/lib.rs","line_start":{line},"column_start":5,"is_primary":true}}]}}}}"#
+            )
+        };
+        let artifact = |test: bool| {
+            format!(
+                r#"{{"reason":"compiler-artifact","package_id":"foo-id","target":{lib},"profile":{{"test":{test}}},"executable":null}}"#
+            )
+        };
+        let cargo_out = [
+            message("unused variable: `x`", 1),
+            artifact(false),
+            message("unused variable: `x`", 1),
+            message("unused variable: `y`", 2),
+            artifact(true),
+            "warning: unused manifest key: package.foo".to_string(),
+            "warning: unused import: `std::fs`".to_string(),
+            " --> src/main.rs:1:5".to_string(),
+            "  |".to_string(),
+            "warning: `bar` (bin \"bar\") generated 1 warning".to_string(),
+            "warning: unused variable: `z`".to_string(),
+            " --> src/main.rs:3:9".to_string(),
+            "warning: `bar` (bin \"bar\" test) generated 2 warnings (1 duplicate)".to_string(),
+        ]
+        .join("\n");
+
+        let cargo_out = CargoOut::parse(&cargo_out)?;
+
+        let key = |package: &str, description: &str| (package.to_string(), description.to_string());
+        assert_eq!(
+            cargo_out.warnings,
+            [
+                (
+                    key("bar", "bin \"bar\""),
+                    vec!["unused import: `std::fs` (src/main.rs:1:5)".to_string()]
+                ),
+                (
+                    key("bar", "bin \"bar\" test"),
+                    vec!["unused variable: `z` (src/main.rs:3:9)".to_string()]
+                ),
+                (key("foo-id", "lib"), vec!["unused variable: `x` (src/lib.rs:1:5)".to_string()]),
+                (
+                    key("foo-id", "lib test"),
+                    vec![
+                        "unused variable: `x` (src/lib.rs:1:5)".to_string(),
+                        "unused variable: `y` (src/lib.rs:2:5)".to_string(),
+                    ]
+                ),
+            ]
+            .into_iter()
+            .collect()
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,306 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parsing and evaluation of the `cfg(...)` expressions used for target-specific dependencies.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::BTreeSet;
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+/// A parsed `cfg` predicate, e.g. `any(unix, target_os = "wasi")`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CfgExpr {
+    /// A bare name, e.g. `unix`.
+    Name(String),
+    /// A key-value pair, e.g. `target_os = "linux"`.
+    KeyValue(String, String),
+    All(Vec<CfgExpr>),
+    Any(Vec<CfgExpr>),
+    Not(Box<CfgExpr>),
+}
+
+impl CfgExpr {
+    /// Parses a `cfg` predicate, without the surrounding `cfg(...)`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut parser = Parser { s, chars: s.char_indices().peekable() };
+        let expr = parser.expr()?;
+        parser.skip_whitespace();
+        if let Some((i, _)) = parser.chars.peek() {
+            bail!("unexpected {:?} at offset {i} in cfg expression {s:?}", &s[*i..]);
+        }
+        Ok(expr)
+    }
+}
+
+/// Recursive descent parser for `cfg` predicates.
+struct Parser<'a> {
+    s: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    /// Consumes the given character, or returns an error if the next character is something else.
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((i, c)) => {
+                bail!("expected {expected:?} but found {c:?} at offset {i} in {:?}", self.s)
+            }
+            None => bail!("expected {expected:?} but found end of cfg expression {:?}", self.s),
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String> {
+        self.skip_whitespace();
+        let mut identifier = String::new();
+        while let Some((_, c)) = self.chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_') {
+            identifier.push(c);
+        }
+        if identifier.is_empty() {
+            bail!("expected identifier in cfg expression {:?}", self.s);
+        }
+        Ok(identifier)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(value),
+                Some((_, '\\')) => match self.chars.next() {
+                    Some((_, c @ ('"' | '\\'))) => value.push(c),
+                    _ => bail!("unsupported escape sequence in cfg expression {:?}", self.s),
+                },
+                Some((_, c)) => value.push(c),
+                None => bail!("unterminated string in cfg expression {:?}", self.s),
+            }
+        }
+    }
+
+    /// Parses a comma-separated list of predicates in parentheses, allowing a trailing comma.
+    fn list(&mut self) -> Result<Vec<CfgExpr>> {
+        self.expect('(')?;
+        let mut exprs = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.chars.next_if(|(_, c)| *c == ')').is_some() {
+                return Ok(exprs);
+            }
+            exprs.push(self.expr()?);
+            self.skip_whitespace();
+            if self.chars.next_if(|(_, c)| *c == ',').is_none() {
+                self.expect(')')?;
+                return Ok(exprs);
+            }
+        }
+    }
+
+    fn expr(&mut self) -> Result<CfgExpr> {
+        let name = self.identifier()?;
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some((_, '=')) => {
+                self.chars.next();
+                Ok(CfgExpr::KeyValue(name, self.string()?))
+            }
+            Some((_, '(')) => {
+                let mut exprs = self.list()?;
+                match name.as_str() {
+                    "all" => Ok(CfgExpr::All(exprs)),
+                    "any" => Ok(CfgExpr::Any(exprs)),
+                    "not" if exprs.len() == 1 => Ok(CfgExpr::Not(Box::new(exprs.remove(0)))),
+                    "not" => bail!("not() takes exactly one predicate in {:?}", self.s),
+                    _ => bail!("unknown cfg operator {name:?} in {:?}", self.s),
+                }
+            }
+            _ => Ok(CfgExpr::Name(name)),
+        }
+    }
+}
+
+/// The set of `cfg` names and key-value pairs which are enabled for a particular target.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TargetDescription {
+    triple: String,
+    cfgs: BTreeSet<(String, Option<String>)>,
+}
+
+impl TargetDescription {
+    /// Returns the description of the given Rust target triple, with the given extra `cfg` flags
+    /// (either bare names or `key="value"` pairs) also enabled.
+    pub fn new(triple: &str, extra_cfgs: &[String]) -> Result<Self> {
+        let mut parts = triple.split('-');
+        let arch = match parts.next().unwrap() {
+            "aarch64" => "aarch64",
+            arch if arch.starts_with("arm") || arch.starts_with("thumbv7") => "arm",
+            arch if arch.starts_with("riscv64") => "riscv64",
+            "x86_64" => "x86_64",
+            "i586" | "i686" => "x86",
+            "wasm32" => "wasm32",
+            arch => bail!("unsupported architecture {arch:?} in target triple {triple:?}"),
+        };
+        let parts: Vec<&str> = parts.collect();
+        let (vendor, os, env) = match parts[..] {
+            ["linux", "android" | "androideabi"] => ("unknown", "android", ""),
+            [vendor, "linux", env] => (vendor, "linux", env.trim_end_matches("eabihf")),
+            [vendor, "darwin"] => (vendor, "macos", ""),
+            [vendor, "windows", env] => (vendor, "windows", env.trim_end_matches("llvm")),
+            [vendor, "fuchsia"] => (vendor, "fuchsia", ""),
+            [vendor, "none", ..] => (vendor, "none", ""),
+            ["unknown", "unknown"] => ("unknown", "unknown", ""),
+            [vendor, "wasi" | "wasip1" | "wasip2"] => (vendor, "wasi", ""),
+            _ => bail!("unsupported target triple {triple:?}"),
+        };
+        let family = match os {
+            "android" | "linux" | "macos" | "fuchsia" => Some("unix"),
+            "windows" => Some("windows"),
+            _ if arch == "wasm32" => Some("wasm"),
+            _ => None,
+        };
+        let pointer_width =
+            if matches!(arch, "aarch64" | "riscv64" | "x86_64") { "64" } else { "32" };
+
+        let mut description = Self { triple: triple.to_owned(), cfgs: BTreeSet::new() };
+        let mut add = |key: &str, value: Option<&str>| {
+            description.cfgs.insert((key.to_owned(), value.map(str::to_owned)));
+        };
+        add("target_arch", Some(arch));
+        add("target_os", Some(os));
+        add("target_env", Some(env));
+        add("target_vendor", Some(vendor));
+        add("target_endian", Some("little"));
+        add("target_pointer_width", Some(pointer_width));
+        for width in ["8", "16", "32", pointer_width, "ptr"] {
+            add("target_has_atomic", Some(width));
+        }
+        if let Some(family) = family {
+            add("target_family", Some(family));
+            if family != "wasm" {
+                add(family, None);
+            }
+        }
+        for extra_cfg in extra_cfgs {
+            match CfgExpr::parse(extra_cfg)
+                .with_context(|| format!("invalid extra_cfg {extra_cfg:?}"))?
+            {
+                CfgExpr::Name(name) => add(&name, None),
+                CfgExpr::KeyValue(key, value) => add(&key, Some(&value)),
+                _ => bail!("extra_cfg {extra_cfg:?} must be a name or key-value pair"),
+            }
+        }
+        Ok(description)
+    }
+
+    /// Returns whether the given predicate is true for this target.
+    pub fn evaluate(&self, expr: &CfgExpr) -> bool {
+        match expr {
+            CfgExpr::Name(name) => self.cfgs.contains(&(name.clone(), None)),
+            CfgExpr::KeyValue(key, value) => {
+                self.cfgs.contains(&(key.clone(), Some(value.clone())))
+            }
+            CfgExpr::All(exprs) => exprs.iter().all(|expr| self.evaluate(expr)),
+            CfgExpr::Any(exprs) => exprs.iter().any(|expr| self.evaluate(expr)),
+            CfgExpr::Not(expr) => !self.evaluate(expr),
+        }
+    }
+
+    /// Returns whether the given dependency target specification from `Cargo.toml` applies to this
+    /// target. This may either be a `cfg(...)` expression or a plain target triple.
+    pub fn matches(&self, target: &str) -> Result<bool> {
+        let target = target.trim();
+        if let Some(predicate) = target.strip_prefix("cfg(").and_then(|t| t.strip_suffix(')')) {
+            let expr = CfgExpr::parse(predicate).map_err(|e| anyhow!("{target:?}: {e}"))?;
+            Ok(self.evaluate(&expr))
+        } else {
+            Ok(target == self.triple)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cfg_expressions() {
+        assert_eq!(CfgExpr::parse("unix").unwrap(), CfgExpr::Name("unix".to_string()));
+        assert_eq!(
+            CfgExpr::parse(r#" target_os = "linux" "#).unwrap(),
+            CfgExpr::KeyValue("target_os".to_string(), "linux".to_string())
+        );
+        assert_eq!(
+            CfgExpr::parse(r#"not(all(target_family = "wasm", target_os = "unknown",))"#).unwrap(),
+            CfgExpr::Not(Box::new(CfgExpr::All(vec![
+                CfgExpr::KeyValue("target_family".to_string(), "wasm".to_string()),
+                CfgExpr::KeyValue("target_os".to_string(), "unknown".to_string()),
+            ])))
+        );
+        assert_eq!(CfgExpr::parse("any()").unwrap(), CfgExpr::Any(vec![]));
+        assert!(CfgExpr::parse("not(unix, windows)").is_err());
+        assert!(CfgExpr::parse("unix windows").is_err());
+        assert!(CfgExpr::parse(r#"target_os = "linux"#).is_err());
+        assert!(CfgExpr::parse("some(unix)").is_err());
+    }
+
+    #[test]
+    fn match_targets() {
+        let android = TargetDescription::new("aarch64-linux-android", &[]).unwrap();
+        let linux = TargetDescription::new("x86_64-unknown-linux-gnu", &[]).unwrap();
+        let musl = TargetDescription::new("x86_64-unknown-linux-musl", &[]).unwrap();
+
+        for target in [
+            "cfg(unix)",
+            "cfg(not(windows))",
+            r#"cfg(any(unix, target_os = "wasi"))"#,
+            r#"cfg(not(all(target_family = "wasm", target_os = "unknown")))"#,
+            r#"cfg(any(target_os = "linux", target_os = "android"))"#,
+            r#"cfg(target_pointer_width = "64")"#,
+        ] {
+            assert!(android.matches(target).unwrap(), "{target}");
+            assert!(linux.matches(target).unwrap(), "{target}");
+        }
+
+        assert!(android.matches(r#"cfg(target_os = "android")"#).unwrap());
+        assert!(!linux.matches(r#"cfg(target_os = "android")"#).unwrap());
+        assert!(linux.matches(r#"cfg(target_env = "gnu")"#).unwrap());
+        assert!(!musl.matches(r#"cfg(target_env = "gnu")"#).unwrap());
+        assert!(android.matches(r#"cfg(target_arch = "aarch64")"#).unwrap());
+        assert!(!linux.matches("cfg(windows)").unwrap());
+        assert!(linux.matches("x86_64-unknown-linux-gnu").unwrap());
+        assert!(!linux.matches("x86_64-pc-windows-gnu").unwrap());
+        assert!(linux.matches("cfg(unix))").is_err());
+    }
+
+    #[test]
+    fn extra_cfgs() {
+        let target = TargetDescription::new(
+            "x86_64-unknown-linux-gnu",
+            &["foo".to_string(), r#"bar="baz""#.to_string()],
+        )
+        .unwrap();
+        assert!(target.matches("cfg(foo)").unwrap());
+        assert!(target.matches(r#"cfg(all(foo, bar = "baz"))"#).unwrap());
+        assert!(!target.matches(r#"cfg(bar = "qux")"#).unwrap());
+        assert!(
+            TargetDescription::new("x86_64-unknown-linux-gnu", &["not(foo)".to_string()]).is_err()
+        );
+    }
+}
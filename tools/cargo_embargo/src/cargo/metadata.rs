@@ -14,24 +14,17 @@
 
 //! Types for parsing cargo.metadata JSON files.
 
+use super::build_script::BuildScriptDirectives;
+use super::cfg::TargetDescription;
 use super::{Crate, CrateType, Extern, ExternType};
-use crate::config::VariantConfig;
+use crate::config::{PackageVariantConfig, VariantConfig};
 use anyhow::{bail, Context, Result};
 use serde::Deserialize;
 use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 
-/// `cfg` strings for dependencies which should be considered enabled. It would be better to parse
-/// them properly, but this is good enough in practice so far.
-const ENABLED_CFGS: [&str; 6] = [
-    r#"unix"#,
-    r#"not(windows)"#,
-    r#"any(unix, target_os = "wasi")"#,
-    r#"not(all(target_family = "wasm", target_os = "unknown"))"#,
-    r#"not(target_family = "wasm")"#,
-    r#"any(target_os = "linux", target_os = "android")"#,
-];
-
 /// `cargo metadata` output.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 pub struct WorkspaceMetadata {
@@ -63,18 +56,18 @@ pub struct DependencyMetadata {
 }
 
 impl DependencyMetadata {
-    /// Returns whether the dependency should be included when the given features are enabled.
-    fn enabled(&self, features: &[String], cfgs: &[String]) -> bool {
-        if let Some(target) = &self.target {
-            if target.starts_with("cfg(") && target.ends_with(')') {
-                let target_cfg = &target[4..target.len() - 1];
-                if !ENABLED_CFGS.contains(&target_cfg) && !cfgs.contains(&target_cfg.to_string()) {
-                    return false;
-                }
+    /// Returns whether the dependency should be included when the given features are enabled and
+    /// building for the given target.
+    fn enabled(&self, features: &[String], target: &TargetDescription) -> Result<bool> {
+        if let Some(dependency_target) = &self.target {
+            if !target.matches(dependency_target).with_context(|| {
+                format!("failed to evaluate target of dependency {:?}", self.name)
+            })? {
+                return Ok(false);
             }
         }
         let name = self.rename.as_ref().unwrap_or(&self.name);
-        !self.optional || features.contains(&format!("dep:{}", name))
+        Ok(!self.optional || features.contains(&format!("dep:{}", name)))
     }
 }
 
@@ -89,6 +82,19 @@ pub struct TargetMetadata {
     pub name: String,
     pub src_path: PathBuf,
     pub test: bool,
+    #[serde(default, rename = "required-features")]
+    pub required_features: Vec<String>,
+}
+
+impl TargetMetadata {
+    /// Returns whether this is an example binary target which is in the given allowlist, and whose
+    /// required features are all enabled.
+    fn is_selected_example(&self, examples: &[String], features: &[String]) -> bool {
+        self.kind.contains(&TargetKind::Example)
+            && self.crate_types == [CrateType::Bin]
+            && examples.contains(&self.name)
+            && self.required_features.iter().all(|feature| features.contains(feature))
+    }
 }
 
 #[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq)]
@@ -114,15 +120,48 @@ pub fn parse_cargo_metadata_str(cargo_metadata: &str, cfg: &VariantConfig) -> Re
         &cfg.features,
         &cfg.extra_cfg,
         cfg.tests,
+        &cfg.targets,
+        &cfg.package,
         &cfg.workspace_excludes,
     )
 }
 
+/// Returns the package and target names of the examples which should be built for the given
+/// config, i.e. those which are in their package's `examples` allowlist and whose required features
+/// are all enabled.
+pub fn selected_examples(
+    cargo_metadata: &str,
+    cfg: &VariantConfig,
+) -> Result<Vec<(String, String)>> {
+    let metadata: WorkspaceMetadata =
+        serde_json::from_str(cargo_metadata).context("failed to parse cargo metadata")?;
+    let mut examples = Vec::new();
+    for package in &metadata.packages {
+        if !metadata.workspace_members.contains(&package.id)
+            || cfg.workspace_excludes.contains(&package.name)
+        {
+            continue;
+        }
+        let Some(package_cfg) = cfg.package.get(&package.name) else {
+            continue;
+        };
+        let features = resolve_features(&cfg.features, &package.features, &package.dependencies);
+        for target in &package.targets {
+            if target.is_selected_example(&package_cfg.examples, &features) {
+                examples.push((package.name.clone(), target.name.clone()));
+            }
+        }
+    }
+    Ok(examples)
+}
+
 fn parse_cargo_metadata(
     metadata: &WorkspaceMetadata,
     features: &Option<Vec<String>>,
     cfgs: &[String],
     include_tests: bool,
+    targets: &[String],
+    package_cfgs: &BTreeMap<String, PackageVariantConfig>,
     workspace_excludes: &[String],
 ) -> Result<Vec<Crate>> {
     let mut crates = Vec::new();
@@ -133,128 +172,218 @@ fn parse_cargo_metadata(
             continue;
         }
 
+        let def = PackageVariantConfig::default();
+        let package_cfg = package_cfgs.get(&package.name).unwrap_or(&def);
         let features = resolve_features(features, &package.features, &package.dependencies);
         let features_without_deps: Vec<String> =
             features.clone().into_iter().filter(|feature| !feature.starts_with("dep:")).collect();
         let package_dir = package_dir_from_id(&package.id)?;
-
-        for target in &package.targets {
-            let target_kinds = target
-                .kind
-                .clone()
-                .into_iter()
-                .filter(|kind| {
-                    [
-                        TargetKind::Bin,
-                        TargetKind::Cdylib,
-                        TargetKind::Lib,
-                        TargetKind::ProcMacro,
-                        TargetKind::Rlib,
-                        TargetKind::Staticlib,
-                        TargetKind::Test,
-                    ]
-                    .contains(kind)
-                })
-                .collect::<Vec<_>>();
-            if target_kinds.is_empty() {
-                // Only binaries, libraries and integration tests are supported.
-                continue;
-            }
-            let main_src = split_src_path(&target.src_path, &package_dir);
-            // Hypens are not allowed in crate names. See
-            // https://github.com/rust-lang/rfcs/blob/master/text/0940-hyphens-considered-harmful.md
-            // for background.
-            let target_name = target.name.replace('-', "_");
-            let target_triple = if target_kinds == [TargetKind::ProcMacro] {
-                None
-            } else {
-                Some("x86_64-unknown-linux-gnu".to_string())
-            };
-            // Don't generate an entry for integration tests, they will be covered by the test case
-            // below.
-            if target_kinds != [TargetKind::Test] {
-                crates.push(Crate {
-                    name: target_name.clone(),
-                    package_name: package.name.to_owned(),
-                    version: Some(package.version.to_owned()),
-                    types: target.crate_types.clone(),
-                    features: features_without_deps.clone(),
-                    edition: package.edition.to_owned(),
-                    license: package.license.clone(),
-                    license_file: package.license_file.clone(),
-                    package_dir: package_dir.clone(),
-                    main_src: main_src.to_owned(),
-                    target: target_triple.clone(),
-                    externs: get_externs(
-                        package,
-                        &metadata.packages,
-                        &features,
-                        cfgs,
-                        &target_kinds,
-                        false,
-                    )?,
-                    cfgs: cfgs.to_owned(),
-                    ..Default::default()
-                });
+        let targets_without_harness = targets_without_harness(Path::new(&package.manifest_path))?;
+        let first_package_crate = crates.len();
+
+        for (triple_index, triple) in targets.iter().enumerate() {
+            let target_description = TargetDescription::new(triple, cfgs)?;
+            for target in &package.targets {
+                let target_kinds = target
+                    .kind
+                    .clone()
+                    .into_iter()
+                    .filter(|kind| {
+                        [
+                            TargetKind::Bench,
+                            TargetKind::Bin,
+                            TargetKind::Cdylib,
+                            TargetKind::Example,
+                            TargetKind::Lib,
+                            TargetKind::ProcMacro,
+                            TargetKind::Rlib,
+                            TargetKind::Staticlib,
+                            TargetKind::Test,
+                        ]
+                        .contains(kind)
+                    })
+                    .collect::<Vec<_>>();
+                if target_kinds.is_empty() {
+                    // Only binaries, libraries, integration tests, benchmarks and examples are supported.
+                    continue;
+                }
+                let main_src = split_src_path(&target.src_path, &package_dir);
+                // Hypens are not allowed in crate names. See
+                // https://github.com/rust-lang/rfcs/blob/master/text/0940-hyphens-considered-harmful.md
+                // for background.
+                let target_name = target.name.replace('-', "_");
+                let target_triple = if target_kinds == [TargetKind::ProcMacro] {
+                    // Proc macros are only built for the host, so only need to be output once.
+                    if triple_index > 0 {
+                        continue;
+                    }
+                    None
+                } else {
+                    Some(triple.clone())
+                };
+                if target_kinds == [TargetKind::Bench] || target_kinds == [TargetKind::Example] {
+                    let crate_type = if target_kinds == [TargetKind::Bench] {
+                        package_cfg.benches.then_some(CrateType::Bench)
+                    } else {
+                        target
+                            .is_selected_example(&package_cfg.examples, &features)
+                            .then_some(CrateType::Example)
+                    };
+                    if let Some(crate_type) = crate_type {
+                        // Benchmarks and examples may use dev-dependencies, like tests.
+                        crates.push(Crate {
+                            name: target_name,
+                            package_name: package.name.to_owned(),
+                            version: Some(package.version.to_owned()),
+                            types: vec![crate_type],
+                            features: features_without_deps.clone(),
+                            edition: package.edition.to_owned(),
+                            license: package.license.clone(),
+                            license_file: package.license_file.clone(),
+                            package_dir: package_dir.clone(),
+                            main_src: main_src.to_owned(),
+                            target: target_triple.clone(),
+                            externs: get_externs(
+                                package,
+                                &metadata.packages,
+                                &features,
+                                &target_description,
+                                &target_kinds,
+                                true,
+                            )?,
+                            cfgs: cfgs.to_owned(),
+                            ..Default::default()
+                        });
+                    }
+                    continue;
+                }
+                // Don't generate an entry for integration tests, they will be covered by the test case
+                // below.
+                if target_kinds != [TargetKind::Test] {
+                    crates.push(Crate {
+                        name: target_name.clone(),
+                        package_name: package.name.to_owned(),
+                        version: Some(package.version.to_owned()),
+                        types: target.crate_types.clone(),
+                        features: features_without_deps.clone(),
+                        edition: package.edition.to_owned(),
+                        license: package.license.clone(),
+                        license_file: package.license_file.clone(),
+                        package_dir: package_dir.clone(),
+                        main_src: main_src.to_owned(),
+                        target: target_triple.clone(),
+                        externs: get_externs(
+                            package,
+                            &metadata.packages,
+                            &features,
+                            &target_description,
+                            &target_kinds,
+                            false,
+                        )?,
+                        cfgs: cfgs.to_owned(),
+                        ..Default::default()
+                    });
+                }
+                // This includes both unit tests and integration tests.
+                if target.test && include_tests {
+                    let test_type = if targets_without_harness.contains(&target_name) {
+                        CrateType::TestNoHarness
+                    } else {
+                        CrateType::Test
+                    };
+                    crates.push(Crate {
+                        name: target_name,
+                        package_name: package.name.to_owned(),
+                        version: Some(package.version.to_owned()),
+                        types: vec![test_type],
+                        features: features_without_deps.clone(),
+                        edition: package.edition.to_owned(),
+                        license: package.license.clone(),
+                        license_file: package.license_file.clone(),
+                        package_dir: package_dir.clone(),
+                        main_src: main_src.to_owned(),
+                        target: target_triple.clone(),
+                        externs: get_externs(
+                            package,
+                            &metadata.packages,
+                            &features,
+                            &target_description,
+                            &target_kinds,
+                            true,
+                        )?,
+                        cfgs: cfgs.to_owned(),
+                        ..Default::default()
+                    });
+                }
             }
-            // This includes both unit tests and integration tests.
-            if target.test && include_tests {
-                crates.push(Crate {
-                    name: target_name,
-                    package_name: package.name.to_owned(),
-                    version: Some(package.version.to_owned()),
-                    types: vec![CrateType::Test],
-                    features: features_without_deps.clone(),
-                    edition: package.edition.to_owned(),
-                    license: package.license.clone(),
-                    license_file: package.license_file.clone(),
-                    package_dir: package_dir.clone(),
-                    main_src: main_src.to_owned(),
-                    target: target_triple.clone(),
-                    externs: get_externs(
-                        package,
-                        &metadata.packages,
-                        &features,
-                        cfgs,
-                        &target_kinds,
-                        true,
-                    )?,
-                    cfgs: cfgs.to_owned(),
-                    ..Default::default()
-                });
+        }
+
+        // Use the recorded output of the package's build script, if any, in place of running it.
+        if let Some(directives) = BuildScriptDirectives::read(&package_dir)? {
+            for c in &mut crates[first_package_crate..] {
+                directives.apply(c);
             }
         }
     }
     Ok(crates)
 }
 
+/// Returns the names of the targets in the given `Cargo.toml` which have `harness = false`, with
+/// hyphens replaced by underscores.
+///
+/// `cargo metadata` doesn't include this information, so it must be read from the manifest.
+pub fn targets_without_harness(manifest_path: &Path) -> Result<Vec<String>> {
+    let manifest = match read_to_string(manifest_path) {
+        Ok(manifest) => manifest,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {manifest_path:?}")),
+    };
+    let manifest: toml::Table =
+        toml::from_str(&manifest).with_context(|| format!("failed to parse {manifest_path:?}"))?;
+    let has_harness =
+        |target: &toml::Value| target.get("harness").and_then(toml::Value::as_bool) != Some(false);
+
+    let mut names = Vec::new();
+    if let Some(lib) = manifest.get("lib").filter(|lib| !has_harness(lib)) {
+        // The library name defaults to the package name.
+        let name = lib.get("name").or_else(|| manifest.get("package")?.get("name"));
+        names.extend(name.and_then(toml::Value::as_str).map(str::to_owned));
+    }
+    for section in ["bin", "test", "bench"] {
+        for target in manifest.get(section).and_then(toml::Value::as_array).into_iter().flatten() {
+            if !has_harness(target) {
+                names.extend(target.get("name").and_then(toml::Value::as_str).map(str::to_owned));
+            }
+        }
+    }
+    Ok(names.into_iter().map(|name| name.replace('-', "_")).collect())
+}
+
 fn get_externs(
     package: &PackageMetadata,
     packages: &[PackageMetadata],
     features: &[String],
-    cfgs: &[String],
+    target: &TargetDescription,
     target_kinds: &[TargetKind],
     test: bool,
 ) -> Result<Vec<Extern>> {
-    let mut externs = package
-        .dependencies
-        .iter()
-        .filter_map(|dependency| {
-            // Kind is None for normal dependencies, as opposed to dev dependencies.
-            if dependency.enabled(features, cfgs)
-                && dependency.kind.as_deref() != Some("build")
-                && (dependency.kind.is_none() || test)
-            {
-                Some(make_extern(packages, dependency))
-            } else {
-                None
-            }
-        })
-        .collect::<Result<Vec<Extern>>>()?;
+    let mut externs = Vec::new();
+    for dependency in &package.dependencies {
+        // Kind is None for normal dependencies, as opposed to dev dependencies.
+        if dependency.kind.as_deref() != Some("build")
+            && (dependency.kind.is_none() || test)
+            && dependency.enabled(features, target)?
+        {
+            externs.push(make_extern(packages, dependency)?);
+        }
+    }
 
-    // If there is a library target and this is a binary or integration test, add the library as an
-    // extern.
-    if matches!(target_kinds, [TargetKind::Bin] | [TargetKind::Test]) {
+    // If there is a library target and this is a binary, integration test, benchmark or example,
+    // add the library as an extern.
+    if matches!(
+        target_kinds,
+        [TargetKind::Bin] | [TargetKind::Test] | [TargetKind::Bench] | [TargetKind::Example]
+    ) {
         for target in &package.targets {
             if target.kind.contains(&TargetKind::Lib) {
                 let lib_name = target.name.replace('-', "_");
@@ -389,6 +518,10 @@ mod tests {
     use googletest::prelude::assert_that;
     use std::fs::{read_to_string, File};
 
+    fn host() -> TargetDescription {
+        TargetDescription::new("x86_64-unknown-linux-gnu", &[]).unwrap()
+    }
+
     #[test]
     fn extract_package_dir_from_id() -> Result<()> {
         assert_eq!(
@@ -469,6 +602,58 @@ mod tests {
         );
     }
 
+    #[test]
+    fn find_targets_without_harness() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let manifest_path = dir.path().join("Cargo.toml");
+        std::fs::write(
+            &manifest_path,
+            r#"
+[package]
+name = "some-package"
+
+[lib]
+harness = false
+
+[[test]]
+name = "with-harness"
+
+[[test]]
+name = "custom-harness"
+harness = false
+"#,
+        )?;
+        assert_eq!(
+            targets_without_harness(&manifest_path)?,
+            vec!["some_package".to_string(), "custom_harness".to_string()]
+        );
+        assert_eq!(
+            targets_without_harness(&dir.path().join("missing.toml"))?,
+            Vec::<String>::new()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn select_examples() {
+        let example = |name: &str, required_features: &[&str]| TargetMetadata {
+            name: name.to_string(),
+            crate_types: vec![CrateType::Bin],
+            kind: vec![TargetKind::Example],
+            required_features: required_features.iter().map(|f| f.to_string()).collect(),
+            ..Default::default()
+        };
+        let examples = ["echo".to_string(), "demo".to_string()];
+        let features = ["std".to_string()];
+
+        assert!(example("echo", &[]).is_selected_example(&examples, &features));
+        assert!(example("demo", &["std"]).is_selected_example(&examples, &features));
+        assert!(!example("demo", &["std", "net"]).is_selected_example(&examples, &features));
+        assert!(!example("other", &[]).is_selected_example(&examples, &features));
+        assert!(!TargetMetadata { kind: vec![TargetKind::Bin], ..example("echo", &[]) }
+            .is_selected_example(&examples, &features));
+    }
+
     #[test]
     fn get_externs_cfg() {
         let package = PackageMetadata {
@@ -531,7 +716,7 @@ mod tests {
             },
         ];
         assert_eq!(
-            get_externs(&package, &packages, &[], &[], &[], false).unwrap(),
+            get_externs(&package, &packages, &[], &host(), &[], false).unwrap(),
             vec![
                 Extern {
                     name: "alwayslib".to_string(),
@@ -549,6 +734,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn get_externs_target_triple() {
+        let package = PackageMetadata {
+            name: "test_package".to_string(),
+            dependencies: vec![DependencyMetadata {
+                name: "androidlib".to_string(),
+                kind: None,
+                optional: false,
+                target: Some("aarch64-linux-android".to_string()),
+                rename: None,
+            }],
+            ..Default::default()
+        };
+        let packages = vec![
+            package.clone(),
+            PackageMetadata {
+                name: "androidlib".to_string(),
+                targets: vec![TargetMetadata {
+                    name: "androidlib".to_string(),
+                    kind: vec![TargetKind::Lib],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        ];
+        let android = TargetDescription::new("aarch64-linux-android", &[]).unwrap();
+        assert_eq!(get_externs(&package, &packages, &[], &host(), &[], false).unwrap(), vec![]);
+        assert_eq!(
+            get_externs(&package, &packages, &[], &android, &[], false).unwrap(),
+            vec![Extern {
+                name: "androidlib".to_string(),
+                lib_name: "androidlib".to_string(),
+                raw_name: "androidlib".to_string(),
+                extern_type: ExternType::Rust
+            }]
+        );
+    }
+
     #[test]
     fn get_externs_extra_cfg() {
         let package = PackageMetadata {
@@ -595,7 +818,15 @@ mod tests {
             },
         ];
         assert_eq!(
-            get_externs(&package, &packages, &[], &["foo".to_string()], &[], false).unwrap(),
+            get_externs(
+                &package,
+                &packages,
+                &[],
+                &TargetDescription::new("x86_64-unknown-linux-gnu", &["foo".to_string()]).unwrap(),
+                &[],
+                false
+            )
+            .unwrap(),
             vec![Extern {
                 name: "foolib".to_string(),
                 lib_name: "foolib".to_string(),
@@ -656,7 +887,8 @@ mod tests {
             },
         ];
         assert_eq!(
-            get_externs(&package, &packages, &["dep:bar".to_string()], &[], &[], false).unwrap(),
+            get_externs(&package, &packages, &["dep:bar".to_string()], &host(), &[], false)
+                .unwrap(),
             vec![
                 Extern {
                     name: "bar".to_string(),
@@ -673,7 +905,8 @@ mod tests {
             ]
         );
         assert_eq!(
-            get_externs(&package, &packages, &["dep:baz".to_string()], &[], &[], false).unwrap(),
+            get_externs(&package, &packages, &["dep:baz".to_string()], &host(), &[], false)
+                .unwrap(),
             vec![
                 Extern {
                     name: "baz".to_string(),
@@ -717,6 +950,7 @@ mod tests {
                         )
                     })
                     .unwrap(),
+                None,
             )
             .unwrap();
             let cargo_metadata_path = testdata_directory_path.join("cargo.metadata");
@@ -0,0 +1,158 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for parsing the JSON output of `cargo build --unit-graph` and
+//! `cargo build --message-format=json`.
+
+use super::metadata::TargetKind;
+use super::CrateType;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// `cargo build --unit-graph` output, describing each rustc invocation which the build would run.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct UnitGraph {
+    pub units: Vec<Unit>,
+}
+
+/// A single unit of compilation, i.e. a rustc invocation or a build script run.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct Unit {
+    pub pkg_id: String,
+    pub target: UnitTarget,
+    pub profile: Profile,
+    /// The target triple, or `None` for the host.
+    pub platform: Option<String>,
+    /// E.g. "build", "test", "bench" or "run-custom-build".
+    pub mode: String,
+    pub features: Vec<String>,
+    pub dependencies: Vec<UnitDependency>,
+}
+
+impl Unit {
+    /// Returns whether the unit builds a test or benchmark, rather than a normal target.
+    pub fn is_test(&self) -> bool {
+        matches!(self.mode.as_str(), "test" | "bench")
+    }
+
+    /// Returns whether the unit builds or runs a build script.
+    pub fn is_build_script(&self) -> bool {
+        self.mode == "run-custom-build" || self.target.kind.contains(&TargetKind::CustomBuild)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct UnitTarget {
+    pub kind: Vec<TargetKind>,
+    pub crate_types: Vec<CrateType>,
+    pub name: String,
+    pub src_path: PathBuf,
+}
+
+/// The profile settings which affect the `-C` flags passed to rustc.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct Profile {
+    pub opt_level: String,
+    pub panic: String,
+    pub debug_assertions: bool,
+    pub overflow_checks: bool,
+    pub rpath: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct UnitDependency {
+    /// Index of the dependency in `UnitGraph::units`.
+    pub index: usize,
+    pub extern_crate_name: String,
+}
+
+/// A message from `cargo build --message-format=json`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Message {
+    CompilerArtifact(Artifact),
+    CompilerMessage(CompilerMessage),
+    BuildScriptExecuted(BuildScriptOutput),
+    #[serde(other)]
+    Other,
+}
+
+/// The files produced by compiling a unit.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct Artifact {
+    pub package_id: String,
+    pub target: UnitTarget,
+    pub profile: ArtifactProfile,
+    /// The path of the binary, for binaries, tests and benchmarks.
+    pub executable: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct ArtifactProfile {
+    pub test: bool,
+}
+
+/// A diagnostic from rustc while compiling a unit.
+///
+/// Cargo prints these before the artifact of the unit which they came from.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct CompilerMessage {
+    pub package_id: String,
+    pub target: UnitTarget,
+    pub message: Diagnostic,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    /// E.g. "error", "warning" or "note".
+    pub level: String,
+    pub spans: Vec<DiagnosticSpan>,
+}
+
+impl Diagnostic {
+    /// Returns a one-line summary of the diagnostic if it is a warning, with the location of its
+    /// primary span if it has one.
+    pub fn warning_summary(&self) -> Option<String> {
+        if self.level != "warning" {
+            return None;
+        }
+        Some(match self.spans.iter().find(|span| span.is_primary) {
+            Some(span) => format!(
+                "{} ({}:{}:{})",
+                self.message, span.file_name, span.line_start, span.column_start
+            ),
+            None => self.message.clone(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct DiagnosticSpan {
+    pub file_name: String,
+    pub line_start: usize,
+    pub column_start: usize,
+    pub is_primary: bool,
+}
+
+/// The instructions printed by a package's build script.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+pub struct BuildScriptOutput {
+    pub package_id: String,
+    /// Libraries to link against, e.g. "static=foo" or "foo".
+    pub linked_libs: Vec<String>,
+    pub cfgs: Vec<String>,
+    pub out_dir: PathBuf,
+}
@@ -26,10 +26,16 @@
 //! options that apply to that variant across all packages, and then a map of
 //! `PackageVariantConfig`s for options specific to a particular package of the variant.
 
+mod jsonc;
+pub mod schema;
+
 use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::{Map, Value};
+use serde_json::{json, Map, Value};
 use std::collections::BTreeMap;
+use std::env;
+use std::fs::read_to_string;
 use std::path::{Path, PathBuf};
 
 fn default_apex_available() -> Vec<String> {
@@ -40,6 +46,22 @@ fn is_default_apex_available(apex_available: &[String]) -> bool {
     apex_available == default_apex_available()
 }
 
+fn default_targets() -> Vec<String> {
+    vec!["x86_64-unknown-linux-gnu".to_string()]
+}
+
+fn is_default_targets(targets: &[String]) -> bool {
+    targets == default_targets()
+}
+
+fn default_bazel_dep_prefix() -> String {
+    "@crates//:".to_string()
+}
+
+fn is_default_bazel_dep_prefix(bazel_dep_prefix: &str) -> bool {
+    bazel_dep_prefix == default_bazel_dep_prefix()
+}
+
 fn default_true() -> bool {
     true
 }
@@ -53,13 +75,21 @@ fn is_false(value: &bool) -> bool {
 }
 
 /// Options that apply to everything.
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub variants: Vec<VariantConfig>,
     /// Package specific config options across all variants.
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub package: BTreeMap<String, PackageConfig>,
+    /// Configs to inherit top-level options from, relative to the directory of this config or, if
+    /// they start with `//`, to `ANDROID_BUILD_TOP`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extends: Vec<String>,
+    /// The top-level options inherited from the configs in `extends`, so that they can be left out
+    /// when this config is written back.
+    #[serde(skip)]
+    pub base: Map<String, Value>,
 }
 
 /// Inserts entries from `defaults` into `variant` if neither it nor `ignored_fields` contain
@@ -76,25 +106,170 @@ fn add_defaults_to_variant(
     }
 }
 
+/// Inserts the options from `base` which aren't set in `options`. Options which are maps are
+/// merged, with entries in `options` taking precedence.
+fn inherit_options(options: &mut Map<String, Value>, base: &Map<String, Value>) {
+    for (key, base_value) in base {
+        match (options.get_mut(key), base_value) {
+            (None, _) => {
+                options.insert(key.to_owned(), base_value.to_owned());
+            }
+            (Some(Value::Object(map)), Value::Object(base_map)) => {
+                for (map_key, map_value) in base_map {
+                    map.entry(map_key).or_insert_with(|| map_value.to_owned());
+                }
+            }
+            (Some(_), _) => {}
+        }
+    }
+}
+
+/// Removes the options from `options` which would be inherited from `base` anyway.
+fn remove_inherited_options(options: &mut Map<String, Value>, base: &Map<String, Value>) {
+    for (key, base_value) in base {
+        match (options.get_mut(key), base_value) {
+            (Some(value), _) if value == base_value => {
+                options.remove(key);
+            }
+            (Some(Value::Object(map)), Value::Object(base_map)) => {
+                map.retain(|map_key, map_value| base_map.get(map_key) != Some(map_value));
+                if map.is_empty() {
+                    options.remove(key);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Returns the JSON value of the given variant option when it isn't set.
+///
+/// This is whichever of the possible default values gives `VariantConfig::default()` when set
+/// explicitly.
+fn default_variant_option(key: &str) -> Value {
+    [
+        Value::Null,
+        json!(false),
+        json!(true),
+        json!([]),
+        json!({}),
+        json!(default_apex_available()),
+        json!(default_targets()),
+        json!(default_bazel_dep_prefix()),
+    ]
+    .into_iter()
+    .find(|candidate| {
+        let mut variant = Map::new();
+        variant.insert(key.to_owned(), candidate.to_owned());
+        serde_json::from_value::<VariantConfig>(Value::Object(variant)).ok()
+            == Some(VariantConfig::default())
+    })
+    .unwrap_or_else(|| panic!("No default value for variant option {key:?}."))
+}
+
+/// Returns the path of a config given in `extends`, for a config in `directory`.
+fn resolve_extends_path(path: &str, directory: Option<&Path>) -> Result<PathBuf> {
+    if let Some(path) = path.strip_prefix("//") {
+        let android_top = env::var("ANDROID_BUILD_TOP")
+            .with_context(|| format!("ANDROID_BUILD_TOP must be set to extend \"//{path}\""))?;
+        Ok(Path::new(&android_top).join(path))
+    } else if let Some(directory) = directory {
+        Ok(directory.join(path))
+    } else {
+        bail!("Can't extend relative path {path:?} of config not read from a file");
+    }
+}
+
+/// Loads the top-level options from the given configs, with options from later configs taking
+/// precedence over earlier ones.
+///
+/// `stack` is the configs currently being loaded, to detect cycles.
+fn load_extended_options(
+    extends: &[String],
+    directory: Option<&Path>,
+    stack: &mut Vec<PathBuf>,
+) -> Result<Map<String, Value>> {
+    let mut options = Map::new();
+    for path in extends {
+        let path = resolve_extends_path(path, directory)?;
+        let mut extended_options =
+            load_base_config(&path, stack).with_context(|| format!("in {path:?}"))?;
+        inherit_options(&mut extended_options, &options);
+        options = extended_options;
+    }
+    Ok(options)
+}
+
+/// Loads the top-level options from the given base config, including those it inherits.
+fn load_base_config(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Map<String, Value>> {
+    let canonical_path =
+        path.canonicalize().with_context(|| format!("failed to read file: {path:?}"))?;
+    if stack.contains(&canonical_path) {
+        bail!("config extends itself");
+    }
+    let json_string =
+        read_to_string(path).with_context(|| format!("failed to read file: {path:?}"))?;
+    let document = jsonc::parse(&json_string).context("failed to parse config")?;
+    let errors = validate(&document);
+    if !errors.is_empty() {
+        bail!("invalid config:\n{}", errors.join("\n"));
+    }
+    for key in ["package", "select", "variants"] {
+        if document.value.get(key).is_some() {
+            bail!(
+                "{}: configs which are extended may only have top-level options, not `{key}`",
+                document.position(&jsonc::child_pointer("", key))
+            );
+        }
+    }
+    let Value::Object(mut options) = document.value else {
+        bail!("Failed to parse config: not an object");
+    };
+    let extends = match options.remove("extends") {
+        Some(extends) => serde_json::from_value(extends)?,
+        None => Vec::new(),
+    };
+
+    stack.push(canonical_path);
+    let base = load_extended_options(&extends, path.parent(), stack);
+    stack.pop();
+    inherit_options(&mut options, &base?);
+    Ok(options)
+}
+
 impl Config {
     /// Names of all fields in [`Config`] other than `variants` (which is treated specially).
-    const FIELD_NAMES: [&'static str; 1] = ["package"];
+    const FIELD_NAMES: [&'static str; 2] = ["extends", "package"];
 
     /// Parses an instance of this config from the given JSON file.
     pub fn from_file(filename: &Path) -> Result<Self> {
-        let json_string = std::fs::read_to_string(filename)
+        let json_string = read_to_string(filename)
             .with_context(|| format!("failed to read file: {:?}", filename))?;
-        Self::from_json_str(&json_string)
+        Self::from_json_str(&json_string, filename.parent())
+            .with_context(|| format!("in {filename:?}"))
     }
 
-    /// Parses an instance of this config from a string of JSON.
-    pub fn from_json_str(json_str: &str) -> Result<Self> {
-        // Ignore comments.
-        let json_str: String =
-            json_str.lines().filter(|l| !l.trim_start().starts_with("//")).collect();
+    /// Parses an instance of this config from a string of JSON, which may contain comments.
+    ///
+    /// Relative paths in `extends` are resolved from `directory`, and are an error if it is `None`.
+    pub fn from_json_str(json_str: &str, directory: Option<&Path>) -> Result<Self> {
         // First parse into untyped map.
-        let mut config: Map<String, Value> =
-            serde_json::from_str(&json_str).context("failed to parse config")?;
+        let document = jsonc::parse(json_str).context("failed to parse config")?;
+        let errors = validate(&document);
+        if !errors.is_empty() {
+            bail!("invalid config:\n{}", errors.join("\n"));
+        }
+        let Value::Object(mut config) = document.value else {
+            bail!("Failed to parse config: not an object");
+        };
+
+        // Inherit top-level options from extended configs, before they are copied into variants.
+        let extends = match config.get("extends") {
+            Some(extends) => serde_json::from_value(extends.to_owned())?,
+            None => Vec::new(),
+        };
+        let base = load_extended_options(&extends, directory, &mut Vec::new())?;
+        inherit_options(&mut config, &base);
 
         // Flatten variants. First, get the variants from the config file.
         let mut variants = match config.remove("variants") {
@@ -157,7 +332,18 @@ impl Config {
         config.insert("variants".to_string(), Value::Array(variants));
 
         // Parse into `Config` struct.
-        serde_json::from_value(Value::Object(config)).context("failed to parse config")
+        let mut config: Config =
+            serde_json::from_value(Value::Object(config)).context("failed to parse config")?;
+        config.base = base;
+        for (i, variant) in config.variants.iter().enumerate() {
+            if variant.max_rustc_warnings.is_some() && !variant.run_cargo {
+                bail!(
+                    "invalid config: variant {i} sets `max_rustc_warnings` with `run_cargo` false, \
+                     but rustc warnings are only collected when cargo runs"
+                );
+            }
+        }
+        Ok(config)
     }
 
     /// Serializes an instance of this config to a string of pretty-printed JSON.
@@ -171,6 +357,16 @@ impl Config {
         let Value::Array(mut variants) = config.remove("variants").unwrap() else {
             panic!("variants wasn't an array.")
         };
+        // Options which are inherited must be set explicitly wherever they have the default value,
+        // or else the inherited value would be used instead.
+        for variant in &mut variants {
+            let variant = variant.as_object_mut().unwrap();
+            for key in self.base.keys() {
+                if !variant.contains_key(key) {
+                    variant.insert(key.to_owned(), default_variant_option(key));
+                }
+            }
+        }
         let mut packages = if let Some(Value::Object(packages)) = config.remove("package") {
             packages
         } else {
@@ -244,6 +440,14 @@ impl Config {
                 }
             }
         }
+        // Leave out options which are inherited anyway.
+        remove_inherited_options(&mut config, &self.base);
+        for variant in &mut variants {
+            variant
+                .as_object_mut()
+                .unwrap()
+                .retain(|key, value| config.contains_key(key) || self.base.get(key) != Some(value));
+        }
         // Put packages and variants back into the top-level config.
         if variants.len() > 1 || !variants[0].as_object().unwrap().is_empty() {
             config.insert("variants".to_string(), Value::Array(variants));
@@ -257,6 +461,132 @@ impl Config {
     }
 }
 
+/// Checks that every option in a config file is valid where it appears, before the variants are
+/// flattened, and returns an error for each one which isn't, giving its position in the file.
+fn validate(document: &jsonc::Document) -> Vec<String> {
+    let mut errors = Vec::new();
+    let Value::Object(config) = &document.value else {
+        return vec![format!("{}: config must be an object", document.position(""))];
+    };
+    for (key, value) in config {
+        let pointer = jsonc::child_pointer("", key);
+        match key.as_str() {
+            "extends" => {
+                if serde_json::from_value::<Vec<String>>(value.to_owned()).is_err() {
+                    errors.push((
+                        document.position(&pointer),
+                        "`extends` must be a list of strings".to_string(),
+                    ));
+                }
+            }
+            "variants" => {
+                let Value::Array(variants) = value else {
+                    errors.push((
+                        document.position(&pointer),
+                        "`variants` must be an array".to_string(),
+                    ));
+                    continue;
+                };
+                for (i, variant) in variants.iter().enumerate() {
+                    let pointer = jsonc::child_pointer(&pointer, &i.to_string());
+                    let context = format!("variant {i}");
+                    let Value::Object(variant) = variant else {
+                        errors.push((
+                            document.position(&pointer),
+                            format!("{context} must be an object"),
+                        ));
+                        continue;
+                    };
+                    for (key, value) in variant {
+                        let pointer = jsonc::child_pointer(&pointer, key);
+                        if key == "package" {
+                            validate_packages(
+                                document,
+                                value,
+                                &pointer,
+                                &format!("{context}, "),
+                                false,
+                                &mut errors,
+                            );
+                        } else {
+                            validate_field::<VariantConfig>(
+                                document,
+                                key,
+                                value,
+                                &pointer,
+                                &format!("{context}: "),
+                                &mut errors,
+                            );
+                        }
+                    }
+                }
+            }
+            "package" => validate_packages(document, value, &pointer, "", true, &mut errors),
+            _ => validate_field::<VariantConfig>(document, key, value, &pointer, "", &mut errors),
+        }
+    }
+    errors.sort();
+    errors.into_iter().map(|(position, error)| format!("{position}: {error}")).collect()
+}
+
+/// Checks the per-package options in `packages`, which is either the top-level `package` map if
+/// `top_level` is true or else a variant's.
+fn validate_packages(
+    document: &jsonc::Document,
+    packages: &Value,
+    pointer: &str,
+    context: &str,
+    top_level: bool,
+    errors: &mut Vec<(jsonc::Position, String)>,
+) {
+    let Value::Object(packages) = packages else {
+        errors.push((document.position(pointer), format!("{context}`package` must be an object")));
+        return;
+    };
+    for (package_name, package) in packages {
+        let pointer = jsonc::child_pointer(pointer, package_name);
+        let context = format!("{context}package {package_name:?}: ");
+        let Value::Object(package) = package else {
+            errors.push((document.position(&pointer), format!("{context}must be an object")));
+            continue;
+        };
+        for (key, value) in package {
+            let pointer = jsonc::child_pointer(&pointer, key);
+            if top_level && PackageConfig::FIELD_NAMES.contains(&key.as_str()) {
+                validate_field::<PackageConfig>(document, key, value, &pointer, &context, errors);
+            } else {
+                validate_field::<PackageVariantConfig>(
+                    document, key, value, &pointer, &context, errors,
+                );
+            }
+        }
+    }
+}
+
+/// Checks that `key` is a valid field of `T` with the given value.
+///
+/// All fields of the config structs have defaults, so this can be done by deserializing an object
+/// with only that field.
+fn validate_field<T: DeserializeOwned>(
+    document: &jsonc::Document,
+    key: &str,
+    value: &Value,
+    pointer: &str,
+    context: &str,
+    errors: &mut Vec<(jsonc::Position, String)>,
+) {
+    let mut object = Map::new();
+    object.insert(key.to_string(), value.clone());
+    if let Err(e) = serde_json::from_value::<T>(Value::Object(object)) {
+        let position = document.position(pointer);
+        if e.to_string().starts_with("unknown field") {
+            errors.push((position, format!("{context}{e}")));
+        } else {
+            errors.push((position, format!("{context}`{key}`: {e}")));
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct VariantConfig {
@@ -331,6 +661,37 @@ pub struct VariantConfig {
     /// Generate a rules.mk build file for this variant if true.
     #[serde(default, skip_serializing_if = "is_false")]
     pub generate_rulesmk: bool,
+    /// Generate a BUILD.bazel file using rules_rust for this variant if true.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub generate_bazel: bool,
+    /// Prefix of Bazel labels for dependencies on crates which aren't in the same package, to which
+    /// the crate's package name is appended.
+    #[serde(
+        default = "default_bazel_dep_prefix",
+        skip_serializing_if = "is_default_bazel_dep_prefix"
+    )]
+    pub bazel_dep_prefix: String,
+    /// Generate a BUILD.gn file for this variant if true.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub generate_gn: bool,
+    /// Map of GN labels to use for dependencies, keyed by crate name, instead of the default of
+    /// `"../<package name>:<crate name>"`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub gn_label_overrides: BTreeMap<String, String>,
+    /// Rust target triples to build for. If there is more than one, the per-target differences
+    /// between modules are output in Soong `arch` and `target` blocks.
+    #[serde(default = "default_targets", skip_serializing_if = "is_default_targets")]
+    pub targets: Vec<String>,
+    /// Soong configuration value for which this variant's modules are used. Variants with the same
+    /// condition are merged into a single set of modules, with Soong `select()` expressions for
+    /// the properties which differ between them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub select: Option<VariantSelect>,
+    /// Maximum number of distinct rustc warnings allowed when building the crates for this variant.
+    /// If there are more, generation fails. If this is set, modules with warnings are also marked
+    /// with a comment. Only valid if `run_cargo` is true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rustc_warnings: Option<usize>,
 }
 
 impl Default for VariantConfig {
@@ -358,10 +719,46 @@ impl Default for VariantConfig {
             run_cargo: true,
             generate_androidbp: true,
             generate_rulesmk: false,
+            generate_bazel: false,
+            bazel_dep_prefix: default_bazel_dep_prefix(),
+            generate_gn: false,
+            gn_label_overrides: Default::default(),
+            targets: default_targets(),
+            select: None,
+            max_rustc_warnings: None,
         }
     }
 }
 
+/// A Soong configuration value for which a variant is used.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct VariantSelect {
+    #[serde(flatten)]
+    pub condition: SelectCondition,
+    /// The value of the condition for which the variant is used. If this is not set then the
+    /// variant is used by default, when none of the other variants with the same condition match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<SelectValue>,
+}
+
+/// A Soong configuration value to select variants on.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectCondition {
+    /// A release flag, e.g. `"RELEASE_RUST_FOO"`.
+    ReleaseFlag(String),
+    /// A Soong config variable, given as a namespace and variable name.
+    SoongConfigVariable(String, String),
+}
+
+/// The value of a [`SelectCondition`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum SelectValue {
+    Bool(bool),
+    String(String),
+}
+
 /// Options that apply to everything in a package (i.e. everything associated with a particular
 /// Cargo.toml file), for all variants.
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
@@ -440,6 +837,13 @@ pub struct PackageVariantConfig {
     /// Directories with headers to export for C usage.
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub exported_c_header_dir: Vec<PathBuf>,
+    /// Whether to output `rust_benchmark` modules for the package's `[[bench]]` targets.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub benches: bool,
+    /// Names of `[[example]]` targets to output `rust_binary` modules for. Examples whose
+    /// `required-features` are not all enabled are skipped.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<String>,
 }
 
 impl Default for PackageVariantConfig {
@@ -459,6 +863,8 @@ impl Default for PackageVariantConfig {
             test_data: Default::default(),
             whole_static_libs: Default::default(),
             exported_c_header_dir: Default::default(),
+            benches: false,
+            examples: Default::default(),
         }
     }
 }
@@ -505,6 +911,7 @@ mod tests {
                 }
             ]
         }"#,
+            None,
         )
         .unwrap();
 
@@ -590,6 +997,7 @@ mod tests {
                 ]
                 .into_iter()
                 .collect(),
+                ..Default::default()
             }
         );
     }
@@ -641,6 +1049,7 @@ mod tests {
             )]
             .into_iter()
             .collect(),
+            ..Default::default()
         };
 
         assert_eq!(
@@ -675,6 +1084,176 @@ mod tests {
         );
     }
 
+    #[test]
+    fn variant_select() {
+        let config = Config::from_json_str(
+            r#"{
+            "select": { "release_flag": "RELEASE_RUST_FOO_STD" },
+            "variants": [
+                { "features": ["std"], "select": { "release_flag": "RELEASE_RUST_FOO_STD", "value": true } },
+                { "features": [] },
+                { "select": { "soong_config_variable": ["foo", "mode"], "value": "fast" } }
+            ]
+        }"#,
+            None,
+        )
+        .unwrap();
+
+        let selects: Vec<_> = config.variants.iter().map(|v| v.select.clone()).collect();
+        assert_eq!(
+            selects,
+            vec![
+                Some(VariantSelect {
+                    condition: SelectCondition::ReleaseFlag("RELEASE_RUST_FOO_STD".to_string()),
+                    value: Some(SelectValue::Bool(true)),
+                }),
+                Some(VariantSelect {
+                    condition: SelectCondition::ReleaseFlag("RELEASE_RUST_FOO_STD".to_string()),
+                    value: None,
+                }),
+                Some(VariantSelect {
+                    condition: SelectCondition::SoongConfigVariable(
+                        "foo".to_string(),
+                        "mode".to_string()
+                    ),
+                    value: Some(SelectValue::String("fast".to_string())),
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn validation_errors() {
+        let error = Config::from_json_str(
+            r#"{
+  "tests": "yes", // Should be a boolean.
+  "package": {
+    "foo": { "patch": "foo.patch", "copy_out": 1 }
+  },
+  "variants": [
+    {},
+    {
+      "package": { "bar": { "patch": "bar.patch" } },
+      "unknown_option": true
+    }
+  ]
+}"#,
+            None,
+        )
+        .unwrap_err();
+        // The lists of expected fields in unknown field errors change whenever an option is added,
+        // so only check the start of each error.
+        let error = format!("{error:#}");
+        let lines: Vec<_> = error.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "invalid config:");
+        assert_eq!(lines[1], "2:3: `tests`: invalid type: string \"yes\", expected a boolean");
+        assert_eq!(
+            lines[2],
+            "4:36: package \"foo\": `copy_out`: invalid type: integer `1`, expected a boolean"
+        );
+        assert!(lines[3].starts_with(
+            "9:29: variant 1, package \"bar\": unknown field `patch`, expected one of "
+        ));
+        assert!(lines[4]
+            .starts_with("10:7: variant 1: unknown field `unknown_option`, expected one of "));
+    }
+
+    #[test]
+    fn max_rustc_warnings_without_cargo() {
+        let error = Config::from_json_str(
+            r#"{ "max_rustc_warnings": 0, "variants": [{}, { "run_cargo": false }] }"#,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "invalid config: variant 1 sets `max_rustc_warnings` with `run_cargo` false, but \
+             rustc warnings are only collected when cargo runs"
+        );
+    }
+
+    #[test]
+    fn extends() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("common.json"),
+            r#"{ "min_sdk_version": "29", "vendor_available": false }"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("base.json"),
+            r#"{
+  "extends": ["common.json"],
+  "apex_available": ["com.android.foo"],
+  "min_sdk_version": "30",
+  "module_visibility": { "liba": ["//a"], "libb": ["//b"] },
+}"#,
+        )
+        .unwrap();
+        let config_path = dir.path().join("cargo_embargo.json");
+        std::fs::write(
+            &config_path,
+            r#"{
+  "extends": ["base.json"],
+  "module_visibility": { "libb": ["//c"] },
+  "variants": [{}, { "min_sdk_version": "31", "vendor_available": true }]
+}"#,
+        )
+        .unwrap();
+
+        let config = Config::from_file(&config_path).unwrap();
+        assert_eq!(config.extends, vec!["base.json".to_string()]);
+        let variant = &config.variants[0];
+        assert_eq!(variant.apex_available, vec!["com.android.foo".to_string()]);
+        assert_eq!(variant.min_sdk_version, Some("30".to_string()));
+        assert!(!variant.vendor_available);
+        assert_eq!(
+            variant.module_visibility,
+            [
+                ("liba".to_string(), vec!["//a".to_string()]),
+                ("libb".to_string(), vec!["//c".to_string()]),
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert_eq!(config.variants[1].min_sdk_version, Some("31".to_string()));
+        assert!(config.variants[1].vendor_available);
+
+        // Only the options which differ from the inherited ones are written back.
+        assert_eq!(
+            config.to_json_string().unwrap(),
+            r#"{
+  "extends": [
+    "base.json"
+  ],
+  "module_visibility": {
+    "libb": [
+      "//c"
+    ]
+  },
+  "variants": [
+    {},
+    {
+      "min_sdk_version": "31",
+      "vendor_available": true
+    }
+  ]
+}"#
+        );
+
+        std::fs::write(dir.path().join("common.json"), r#"{ "extends": ["base.json"] }"#).unwrap();
+        assert_eq!(
+            format!("{:#}", Config::from_file(&config_path).unwrap_err()),
+            format!(
+                "in {config_path:?}: in {:?}: in {:?}: in {:?}: config extends itself",
+                dir.path().join("base.json"),
+                dir.path().join("common.json"),
+                dir.path().join("base.json"),
+            )
+        );
+    }
+
     #[test]
     fn factor_trivial_variant() {
         let config = Config {
@@ -683,7 +1262,7 @@ mod tests {
                 package: [("argh".to_string(), Default::default())].into_iter().collect(),
                 ..Default::default()
             }],
-            package: Default::default(),
+            ..Default::default()
         };
 
         assert_eq!(
@@ -0,0 +1,373 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parser for JSON with comments, which keeps track of where each value is in the file so that
+//! errors can point to it.
+//!
+//! Both `//` and `/* */` comments are allowed anywhere whitespace is, as are trailing commas in
+//! objects and arrays.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+/// A 1-based line and column in a file.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// A parsed JSON document, along with the position of each value in it.
+#[derive(Clone, Debug)]
+pub struct Document {
+    pub value: Value,
+    /// The position of each value, keyed by its JSON pointer. For object members this is the
+    /// position of the key.
+    positions: BTreeMap<String, Position>,
+}
+
+impl Document {
+    /// Returns the position of the value with the given JSON pointer, or of its closest ancestor
+    /// if it isn't in the document.
+    pub fn position(&self, pointer: &str) -> Position {
+        let mut pointer = pointer;
+        loop {
+            if let Some(position) = self.positions.get(pointer) {
+                return *position;
+            }
+            match pointer.rsplit_once('/') {
+                Some((parent, _)) => pointer = parent,
+                None => return Position { line: 1, column: 1 },
+            }
+        }
+    }
+}
+
+/// Returns the JSON pointer for the given key or index within the value with JSON pointer
+/// `parent`.
+pub fn child_pointer(parent: &str, key: &str) -> String {
+    format!("{parent}/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+/// Parses the given JSON with comments.
+pub fn parse(text: &str) -> Result<Document> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        index: 0,
+        line: 1,
+        column: 1,
+        positions: BTreeMap::new(),
+    };
+    parser.skip_whitespace()?;
+    let value = parser.parse_value("")?;
+    parser.skip_whitespace()?;
+    if parser.index < parser.chars.len() {
+        return parser.error("unexpected content after the end of the value");
+    }
+    Ok(Document { value, positions: parser.positions })
+}
+
+struct Parser {
+    chars: Vec<char>,
+    index: usize,
+    line: usize,
+    column: usize,
+    positions: BTreeMap<String, Position>,
+}
+
+impl Parser {
+    fn position(&self) -> Position {
+        Position { line: self.line, column: self.column }
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T> {
+        bail!("{}: {message}", self.position())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.index += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        if self.peek() != Some(expected) {
+            return self.error(&format!("expected {expected:?}"));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    /// Skips over any whitespace and comments.
+    fn skip_whitespace(&mut self) -> Result<()> {
+        loop {
+            match (self.peek(), self.chars.get(self.index + 1)) {
+                (Some(c), _) if c.is_whitespace() => {
+                    self.advance();
+                }
+                (Some('/'), Some('/')) => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.advance();
+                    }
+                }
+                (Some('/'), Some('*')) => {
+                    let start = self.position();
+                    self.advance();
+                    self.advance();
+                    while !(self.peek() == Some('*')
+                        && self.chars.get(self.index + 1) == Some(&'/'))
+                    {
+                        if self.advance().is_none() {
+                            bail!("{start}: unterminated comment");
+                        }
+                    }
+                    self.advance();
+                    self.advance();
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Parses a value, recording its position under the given JSON pointer if it isn't an object
+    /// member.
+    fn parse_value(&mut self, pointer: &str) -> Result<Value> {
+        let position = self.position();
+        self.positions.entry(pointer.to_string()).or_insert(position);
+        match self.peek() {
+            Some('{') => self.parse_object(pointer),
+            Some('[') => self.parse_array(pointer),
+            Some('"') => Ok(Value::String(self.parse_string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.position();
+                let mut word = String::new();
+                while let Some(c) = self.peek().filter(char::is_ascii_alphabetic) {
+                    word.push(c);
+                    self.advance();
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "null" => Ok(Value::Null),
+                    _ => bail!("{start}: unexpected {word:?}"),
+                }
+            }
+            Some(c) => self.error(&format!("unexpected {c:?}")),
+            None => self.error("unexpected end of file"),
+        }
+    }
+
+    fn parse_object(&mut self, pointer: &str) -> Result<Value> {
+        self.expect('{')?;
+        let mut map = Map::new();
+        loop {
+            self.skip_whitespace()?;
+            if self.peek() == Some('}') {
+                self.advance();
+                return Ok(Value::Object(map));
+            }
+            let key_position = self.position();
+            if self.peek() != Some('"') {
+                return self.error("expected string key or '}'");
+            }
+            let key = self.parse_string()?;
+            let member_pointer = child_pointer(pointer, &key);
+            if map.contains_key(&key) {
+                bail!("{key_position}: duplicate key {key:?}");
+            }
+            self.positions.insert(member_pointer.clone(), key_position);
+            self.skip_whitespace()?;
+            self.expect(':')?;
+            self.skip_whitespace()?;
+            let value = self.parse_value(&member_pointer)?;
+            map.insert(key, value);
+            self.skip_whitespace()?;
+            match self.peek() {
+                Some(',') => {
+                    self.advance();
+                }
+                Some('}') => {}
+                _ => return self.error("expected ',' or '}'"),
+            }
+        }
+    }
+
+    fn parse_array(&mut self, pointer: &str) -> Result<Value> {
+        self.expect('[')?;
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace()?;
+            if self.peek() == Some(']') {
+                self.advance();
+                return Ok(Value::Array(values));
+            }
+            let element_pointer = child_pointer(pointer, &values.len().to_string());
+            values.push(self.parse_value(&element_pointer)?);
+            self.skip_whitespace()?;
+            match self.peek() {
+                Some(',') => {
+                    self.advance();
+                }
+                Some(']') => {}
+                _ => return self.error("expected ',' or ']'"),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        let start = self.position();
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.advance() {
+                None | Some('\n') => bail!("{start}: unterminated string"),
+                Some('"') => return Ok(value),
+                Some('\\') => {
+                    let escape_position = self.position();
+                    match self.advance() {
+                        Some('"') => value.push('"'),
+                        Some('\\') => value.push('\\'),
+                        Some('/') => value.push('/'),
+                        Some('b') => value.push('\x08'),
+                        Some('f') => value.push('\x0c'),
+                        Some('n') => value.push('\n'),
+                        Some('r') => value.push('\r'),
+                        Some('t') => value.push('\t'),
+                        Some('u') => {
+                            let mut code = self.parse_hex4()?;
+                            // Characters outside the BMP are given as a UTF-16 surrogate pair.
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.peek() == Some('\\')
+                                && self.chars.get(self.index + 1) == Some(&'u')
+                            {
+                                self.advance();
+                                self.advance();
+                                let low = self.parse_hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    bail!("{escape_position}: invalid unicode escape");
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            let c = char::from_u32(code).with_context(|| {
+                                format!("{escape_position}: invalid unicode escape")
+                            })?;
+                            value.push(c);
+                        }
+                        _ => bail!("{escape_position}: invalid escape"),
+                    }
+                }
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32> {
+        let start = self.position();
+        let mut digits = String::new();
+        for _ in 0..4 {
+            digits.extend(self.advance());
+        }
+        u32::from_str_radix(&digits, 16).with_context(|| format!("{start}: invalid unicode escape"))
+    }
+
+    fn parse_number(&mut self) -> Result<Value> {
+        let start = self.position();
+        let mut number = String::new();
+        while let Some(c) =
+            self.peek().filter(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            number.push(c);
+            self.advance();
+        }
+        serde_json::from_str(&number).with_context(|| format!("{start}: invalid number {number}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_with_comments() {
+        let document = parse(
+            r#"{
+  // Comment.
+  "tests": true, // Trailing comment.
+  /* Block
+     comment. */
+  "variants": [
+    { "features": ["aé", "😀"], },
+    { "min_sdk_version": null, "count": -1.5e2 },
+  ],
+}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            document.value,
+            json!({
+                "tests": true,
+                "variants": [
+                    { "features": ["aé", "😀"] },
+                    { "min_sdk_version": null, "count": -150.0 },
+                ],
+            })
+        );
+        assert_eq!(document.position("/tests"), Position { line: 3, column: 3 });
+        assert_eq!(document.position("/variants/0"), Position { line: 7, column: 5 });
+        assert_eq!(document.position("/variants/1/count"), Position { line: 8, column: 32 });
+        assert_eq!(document.position("/variants/1/missing"), Position { line: 8, column: 5 });
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            parse("{\n  \"a\": 1\n  \"b\": 2\n}").unwrap_err().to_string(),
+            "3:3: expected ',' or '}'"
+        );
+        assert_eq!(
+            parse("{\"a\": 1, \"a\": 2}").unwrap_err().to_string(),
+            "1:10: duplicate key \"a\""
+        );
+        assert_eq!(
+            parse("[1] x").unwrap_err().to_string(),
+            "1:5: unexpected content after the end of the value"
+        );
+        assert_eq!(parse("/* never closed").unwrap_err().to_string(), "1:1: unterminated comment");
+        assert_eq!(
+            parse(r#"["\ud800\u0000"]"#).unwrap_err().to_string(),
+            "1:4: invalid unicode escape"
+        );
+    }
+}
@@ -0,0 +1,340 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! JSON Schema for `cargo_embargo.json`, for editor completion and validation.
+//!
+//! This must be kept in sync with the config structs; the tests check that it has the same fields.
+
+use serde_json::{json, Map, Value};
+
+fn boolean(description: &str, default: bool) -> Value {
+    json!({ "type": "boolean", "default": default, "description": description })
+}
+
+fn string(description: &str) -> Value {
+    json!({ "type": "string", "description": description })
+}
+
+fn string_list(description: &str) -> Value {
+    json!({ "type": "array", "items": { "type": "string" }, "description": description })
+}
+
+fn string_map(description: &str, values: Value) -> Value {
+    json!({ "type": "object", "additionalProperties": values, "description": description })
+}
+
+fn object(properties: Map<String, Value>) -> Value {
+    json!({ "type": "object", "properties": properties, "additionalProperties": false })
+}
+
+/// Returns the schema for the options of [`super::VariantConfig`] other than `package`.
+fn variant_properties() -> Map<String, Value> {
+    let mut properties = Map::new();
+    let mut add = |name: &str, schema: Value| properties.insert(name.to_string(), schema);
+    add("tests", boolean("Whether to output `rust_test` modules.", false));
+    add(
+        "features",
+        string_list("Set of features to enable. If not set, uses the default crate features."),
+    );
+    add("workspace", boolean("Whether to build with `--workspace`.", false));
+    add(
+        "workspace_excludes",
+        string_list("When workspace is enabled, list of `--exclude` crates."),
+    );
+    add("global_defaults", string("Value to use for every generated module's `defaults` field."));
+    let mut apex_available =
+        string_list("Value to use for every generated library module's `apex_available` field.");
+    apex_available["default"] = json!(super::default_apex_available());
+    add("apex_available", apex_available);
+    for (name, default) in [
+        ("native_bridge_supported", false),
+        ("product_available", true),
+        ("ramdisk_available", false),
+        ("recovery_available", false),
+        ("vendor_available", true),
+        ("vendor_ramdisk_available", false),
+    ] {
+        add(
+            name,
+            boolean(
+                &format!("Value to use for every generated library module's `{name}` field."),
+                default,
+            ),
+        );
+    }
+    add("min_sdk_version", string("Minimum SDK version."));
+    add(
+        "module_name_overrides",
+        string_map("Map of renames for modules.", json!({ "type": "string" })),
+    );
+    add("cfg_blocklist", string_list("`cfg` flags in this list will not be included."));
+    add("extra_cfg", string_list("Extra `cfg` flags to enable in output modules."));
+    add("module_blocklist", string_list("Modules in this list will not be generated."));
+    add(
+        "module_visibility",
+        string_map(
+            "Modules name => Soong \"visibility\" property.",
+            json!({ "type": "array", "items": { "type": "string" } }),
+        ),
+    );
+    add(
+        "run_cargo",
+        boolean(
+            "Whether to run the cargo build and parse its output, rather than just figuring \
+             things out from the cargo metadata.",
+            true,
+        ),
+    );
+    add(
+        "generate_androidbp",
+        boolean("Generate an Android.bp build file for this variant if true.", true),
+    );
+    add(
+        "generate_rulesmk",
+        boolean("Generate a rules.mk build file for this variant if true.", false),
+    );
+    add(
+        "generate_bazel",
+        boolean("Generate a BUILD.bazel file using rules_rust for this variant if true.", false),
+    );
+    let mut bazel_dep_prefix = string(
+        "Prefix of Bazel labels for dependencies on crates which aren't in the same package, to \
+         which the crate's package name is appended.",
+    );
+    bazel_dep_prefix["default"] = json!(super::default_bazel_dep_prefix());
+    add("bazel_dep_prefix", bazel_dep_prefix);
+    add("generate_gn", boolean("Generate a BUILD.gn file for this variant if true.", false));
+    add(
+        "gn_label_overrides",
+        string_map(
+            "Map of GN labels to use for dependencies, keyed by crate name, instead of the default \
+             of `\"../<package name>:<crate name>\"`.",
+            json!({ "type": "string" }),
+        ),
+    );
+    let mut targets = string_list("Rust target triples to build for.");
+    targets["default"] = json!(super::default_targets());
+    add("targets", targets);
+    add(
+        "select",
+        json!({
+            "type": "object",
+            "description": "Soong configuration value for which this variant's modules are used.",
+            "properties": {
+                "release_flag": { "type": "string" },
+                "soong_config_variable": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "minItems": 2,
+                    "maxItems": 2,
+                },
+                "value": { "type": ["boolean", "string"] },
+            },
+            "oneOf": [
+                { "required": ["release_flag"] },
+                { "required": ["soong_config_variable"] },
+            ],
+            "additionalProperties": false,
+        }),
+    );
+    add(
+        "max_rustc_warnings",
+        json!({
+            "type": "integer",
+            "minimum": 0,
+            "description": "Maximum number of distinct rustc warnings allowed when building the \
+                            crates for this variant. If there are more, generation fails. If this \
+                            is set, modules with warnings are marked with a comment. Requires \
+                            `run_cargo`.",
+        }),
+    );
+    properties
+}
+
+/// Returns the schema for the options of [`super::PackageConfig`].
+fn package_properties() -> Map<String, Value> {
+    let mut properties = Map::new();
+    let mut add = |name: &str, schema: Value| properties.insert(name.to_string(), schema);
+    add(
+        "add_toplevel_block",
+        string("File with content to append to the end of the generated Android.bp."),
+    );
+    add("patch", string("Patch file to apply after Android.bp is generated."));
+    add("rulesmk_patch", string("Patch file to apply after rules.mk is generated."));
+    add(
+        "license_text",
+        string("`license_text` to use for the `license` module, overriding the package's."),
+    );
+    properties
+}
+
+/// Returns the schema for the options of [`super::PackageVariantConfig`].
+fn package_variant_properties() -> Map<String, Value> {
+    let mut properties = Map::new();
+    let mut add = |name: &str, schema: Value| properties.insert(name.to_string(), schema);
+    add("alloc", boolean("Link against `alloc`. Only valid if `no_std` is also true.", false));
+    add("device_supported", boolean("Whether to compile for device.", true));
+    add("host_supported", boolean("Whether to compile for host.", true));
+    add("host_cross_supported", boolean("Whether to compile for non-build host targets.", true));
+    add(
+        "host_first_multilib",
+        boolean("Add a `compile_multilib: \"first\"` property to host modules.", false),
+    );
+    add(
+        "force_rlib",
+        boolean("Generate \"rust_library_rlib\" instead of \"rust_library\".", false),
+    );
+    add(
+        "no_presubmit",
+        boolean("Whether to disable \"unit_test\" for \"rust_test\" modules.", false),
+    );
+    add(
+        "add_module_block",
+        string("File with content to append to the end of each generated module."),
+    );
+    add(
+        "dep_blocklist",
+        string_list("Modules in this list will not be added as dependencies of generated modules."),
+    );
+    add("no_std", boolean("Don't link against `std`, only `core`.", false));
+    add(
+        "copy_out",
+        boolean(
+            "Copy build.rs output to ./out/* and add a genrule to copy ./out/* to genrule output.",
+            false,
+        ),
+    );
+    add(
+        "test_data",
+        string_map(
+            "Add the given files to the given tests' `data` property. The key is the test source \
+             filename relative to the crate root.",
+            json!({ "type": "array", "items": { "type": "string" } }),
+        ),
+    );
+    add(
+        "whole_static_libs",
+        string_list("Static libraries in this list will instead be added as whole_static_libs."),
+    );
+    add("exported_c_header_dir", string_list("Directories with headers to export for C usage."));
+    add(
+        "benches",
+        boolean(
+            "Whether to output `rust_benchmark` modules for the package's `[[bench]]` targets.",
+            false,
+        ),
+    );
+    add(
+        "examples",
+        string_list("Names of `[[example]]` targets to output `rust_binary` modules for."),
+    );
+    properties
+}
+
+/// Returns a JSON Schema for `cargo_embargo.json`.
+pub fn json_schema() -> Value {
+    let mut package = package_properties();
+    package.extend(package_variant_properties());
+    let mut variant = variant_properties();
+    variant.insert(
+        "package".to_string(),
+        string_map(
+            "Package specific config options for this variant.",
+            json!({ "$ref": "#/$defs/PackageVariantConfig" }),
+        ),
+    );
+    let mut top_level = variant_properties();
+    top_level.insert(
+        "package".to_string(),
+        string_map(
+            "Package specific config options across all variants.",
+            json!({ "$ref": "#/$defs/PackageConfig" }),
+        ),
+    );
+    top_level.insert(
+        "extends".to_string(),
+        string_list(
+            "Configs to inherit top-level options from, relative to this config or, if they start \
+             with `//`, to the root of the Android tree.",
+        ),
+    );
+    top_level.insert(
+        "variants".to_string(),
+        json!({
+            "type": "array",
+            "items": { "$ref": "#/$defs/VariantConfig" },
+            "description": "Variants to generate, each overriding the top-level options.",
+        }),
+    );
+
+    let mut schema = object(top_level);
+    schema["$schema"] = json!("https://json-schema.org/draft/2020-12/schema");
+    schema["title"] = json!("cargo_embargo.json");
+    schema["$defs"] = json!({
+        "VariantConfig": object(variant),
+        "PackageConfig": object(package),
+        "PackageVariantConfig": object(package_variant_properties()),
+    });
+    schema
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        default_variant_option, PackageConfig, PackageVariantConfig, VariantConfig,
+    };
+    use serde::de::DeserializeOwned;
+    use std::collections::BTreeSet;
+
+    /// Returns the names of the fields of `T`, from the error for an unknown field.
+    fn field_names<T: DeserializeOwned>() -> BTreeSet<String> {
+        let error = serde_json::from_value::<T>(json!({ "unknown": 0 })).err().unwrap().to_string();
+        let (_, expected) = error.split_once("expected one of ").unwrap();
+        expected.split(", ").map(|name| name.trim_matches('`').to_string()).collect()
+    }
+
+    fn property_names(properties: &Value) -> BTreeSet<String> {
+        properties.as_object().unwrap().keys().cloned().collect()
+    }
+
+    #[test]
+    fn schema_matches_structs() {
+        let schema = json_schema();
+        let defs = &schema["$defs"];
+        assert_eq!(
+            property_names(&defs["VariantConfig"]["properties"]),
+            field_names::<VariantConfig>()
+        );
+        assert_eq!(
+            property_names(&defs["PackageConfig"]["properties"]),
+            &field_names::<PackageConfig>() | &field_names::<PackageVariantConfig>()
+        );
+        assert_eq!(
+            property_names(&defs["PackageVariantConfig"]["properties"]),
+            field_names::<PackageVariantConfig>()
+        );
+        // Every variant option must have a default, which matches the schema if it gives one.
+        for (key, property) in defs["VariantConfig"]["properties"].as_object().unwrap() {
+            let default = default_variant_option(key);
+            if let Some(schema_default) = property.get("default") {
+                assert_eq!(schema_default, &default, "default of {key}");
+            }
+        }
+        let mut top_level = field_names::<VariantConfig>();
+        top_level.insert("extends".to_string());
+        top_level.insert("variants".to_string());
+        assert_eq!(property_names(&schema["properties"]), top_level);
+    }
+}
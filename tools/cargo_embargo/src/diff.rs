@@ -0,0 +1,211 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Line-based unified diffs, for showing how generated build files differ from existing ones.
+
+use std::fmt::Write;
+
+/// Number of unchanged lines to show around each change.
+const CONTEXT_LINES: usize = 3;
+
+/// A single line of a diff.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Line<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+impl Line<'_> {
+    fn is_change(&self) -> bool {
+        !matches!(self, Line::Equal(_))
+    }
+}
+
+/// Returns a minimal sequence of line edits to turn `old` into `new`, found via the longest common
+/// subsequence.
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<Line<'a>> {
+    // Strip the common prefix and suffix, as they are usually most of the file.
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    // lcs[i][j] is the length of the longest common subsequence of old_middle[i..] and
+    // new_middle[j..].
+    let mut lcs = vec![vec![0usize; new_middle.len() + 1]; old_middle.len() + 1];
+    for i in (0..old_middle.len()).rev() {
+        for j in (0..new_middle.len()).rev() {
+            lcs[i][j] = if old_middle[i] == new_middle[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines: Vec<Line> = old[..prefix].iter().map(|line| Line::Equal(line)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < old_middle.len() || j < new_middle.len() {
+        if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+            lines.push(Line::Equal(old_middle[i]));
+            i += 1;
+            j += 1;
+        } else if j == new_middle.len() || (i < old_middle.len() && lcs[i + 1][j] >= lcs[i][j + 1])
+        {
+            lines.push(Line::Delete(old_middle[i]));
+            i += 1;
+        } else {
+            lines.push(Line::Insert(new_middle[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[old.len() - suffix..].iter().map(|line| Line::Equal(line)));
+    lines
+}
+
+/// Writes a single line of a diff with the given prefix, noting if it is missing a final newline.
+fn write_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+/// Returns a unified diff from `old` to `new`, with the given file names in the header, or an empty
+/// string if they are the same.
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+    let lines = diff_lines(&old_lines, &new_lines);
+
+    let mut out = String::new();
+    let mut hunk_start = 0;
+    while let Some(first_change) = lines[hunk_start..].iter().position(Line::is_change) {
+        if out.is_empty() {
+            writeln!(out, "--- {old_name}\n+++ {new_name}").unwrap();
+        }
+        let first_change = hunk_start + first_change;
+        // Extend the hunk until there is a long enough run of unchanged lines.
+        let mut end = first_change;
+        while let Some(next_change) =
+            lines[end + 1..].iter().take(2 * CONTEXT_LINES + 1).position(Line::is_change)
+        {
+            end += next_change + 1;
+        }
+        let start = first_change.saturating_sub(CONTEXT_LINES).max(hunk_start);
+        let end = (end + 1 + CONTEXT_LINES).min(lines.len());
+
+        // Line numbers of the start of the hunk.
+        let old_start = lines[..start].iter().filter(|l| !matches!(l, Line::Insert(_))).count();
+        let new_start = lines[..start].iter().filter(|l| !matches!(l, Line::Delete(_))).count();
+        let hunk = &lines[start..end];
+        let old_count = hunk.iter().filter(|l| !matches!(l, Line::Insert(_))).count();
+        let new_count = hunk.iter().filter(|l| !matches!(l, Line::Delete(_))).count();
+        // An empty range is identified by the line before it.
+        let range_start = |start: usize, count: usize| if count == 0 { start } else { start + 1 };
+        writeln!(
+            out,
+            "@@ -{},{old_count} +{},{new_count} @@",
+            range_start(old_start, old_count),
+            range_start(new_start, new_count)
+        )
+        .unwrap();
+        for line in hunk {
+            match line {
+                Line::Equal(line) => write_line(&mut out, ' ', line),
+                Line::Delete(line) => write_line(&mut out, '-', line),
+                Line::Insert(line) => write_line(&mut out, '+', line),
+            }
+        }
+        hunk_start = end;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", "old", "new"), "");
+    }
+
+    #[test]
+    fn separate_hunks() {
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\n12\n";
+        assert_eq!(
+            unified_diff(old, new, "a/Android.bp", "b/Android.bp"),
+            "--- a/Android.bp
++++ b/Android.bp
+@@ -1,6 +1,6 @@
+ 1
+ 2
+-3
++three
+ 4
+ 5
+ 6
+@@ -8,5 +8,4 @@
+ 8
+ 9
+ 10
+-11
+ 12
+"
+        );
+    }
+
+    #[test]
+    fn merged_hunk() {
+        let old = "1\n2\n3\n4\n5\n6\n";
+        let new = "0\n1\n2\n3\n4\n6\n";
+        assert_eq!(
+            unified_diff(old, new, "old", "new"),
+            "--- old
++++ new
+@@ -1,6 +1,6 @@
++0
+ 1
+ 2
+ 3
+ 4
+-5
+ 6
+"
+        );
+    }
+
+    #[test]
+    fn new_file_without_newline() {
+        assert_eq!(
+            unified_diff("", "a\nb", "old", "new"),
+            "--- old
++++ new
+@@ -0,0 +1,2 @@
++a
++b
+\\ No newline at end of file
+"
+        );
+    }
+}
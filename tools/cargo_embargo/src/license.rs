@@ -0,0 +1,212 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Choosing licenses from the SPDX license expressions of packages, and finding their license
+//! files.
+
+use anyhow::{anyhow, Context, Result};
+use spdx::{Expression, LicenseItem, LicenseReq, Licensee, ParseMode};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+/// Licenses in order of preference, when a package offers a choice. This is the same order as
+/// `license_checker` uses.
+static LICENSE_PREFERENCE: LazyLock<Vec<Licensee>> = LazyLock::new(|| {
+    [
+        "Apache-2.0",
+        "MIT",
+        "BSD-3-Clause",
+        "BSD-2-Clause",
+        "ISC",
+        "MPL-2.0",
+        "0BSD",
+        "Unlicense",
+        "Zlib",
+        "Unicode-DFS-2016",
+        "NCSA",
+        "OpenSSL",
+    ]
+    .into_iter()
+    .map(|license| Licensee::parse(license).unwrap())
+    .collect()
+});
+
+/// Patterns for the names of license files in a package directory.
+const LICENSE_FILE_GLOBS: &[&str] =
+    &["LICENSE", "LICENCE", "LICENSE.*", "LICENSE-*", "LICENSES/*", "UNLICENSE", "COPYING"];
+
+/// License file names which are specific to one license, in upper case and without any `.txt` or
+/// `.md` extension. A name may appear more than once if it is used for several versions of a
+/// license.
+const LICENSE_FILE_NAMES: &[(&str, &str)] = &[
+    ("LICENSE-MIT", "MIT"),
+    ("LICENSES/MIT", "MIT"),
+    ("LICENSE-APACHE", "Apache-2.0"),
+    ("LICENSE-APACHE-2.0", "Apache-2.0"),
+    ("LICENSES/APACHE-2.0", "Apache-2.0"),
+    ("LICENSE-BSD-3-CLAUSE", "BSD-3-Clause"),
+    ("LICENSE-UNICODE", "Unicode-DFS-2016"),
+    ("LICENSE-UNICODE", "Unicode-3.0"),
+    ("LICENSE-0BSD", "0BSD"),
+    ("LICENSE-ZLIB", "Zlib"),
+    ("UNLICENSE", "Unlicense"),
+];
+
+/// Parses the given SPDX license expression from a `Cargo.toml`, and returns the licenses which
+/// must be complied with. Where the expression offers a choice, licenses are chosen in order of
+/// `LICENSE_PREFERENCE`, falling back to those which appear first in the expression.
+///
+/// As in older `Cargo.toml` files, `/` is treated as `OR`.
+pub fn choose_licenses(license: &str) -> Result<Vec<LicenseReq>> {
+    let expression = Expression::parse_mode(license, ParseMode::LAX)
+        .with_context(|| format!("failed to parse license expression {license:?}"))?;
+    // A licensee for each license in the expression, which is used if none of the preferred
+    // licenses are allowed. Licensees can't be "or later", but do satisfy "or later" requirements.
+    let fallback: Vec<Licensee> = expression
+        .requirements()
+        .map(|requirement| {
+            let license = match &requirement.req.license {
+                LicenseItem::Spdx { id, .. } => LicenseItem::Spdx { id: *id, or_later: false },
+                other => other.clone(),
+            };
+            Licensee::new(license, requirement.req.exception)
+        })
+        .collect();
+    expression
+        .minimized_requirements(LICENSE_PREFERENCE.iter().chain(&fallback))
+        .with_context(|| format!("failed to choose licenses from {license:?}"))
+}
+
+/// Returns the Soong `license_kinds` for the given license, including one for its exception if it
+/// has one.
+pub fn license_kinds(license: &LicenseReq) -> Vec<String> {
+    let mut kinds = vec![match &license.license {
+        LicenseItem::Spdx { id, .. } => format!("SPDX-license-identifier-{}", id.name),
+        LicenseItem::Other { lic_ref, .. } => format!("SPDX-license-identifier-{lic_ref}"),
+    }];
+    if let Some(exception) = license.exception {
+        kinds.push(format!("SPDX-license-identifier-{}", exception.name));
+    }
+    kinds
+}
+
+/// Returns the licenses which the license file at the given path relative to the package directory
+/// may be for, or an empty list if its name isn't specific to one license.
+fn classify_license_file(path: &Path) -> Vec<LicenseReq> {
+    let path = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("txt" | "md") => path.with_extension(""),
+        _ => path.to_owned(),
+    };
+    let Some(name) = path.to_str().map(str::to_ascii_uppercase) else {
+        return Vec::new();
+    };
+    LICENSE_FILE_NAMES
+        .iter()
+        .filter(|(file_name, _)| *file_name == name)
+        .map(|(_, license)| Licensee::parse(license).unwrap().into_req())
+        .collect()
+}
+
+/// Finds the license files in the given package directory, and returns their paths relative to it
+/// along with the licenses which each may be for, if they can be told from its name.
+pub fn find_license_files(package_dir: &Path) -> Result<Vec<(PathBuf, Vec<LicenseReq>)>> {
+    let mut files = Vec::new();
+    for pattern in LICENSE_FILE_GLOBS {
+        let pattern = package_dir.join(pattern);
+        let pattern = pattern
+            .to_str()
+            .ok_or_else(|| anyhow!("failed to convert path {pattern:?} to string"))?;
+        for path in glob::glob(pattern)? {
+            let path = path?;
+            let relative = path.strip_prefix(package_dir)?.to_owned();
+            if !path.is_symlink() && !files.iter().any(|(file, _)| *file == relative) {
+                let licenses = classify_license_file(&relative);
+                files.push((relative, licenses));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chosen_kinds(license: &str) -> Vec<String> {
+        choose_licenses(license).unwrap().iter().flat_map(license_kinds).collect()
+    }
+
+    #[test]
+    fn choose_licenses_by_preference() {
+        assert_eq!(chosen_kinds("MIT"), vec!["SPDX-license-identifier-MIT"]);
+        assert_eq!(chosen_kinds("MIT OR Apache-2.0"), vec!["SPDX-license-identifier-Apache-2.0"]);
+        assert_eq!(chosen_kinds("MIT/Apache-2.0"), vec!["SPDX-license-identifier-Apache-2.0"]);
+        assert_eq!(chosen_kinds("Unlicense OR MIT"), vec!["SPDX-license-identifier-MIT"]);
+        assert_eq!(
+            chosen_kinds("Apache-2.0 or BSD-3-Clause"),
+            vec!["SPDX-license-identifier-Apache-2.0"]
+        );
+        assert_eq!(
+            chosen_kinds("Apache-2.0 WITH LLVM-exception OR Apache-2.0 OR MIT"),
+            vec!["SPDX-license-identifier-Apache-2.0"]
+        );
+        assert_eq!(chosen_kinds("MIT OR LGPL-3.0-or-later"), vec!["SPDX-license-identifier-MIT"]);
+        assert_eq!(chosen_kinds("BSL-1.0"), vec!["SPDX-license-identifier-BSL-1.0"]);
+        assert!(choose_licenses("not a license").is_err());
+    }
+
+    #[test]
+    fn choose_licenses_and_with() {
+        assert_eq!(
+            chosen_kinds("(MIT OR Apache-2.0) AND Unicode-DFS-2016"),
+            vec!["SPDX-license-identifier-Apache-2.0", "SPDX-license-identifier-Unicode-DFS-2016"]
+        );
+        assert_eq!(
+            chosen_kinds("Apache-2.0 WITH LLVM-exception"),
+            vec!["SPDX-license-identifier-Apache-2.0", "SPDX-license-identifier-LLVM-exception"]
+        );
+    }
+
+    #[test]
+    fn find_and_classify_license_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("LICENSES"))?;
+        for file in [
+            "LICENSE-APACHE",
+            "LICENSE-mit.md",
+            "LICENSES/MIT",
+            "LICENSE-UNICODE",
+            "COPYING",
+            "README.md",
+        ] {
+            std::fs::write(dir.path().join(file), "")?;
+        }
+        let mit = Licensee::parse("MIT").unwrap().into_req();
+        let apache = Licensee::parse("Apache-2.0").unwrap().into_req();
+        let unicode_2016 = Licensee::parse("Unicode-DFS-2016").unwrap().into_req();
+        let unicode_3 = Licensee::parse("Unicode-3.0").unwrap().into_req();
+        assert_eq!(
+            find_license_files(dir.path())?,
+            vec![
+                (PathBuf::from("COPYING"), vec![]),
+                (PathBuf::from("LICENSE-APACHE"), vec![apache]),
+                (PathBuf::from("LICENSE-UNICODE"), vec![unicode_2016, unicode_3]),
+                (PathBuf::from("LICENSE-mit.md"), vec![mit.clone()]),
+                (PathBuf::from("LICENSES/MIT"), vec![mit]),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -26,9 +26,14 @@
 //! The last step often involves messy, project specific business logic, so many options are
 //! available to tweak it via a config file.
 
+mod backend;
 mod bp;
+mod cache;
 mod cargo;
 mod config;
+mod diff;
+mod license;
+mod patch;
 
 use crate::config::Config;
 use crate::config::PackageConfig;
@@ -38,102 +43,64 @@ use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
-use bp::*;
+use backend::android_bp::AndroidBpBackend;
+use backend::bazel::BazelBackend;
+use backend::gn::GnBackend;
+use backend::rules_mk::RulesMkBackend;
+use backend::{Backend, Package, Variant};
 use cargo::{
-    cargo_out::parse_cargo_out, metadata::parse_cargo_metadata_str, Crate, CrateType, ExternType,
+    build_script::{find_build_script_directives, BuildScriptDirectives, BUILD_SCRIPT_OUTPUT_FILE},
+    cargo_out::{build_script_logs, parse_cargo_out},
+    metadata::{parse_cargo_metadata_str, selected_examples},
+    Crate,
 };
 use clap::Parser;
 use clap::Subcommand;
 use log::debug;
 use nix::fcntl::OFlag;
 use nix::unistd::pipe2;
-use std::collections::BTreeMap;
 use std::collections::VecDeque;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
-use std::fs::{read_to_string, write, File};
-use std::io::{Read, Write};
+use std::fs::{create_dir_all, read_to_string, remove_file, write, File};
+use std::io::Read;
+use std::panic;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
-use std::sync::LazyLock;
+use std::thread;
 use tempfile::tempdir;
 
 // Major TODOs
 //  * handle errors, esp. in cargo.out parsing. they should fail the program with an error code
 //  * handle warnings. put them in comments in the android.bp, some kind of report section
 
-/// Rust modules which shouldn't use the default generated names, to avoid conflicts or confusion.
-pub static RENAME_MAP: LazyLock<BTreeMap<&str, &str>> = LazyLock::new(|| {
-    [
-        ("libash", "libash_rust"),
-        ("libatomic", "libatomic_rust"),
-        ("libbacktrace", "libbacktrace_rust"),
-        ("libbase", "libbase_rust"),
-        ("libbase64", "libbase64_rust"),
-        ("libfuse", "libfuse_rust"),
-        ("libgcc", "libgcc_rust"),
-        ("liblog", "liblog_rust"),
-        ("libminijail", "libminijail_rust"),
-        ("libsync", "libsync_rust"),
-        ("libx86_64", "libx86_64_rust"),
-        ("libxml", "libxml_rust"),
-        ("protoc_gen_rust", "protoc-gen-rust"),
-    ]
-    .into_iter()
-    .collect()
-});
-
-/// This map tracks Rust crates that have special rules.mk modules that were not
-/// generated automatically by this script. Examples include compiler builtins
-/// and other foundational libraries. It also tracks the location of rules.mk
-/// build files for crates that are not under external/rust/crates.
-pub static RULESMK_RENAME_MAP: LazyLock<BTreeMap<&str, &str>> = LazyLock::new(|| {
-    [
-        ("liballoc", "trusty/user/base/lib/liballoc-rust"),
-        ("libcompiler_builtins", "trusty/user/base/lib/libcompiler_builtins-rust"),
-        ("libcore", "trusty/user/base/lib/libcore-rust"),
-        ("libhashbrown", "trusty/user/base/lib/libhashbrown-rust"),
-        ("libpanic_abort", "trusty/user/base/lib/libpanic_abort-rust"),
-        ("libstd", "trusty/user/base/lib/libstd-rust"),
-        ("libstd_detect", "trusty/user/base/lib/libstd_detect-rust"),
-        ("libunwind", "trusty/user/base/lib/libunwind-rust"),
-    ]
-    .into_iter()
-    .collect()
-});
-
-/// Given a proposed module name, returns `None` if it is blocked by the given config, or
-/// else apply any name overrides and returns the name to use.
-fn override_module_name(
-    module_name: &str,
-    blocklist: &[String],
-    module_name_overrides: &BTreeMap<String, String>,
-    rename_map: &BTreeMap<&str, &str>,
-) -> Option<String> {
-    if blocklist.iter().any(|blocked_name| blocked_name == module_name) {
-        None
-    } else if let Some(overridden_name) = module_name_overrides.get(module_name) {
-        Some(overridden_name.to_string())
-    } else if let Some(renamed) = rename_map.get(module_name) {
-        Some(renamed.to_string())
-    } else {
-        Some(module_name.to_string())
-    }
-}
-
 /// Command-line parameters for `cargo_embargo`.
 #[derive(Parser, Debug)]
 struct Args {
     /// Use the cargo binary in the `cargo_bin` directory. Defaults to using the Android prebuilt.
     #[clap(long)]
     cargo_bin: Option<PathBuf>,
-    /// Store `cargo build` output in this directory. If not set, a temporary directory is created and used.
+    /// Store `cargo build` output in this directory, in a `variant_<n>` subdirectory for each
+    /// variant. If not set, a temporary directory is created and used.
     #[clap(long)]
     cargo_out_dir: Option<PathBuf>,
     /// Skip the `cargo build` commands and reuse the "cargo.out" file from a previous run if
     /// available. Requires setting --cargo_out_dir.
     #[clap(long)]
     reuse_cargo_out: bool,
+    /// Always run cargo, rather than reusing its output from a previous run with the same sources,
+    /// config and toolchain.
+    #[clap(long)]
+    no_cache: bool,
+    /// Record the output of each package's build script to a `cargo_embargo_build_script.json` file
+    /// in the package, to be used when `run_cargo` is false.
+    #[clap(long)]
+    record_build_scripts: bool,
+    /// If a patch fails to apply, write the hunks which failed next to the build file, e.g. to
+    /// `Android.bp.rej`.
+    #[clap(long)]
+    write_rejects: bool,
     #[command(subcommand)]
     mode: Mode,
 }
@@ -146,6 +113,13 @@ enum Mode {
         /// `cargo_embargo.json` config file to use.
         config: PathBuf,
     },
+    /// Checks whether the existing `Android.bp` and `rules.mk` files for the crates under the current
+    /// directory are what would be generated using the given config file. Prints a diff and fails
+    /// if not.
+    Check {
+        /// `cargo_embargo.json` config file to use.
+        config: PathBuf,
+    },
     /// Dumps information about the crates to the given JSON file.
     DumpCrates {
         /// `cargo_embargo.json` config file to use.
@@ -159,6 +133,8 @@ enum Mode {
         /// `cargo_embargo.json` config file to create.
         config: PathBuf,
     },
+    /// Prints a JSON Schema for `cargo_embargo.json` config files, for use by editors.
+    Schema,
 }
 
 fn main() -> Result<()> {
@@ -176,11 +152,17 @@ fn main() -> Result<()> {
             dump_crates(&args, config, crates, intermediates_dir)?;
         }
         Mode::Generate { config } => {
-            run_embargo(&args, config, intermediates_dir)?;
+            run_embargo(&args, config, intermediates_dir, false)?;
+        }
+        Mode::Check { config } => {
+            run_embargo(&args, config, intermediates_dir, true)?;
         }
         Mode::Autoconfig { config } => {
             autoconfig(&args, config, intermediates_dir)?;
         }
+        Mode::Schema => {
+            println!("{}", serde_json::to_string_pretty(&config::schema::json_schema())?);
+        }
     }
 
     Ok(())
@@ -210,31 +192,48 @@ fn autoconfig(args: &Args, config_filename: &Path, intermediates_dir: &Path) ->
     println!("Trying default config with tests...");
     let mut config_with_build = Config {
         variants: vec![VariantConfig { tests: true, ..Default::default() }],
-        package: Default::default(),
+        ..Default::default()
     };
-    let mut crates_with_build = make_all_crates(args, &config_with_build, intermediates_dir)?;
+    let mut cargo_outputs = make_all_cargo_outputs(args, &config_with_build, intermediates_dir)?;
+    let mut crates_with_build = parse_all_crates(&config_with_build, &cargo_outputs)?;
 
     let has_tests =
-        crates_with_build[0].iter().any(|c| c.types.contains(&CrateType::Test) && !c.empty_test);
+        crates_with_build[0].iter().any(|c| c.types.iter().any(|t| t.is_test()) && !c.empty_test);
     if !has_tests {
         println!("No tests, removing from config.");
-        config_with_build =
-            Config { variants: vec![Default::default()], package: Default::default() };
-        crates_with_build = make_all_crates(args, &config_with_build, intermediates_dir)?;
+        config_with_build = Config { variants: vec![Default::default()], ..Default::default() };
+        cargo_outputs = make_all_cargo_outputs(args, &config_with_build, intermediates_dir)?;
+        crates_with_build = parse_all_crates(&config_with_build, &cargo_outputs)?;
     }
 
     println!("Trying without cargo build...");
+    // Record the output of build scripts so that they don't need to be run.
+    let recorded =
+        record_build_script_directives(&config_with_build, &crates_with_build, intermediates_dir)?;
     let config_no_build = Config {
         variants: vec![VariantConfig { run_cargo: false, tests: has_tests, ..Default::default() }],
-        package: Default::default(),
+        ..Default::default()
     };
-    let crates_without_build = make_all_crates(args, &config_no_build, intermediates_dir)?;
+    let crates_without_build = parse_all_crates(&config_no_build, &cargo_outputs)?;
 
-    let config = if crates_with_build == crates_without_build {
+    let config = if same_crates_ignoring_warnings(&crates_with_build, &crates_without_build) {
         println!("Output without build was the same, using that.");
+        for (path, _) in recorded.iter().filter(|(path, _)| path.exists()) {
+            println!(
+                "Recorded build script output to {path:?}, which must be kept with the config."
+            );
+        }
         config_no_build
     } else {
         println!("Output without build was different. Need to run cargo build.");
+        // Put back the previous recorded build script outputs, if any.
+        for (path, previous) in recorded {
+            if let Some(previous) = previous {
+                write(&path, previous)?;
+            } else {
+                remove_file(&path)?;
+            }
+        }
         println!("With build: {}", serde_json::to_string_pretty(&crates_with_build)?);
         println!("Without build: {}", serde_json::to_string_pretty(&crates_without_build)?);
         config_with_build
@@ -280,17 +279,43 @@ fn add_to_path(extra_path: PathBuf) -> Result<()> {
     Ok(())
 }
 
-/// Calls make_crates for each variant in the given config.
+/// Returns the directory for the cargo output and target directory of the given variant.
+fn variant_intermediates_dir(intermediates_dir: &Path, variant_index: usize) -> PathBuf {
+    intermediates_dir.join(format!("variant_{variant_index}"))
+}
+
+/// Returns the name of the directory in a package to which build script outputs for the given
+/// variant are copied if `copy_out` is enabled. The first variant uses `out`, so that packages with
+/// a single variant are unaffected.
+fn out_dir_name(variant_index: usize) -> String {
+    if variant_index == 0 {
+        "out".to_string()
+    } else {
+        format!("out_{variant_index}")
+    }
+}
+
+/// Runs cargo for each variant in the given config and parses its output into crates.
 fn make_all_crates(args: &Args, cfg: &Config, intermediates_dir: &Path) -> Result<Vec<Vec<Crate>>> {
-    cfg.variants.iter().map(|variant| make_crates(args, variant, intermediates_dir)).collect()
+    let cargo_outputs = make_all_cargo_outputs(args, cfg, intermediates_dir)?;
+    parse_all_crates(cfg, &cargo_outputs)
 }
 
-fn make_crates(args: &Args, cfg: &VariantConfig, intermediates_dir: &Path) -> Result<Vec<Crate>> {
+/// Runs cargo for each variant in the given config, returning the output for each variant.
+///
+/// The variants are run concurrently, each with its own intermediates directory and so its own
+/// target directory.
+fn make_all_cargo_outputs(
+    args: &Args,
+    cfg: &Config,
+    intermediates_dir: &Path,
+) -> Result<Vec<CargoOutput>> {
     if !Path::new("Cargo.toml").try_exists().context("when checking Cargo.toml")? {
         bail!("Cargo.toml missing. Run in a directory with a Cargo.toml file.");
     }
 
-    // Add the custom cargo to PATH.
+    // Add the custom cargo to PATH. This must be done before starting any threads, as it modifies
+    // the environment.
     // NOTE: If the directory with cargo has more binaries, this could have some unpredictable side
     // effects. That is partly intended though, because we want to use that cargo binary's
     // associated rustc.
@@ -302,49 +327,193 @@ fn make_crates(args: &Args, cfg: &VariantConfig, intermediates_dir: &Path) -> Re
     };
     add_to_path(cargo_bin)?;
 
+    // Build script outputs can only be recorded from the target directory if cargo is really run.
+    let use_cache = !args.no_cache
+        && !args.record_build_scripts
+        && !matches!(args.mode, Mode::Autoconfig { .. });
+    let cache = if use_cache { cargo_cache(intermediates_dir) } else { None };
+    thread::scope(|scope| {
+        let handles: Vec<_> = cfg
+            .variants
+            .iter()
+            .enumerate()
+            .map(|(variant_index, variant)| {
+                let variant_dir = variant_intermediates_dir(intermediates_dir, variant_index);
+                let cache = cache.as_ref().and_then(
+                    |(cache_dir, source_tree_hash, version, environment)| {
+                        let key =
+                            cache::cache_key(variant, *source_tree_hash, version, environment)?;
+                        Some((cache_dir.as_path(), key))
+                    },
+                );
+                scope.spawn(move || make_cargo_output(args, variant, &variant_dir, cache))
+            })
+            .collect();
+        // Join the threads in order, so that the results are in the same order as the variants.
+        handles
+            .into_iter()
+            .enumerate()
+            .map(|(variant_index, handle)| {
+                handle
+                    .join()
+                    .unwrap_or_else(|e| panic::resume_unwind(e))
+                    .with_context(|| format!("failed to run cargo for variant {variant_index}"))
+            })
+            .collect()
+    })
+}
+
+/// Returns the cache directory, hash of the source tree in the current directory, toolchain version
+/// and environment with which to cache cargo output, or `None` if they can't be determined.
+fn cargo_cache(intermediates_dir: &Path) -> Option<(PathBuf, u64, String, String)> {
+    let cache_dir = cache::cache_dir()?;
+    let result = || -> Result<_> {
+        let current_dir = env::current_dir()?;
+        // Changes to path dependencies outside the current directory wouldn't be noticed.
+        if let Some(path) = cache::external_path_dependencies(&current_dir)?.first() {
+            bail!("path dependency {path:?} is outside {current_dir:?}");
+        }
+        let source_tree_hash =
+            cache::hash_source_tree(&current_dir, &current_dir.join(intermediates_dir))?;
+        Ok((cache_dir, source_tree_hash, cache::toolchain_version()?, cache::cargo_environment()))
+    }();
+    match result {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            eprintln!("WARNING: not caching cargo output: {e:#}");
+            None
+        }
+    }
+}
+
+/// Runs cargo for the given variant, or reads its previous output if `--reuse-cargo-out` was
+/// passed or there is an entry for it in the cache.
+///
+/// `cache` is the cache directory and the key for the variant, if its output should be cached.
+fn make_cargo_output(
+    args: &Args,
+    cfg: &VariantConfig,
+    intermediates_dir: &Path,
+    cache: Option<(&Path, String)>,
+) -> Result<CargoOutput> {
+    create_dir_all(intermediates_dir)
+        .with_context(|| format!("failed to create {intermediates_dir:?}"))?;
+
     let cargo_out_path = intermediates_dir.join("cargo.out");
     let cargo_metadata_path = intermediates_dir.join("cargo.metadata");
-    let cargo_output = if args.reuse_cargo_out && cargo_out_path.exists() {
-        CargoOutput {
+    if args.reuse_cargo_out && cargo_out_path.exists() {
+        Ok(CargoOutput {
             cargo_out: read_to_string(cargo_out_path)?,
             cargo_metadata: read_to_string(cargo_metadata_path)?,
-        }
+        })
     } else {
-        let cargo_output =
-            generate_cargo_out(cfg, intermediates_dir).context("generate_cargo_out failed")?;
+        let cached = cache.as_ref().and_then(|(cache_dir, key)| {
+            let cargo_output = cache::read_cached(cache_dir, key)?;
+            debug!("Reusing cached cargo output from {:?}", cache_dir.join(key));
+            Some(cargo_output)
+        });
+        let cargo_output = if let Some(cargo_output) = cached {
+            cargo_output
+        } else {
+            let cargo_output =
+                generate_cargo_out(cfg, intermediates_dir).context("generate_cargo_out failed")?;
+            if let Some((cache_dir, key)) = &cache {
+                if let Err(e) = cache::write_cached(cache_dir, key, &cargo_output) {
+                    eprintln!("WARNING: failed to cache cargo output: {e:#}");
+                }
+            }
+            cargo_output
+        };
         if cfg.run_cargo {
             write(cargo_out_path, &cargo_output.cargo_out)?;
         }
         write(cargo_metadata_path, &cargo_output.cargo_metadata)?;
-        cargo_output
-    };
+        Ok(cargo_output)
+    }
+}
 
-    if cfg.run_cargo {
-        parse_cargo_out(&cargo_output).context("parse_cargo_out failed")
-    } else {
-        parse_cargo_metadata_str(&cargo_output.cargo_metadata, cfg)
+/// Parses the crates of each variant in the given config from the corresponding cargo output.
+///
+/// Variants with `run_cargo` disabled only use the `cargo metadata` output, so the output of a run
+/// with it enabled can be reused to check what they would generate.
+fn parse_all_crates(cfg: &Config, cargo_outputs: &[CargoOutput]) -> Result<Vec<Vec<Crate>>> {
+    assert_eq!(cfg.variants.len(), cargo_outputs.len());
+    cfg.variants
+        .iter()
+        .zip(cargo_outputs)
+        .map(|(variant, cargo_output)| {
+            if variant.run_cargo {
+                parse_cargo_out(cargo_output, &variant.extra_cfg).context("parse_cargo_out failed")
+            } else {
+                parse_cargo_metadata_str(&cargo_output.cargo_metadata, variant)
+            }
+        })
+        .collect()
+}
+
+/// Returns whether the crates for each variant are the same, other than their rustc warnings.
+fn same_crates_ignoring_warnings(a: &[Vec<Crate>], b: &[Vec<Crate>]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignoring_warnings(b))
+        })
+}
+
+/// Fails if the crates for any variant have more rustc warnings than its `max_rustc_warnings`.
+fn check_rustc_warnings(cfg: &Config, crates: &[Vec<Crate>]) -> Result<()> {
+    for (variant_index, (variant, crates)) in cfg.variants.iter().zip(crates).enumerate() {
+        let Some(max_rustc_warnings) = variant.max_rustc_warnings else {
+            continue;
+        };
+        // The same warning may be reported for several crates, e.g. a library and its unit tests.
+        let warnings: BTreeSet<(&str, &str)> = crates
+            .iter()
+            .flat_map(|c| c.warnings.iter().map(|w| (c.package_name.as_str(), w.as_str())))
+            .collect();
+        if warnings.len() > max_rustc_warnings {
+            for (package_name, warning) in &warnings {
+                eprintln!("{package_name}: warning: {warning}");
+            }
+            bail!(
+                "variant {variant_index} has {} rustc warnings, more than max_rustc_warnings ({})",
+                warnings.len(),
+                max_rustc_warnings
+            );
+        }
     }
+    Ok(())
 }
 
 /// Runs cargo_embargo with the given JSON configuration file.
-fn run_embargo(args: &Args, config_filename: &Path, intermediates_dir: &Path) -> Result<()> {
-    let intermediates_glob = intermediates_dir
-        .to_str()
-        .ok_or(anyhow!("Failed to convert intermediate dir path to string"))?
-        .to_string()
-        + "target.tmp/**/build/*/out/*";
-
+///
+/// If `check` is true, rather than writing the build files, checks that the existing ones are up to
+/// date.
+fn run_embargo(
+    args: &Args,
+    config_filename: &Path,
+    intermediates_dir: &Path,
+    check: bool,
+) -> Result<()> {
     let cfg = Config::from_file(config_filename)?;
-    let crates = make_all_crates(args, &cfg, intermediates_dir)?;
+    let cargo_outputs = make_all_cargo_outputs(args, &cfg, intermediates_dir)?;
+    let crates = parse_all_crates(&cfg, &cargo_outputs)?;
+    check_rustc_warnings(&cfg, &crates)?;
+    if args.record_build_scripts && !check {
+        record_build_script_directives(&cfg, &crates, intermediates_dir)?;
+    }
 
-    // TODO: Use different directories for different variants.
-    // Find out files.
-    // Example: target.tmp/x86_64-unknown-linux-gnu/debug/build/metrics-d2dd799cebf1888d/out/event_details.rs
+    // Find out files in each variant's target directory.
+    // Example: variant_0/target.tmp/x86_64-unknown-linux-gnu/debug/build/metrics-d2dd799cebf1888d/out/event_details.rs
     let num_variants = cfg.variants.len();
     let mut package_out_files: BTreeMap<String, Vec<Vec<PathBuf>>> = BTreeMap::new();
     for (variant_index, variant_cfg) in cfg.variants.iter().enumerate() {
         if variant_cfg.package.iter().any(|(_, v)| v.copy_out) {
-            for entry in glob::glob(&intermediates_glob)? {
+            let out_glob = variant_intermediates_dir(intermediates_dir, variant_index)
+                .join("target.tmp/**/build/*/out/*");
+            let out_glob = out_glob
+                .to_str()
+                .ok_or(anyhow!("Failed to convert intermediate dir path to string"))?;
+            for entry in glob::glob(out_glob)? {
                 match entry {
                     Ok(path) => {
                         let package_name = || -> Option<_> {
@@ -363,19 +532,87 @@ fn run_embargo(args: &Args, config_filename: &Path, intermediates_dir: &Path) ->
         }
     }
 
-    // If we were configured to run cargo, check whether we could have got away without it.
+    // If we were configured to run cargo, check whether we could have got away without it. This
+    // only needs the `cargo metadata` output which we already have, so doesn't run cargo again.
     if cfg.variants.iter().any(|variant| variant.run_cargo) && package_out_files.is_empty() {
         let mut cfg_no_cargo = cfg.clone();
         for variant in &mut cfg_no_cargo.variants {
             variant.run_cargo = false;
         }
-        let crates_no_cargo = make_all_crates(args, &cfg_no_cargo, intermediates_dir)?;
-        if crates_no_cargo == crates {
+        let crates_no_cargo = parse_all_crates(&cfg_no_cargo, &cargo_outputs)?;
+        if same_crates_ignoring_warnings(&crates_no_cargo, &crates) {
             eprintln!("Running cargo appears to be unnecessary for this crate, consider adding `\"run_cargo\": false` to your cargo_embargo.json.");
         }
     }
 
-    write_all_build_files(&cfg, crates, &package_out_files)
+    write_all_build_files(&cfg, crates, &package_out_files, check, args.write_rejects)
+}
+
+/// Records the output of the build scripts of the packages in `crates` from the target directories
+/// of the variants which ran cargo, so that it can be used when `run_cargo` is false.
+///
+/// Any previously recorded output is removed for packages whose build script output is now empty or
+/// can't be recorded, so that it isn't used instead.
+///
+/// Returns the path of each file written or removed, along with its previous contents if it already
+/// existed.
+fn record_build_script_directives(
+    cfg: &Config,
+    crates: &[Vec<Crate>],
+    intermediates_dir: &Path,
+) -> Result<Vec<(PathBuf, Option<String>)>> {
+    let package_dirs: BTreeMap<&str, &Path> = crates
+        .iter()
+        .flatten()
+        .map(|c| (c.package_name.as_str(), c.package_dir.as_path()))
+        .collect();
+    let mut all_directives: BTreeMap<String, Vec<BuildScriptDirectives>> = BTreeMap::new();
+    for (variant_index, variant) in cfg.variants.iter().enumerate() {
+        if !variant.run_cargo {
+            continue;
+        }
+        let target_dir =
+            variant_intermediates_dir(intermediates_dir, variant_index).join("target.tmp");
+        for (package_name, directives) in find_build_script_directives(&target_dir)? {
+            all_directives.entry(package_name).or_default().extend(directives);
+        }
+    }
+
+    let mut recorded = Vec::new();
+    // Only record outputs for the packages which build files are generated for.
+    for (package_name, package_dir) in package_dirs {
+        let directives = all_directives.get(package_name).map_or(&[][..], Vec::as_slice);
+        let path = package_dir.join(BUILD_SCRIPT_OUTPUT_FILE);
+        let previous = match read_to_string(&path) {
+            Ok(previous) => Some(previous),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path:?}")),
+        };
+        let to_record = match directives {
+            // Build scripts which don't affect how the crates are built, e.g. which only print
+            // `cargo:rerun-if-changed`, needn't be recorded.
+            [] => None,
+            [first] if *first == BuildScriptDirectives::default() => None,
+            [first, rest @ ..] if rest.iter().any(|directives| directives != first) => {
+                eprintln!(
+                    "WARNING: not recording build script output for {package_name} as it differs \
+                     between targets or variants"
+                );
+                None
+            }
+            [first, ..] => Some(first),
+        };
+        if let Some(directives) = to_record {
+            directives.write(package_dir)?;
+        } else if previous.is_some() {
+            remove_file(&path).with_context(|| format!("failed to remove {path:?}"))?;
+            eprintln!("Removed previously recorded build script output {path:?}");
+        } else {
+            continue;
+        }
+        recorded.push((path, previous));
+    }
+    Ok(recorded)
 }
 
 /// Input is indexed by variant, then all crates for that variant.
@@ -396,10 +633,16 @@ fn group_by_package(crates: Vec<Vec<Crate>>) -> BTreeMap<PathBuf, Vec<Vec<Crate>
     module_by_package
 }
 
+/// Writes the build files for all packages or, if `check` is true, checks that the existing build
+/// files are up to date and fails if they aren't.
+///
+/// If `write_rejects` is true then hunks of patches which fail to apply are written to `.rej` files.
 fn write_all_build_files(
     cfg: &Config,
     crates: Vec<Vec<Crate>>,
     package_out_files: &BTreeMap<String, Vec<Vec<PathBuf>>>,
+    check: bool,
+    write_rejects: bool,
 ) -> Result<()> {
     // Group by package.
     let module_by_package = group_by_package(crates);
@@ -407,16 +650,22 @@ fn write_all_build_files(
     let num_variants = cfg.variants.len();
     let empty_package_out_files = vec![vec![]; num_variants];
     let mut has_error = false;
+    let mut stale_packages = Vec::new();
     // Write a build file per package.
     for (package_dir, crates) in module_by_package {
         let package_name = &crates.iter().flatten().next().unwrap().package_name;
-        if let Err(e) = write_build_files(
-            cfg,
-            package_name,
-            package_dir,
-            &crates,
-            package_out_files.get(package_name).unwrap_or(&empty_package_out_files),
-        ) {
+        let out_files = package_out_files.get(package_name).unwrap_or(&empty_package_out_files);
+        let result = if check {
+            check_build_files(cfg, package_name, &package_dir, &crates, out_files, write_rejects)
+                .map(|up_to_date| {
+                    if !up_to_date {
+                        stale_packages.push(package_name.clone());
+                    }
+                })
+        } else {
+            write_build_files(cfg, package_name, &package_dir, &crates, out_files, write_rejects)
+        };
+        if let Err(e) = result {
             // print the error, but continue to accumulate all of the errors
             eprintln!("ERROR: {:#}", e);
             has_error = true;
@@ -425,6 +674,9 @@ fn write_all_build_files(
     if has_error {
         panic!("Encountered fatal errors that must be fixed.");
     }
+    if !stale_packages.is_empty() {
+        bail!("Build files are out of date for packages: {}", stale_packages.join(", "));
+    }
 
     Ok(())
 }
@@ -466,6 +718,49 @@ pub struct CargoOutput {
     cargo_out: String,
 }
 
+/// Runs the `cargo build` command returned by `make_command` with `--message-format=json`, and
+/// returns its output.
+///
+/// If cargo supports it, the output is preceded by the unit graph for the same command, which
+/// needs `RUSTC_BOOTSTRAP` as `--unit-graph` is unstable. If the first attempt to get a unit graph
+/// fails then a warning is printed and `unit_graph_supported` is set to false so that no more are
+/// attempted, and the crates are instead parsed from the rustc invocations.
+fn run_cargo_build(
+    make_command: impl Fn() -> Command,
+    unit_graph_supported: &mut Option<bool>,
+) -> Result<String> {
+    let mut output = String::new();
+    if *unit_graph_supported != Some(false) {
+        let unit_graph = run_cargo(
+            make_command()
+                .args(["--unit-graph", "-Z", "unstable-options"])
+                .env("RUSTC_BOOTSTRAP", "1"),
+            false,
+        );
+        match (unit_graph, *unit_graph_supported) {
+            (Ok(unit_graph), _) => {
+                output += &unit_graph;
+                *unit_graph_supported = Some(true);
+            }
+            (Err(e), None) => {
+                eprintln!(
+                    "WARNING: failed to get unit graph, falling back to parsing rustc invocations: \
+                     {e:#}"
+                );
+                *unit_graph_supported = Some(false);
+            }
+            // Don't mix unit graphs with parsed rustc invocations.
+            (Err(e), Some(_)) => return Err(e).context("failed to get unit graph"),
+        }
+    }
+    let json_output = run_cargo(make_command().arg("--message-format=json"), true)?;
+    // Cargo doesn't print the output of build scripts along with JSON messages, so read it from
+    // the target directory instead.
+    output += &build_script_logs(&json_output)?;
+    output += &json_output;
+    Ok(output)
+}
+
 /// Run various cargo commands and returns the output.
 fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<CargoOutput> {
     let verbose_args = ["-v"];
@@ -475,7 +770,7 @@ fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<C
     run_cargo(Command::new("cargo").arg("clean").arg("--target-dir").arg(&target_dir), true)
         .context("Running cargo clean")?;
 
-    let default_target = "x86_64-unknown-linux-gnu";
+    let host_target = "x86_64-unknown-linux-gnu";
     let feature_args = if let Some(features) = &cfg.features {
         if features.is_empty() {
             vec!["--no-default-features".to_string()]
@@ -512,6 +807,8 @@ fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<C
     .context("Running cargo metadata")?;
 
     let mut cargo_out = String::new();
+    // Whether cargo supports `--unit-graph`, or `None` if it hasn't been tried yet.
+    let mut unit_graph_supported = None;
     if cfg.run_cargo {
         let envs = if cfg.extra_cfg.is_empty() {
             vec![]
@@ -526,99 +823,117 @@ fn generate_cargo_out(cfg: &VariantConfig, intermediates_dir: &Path) -> Result<C
             )]
         };
 
-        // cargo build
-        cargo_out += &run_cargo(
-            Command::new("cargo")
-                .envs(envs.clone())
-                .args(["build", "--target", default_target])
-                .args(verbose_args)
-                .arg("--target-dir")
-                .arg(&target_dir)
-                .args(&workspace_args)
-                .args(&feature_args),
-            true,
-        )?;
-
-        if cfg.tests {
-            // cargo build --tests
-            cargo_out += &run_cargo(
-                Command::new("cargo")
-                    .envs(envs.clone())
-                    .args(["build", "--target", default_target, "--tests"])
-                    .args(verbose_args)
-                    .arg("--target-dir")
-                    .arg(&target_dir)
-                    .args(&workspace_args)
-                    .args(&feature_args),
-                true,
-            )?;
-            // cargo test -- --list
-            cargo_out += &run_cargo(
-                Command::new("cargo")
-                    .envs(envs)
-                    .args(["test", "--target", default_target])
-                    .arg("--target-dir")
-                    .arg(&target_dir)
-                    .args(&workspace_args)
-                    .args(&feature_args)
-                    .args(["--", "--list"]),
-                true,
+        for target in &cfg.targets {
+            // cargo build
+            cargo_out += &run_cargo_build(
+                || {
+                    let mut cmd = Command::new("cargo");
+                    cmd.envs(envs.clone())
+                        .args(["build", "--target", target])
+                        .args(verbose_args)
+                        .arg("--target-dir")
+                        .arg(&target_dir)
+                        .args(&workspace_args)
+                        .args(&feature_args);
+                    cmd
+                },
+                &mut unit_graph_supported,
             )?;
-        }
-    }
 
-    Ok(CargoOutput { cargo_metadata, cargo_out })
-}
+            if cfg.package.values().any(|package_cfg| package_cfg.benches) {
+                // cargo build --bench '*'
+                // Unlike `--benches`, this doesn't also build the library's unit tests in bench
+                // mode.
+                cargo_out += &run_cargo_build(
+                    || {
+                        let mut cmd = Command::new("cargo");
+                        cmd.envs(envs.clone())
+                            .args(["build", "--target", target, "--bench", "*"])
+                            .args(verbose_args)
+                            .arg("--target-dir")
+                            .arg(&target_dir)
+                            .args(&workspace_args)
+                            .args(&feature_args);
+                        cmd
+                    },
+                    &mut unit_graph_supported,
+                )?;
+            }
 
-/// Read and return license and other header lines from a build file.
-///
-/// Skips initial comment lines, then returns all lines before the first line
-/// starting with `rust_`, `genrule {`, or `LOCAL_DIR`.
-///
-/// If `path` could not be read, return a placeholder license TODO line.
-fn read_license_header(path: &Path) -> Result<String> {
-    // Keep the old license header.
-    match std::fs::read_to_string(path) {
-        Ok(s) => Ok(s
-            .lines()
-            .skip_while(|l| l.starts_with("//") || l.starts_with('#'))
-            .take_while(|l| {
-                !l.starts_with("rust_")
-                    && !l.starts_with("genrule {")
-                    && !l.starts_with("LOCAL_DIR")
-            })
-            .collect::<Vec<&str>>()
-            .join("\n")),
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            Ok("// DO NOT SUBMIT: Add license before submitting.\n".to_string())
+            for (package_name, example) in selected_examples(&cargo_metadata, cfg)? {
+                // cargo build --example
+                cargo_out += &run_cargo_build(
+                    || {
+                        let mut cmd = Command::new("cargo");
+                        cmd.envs(envs.clone())
+                            .args(["build", "--target", target])
+                            .args(["--package", &package_name, "--example", &example])
+                            .args(verbose_args)
+                            .arg("--target-dir")
+                            .arg(&target_dir)
+                            .args(&feature_args);
+                        cmd
+                    },
+                    &mut unit_graph_supported,
+                )?;
+            }
+
+            if cfg.tests {
+                // cargo build --tests
+                cargo_out += &run_cargo_build(
+                    || {
+                        let mut cmd = Command::new("cargo");
+                        cmd.envs(envs.clone())
+                            .args(["build", "--target", target, "--tests"])
+                            .args(verbose_args)
+                            .arg("--target-dir")
+                            .arg(&target_dir)
+                            .args(&workspace_args)
+                            .args(&feature_args);
+                        cmd
+                    },
+                    &mut unit_graph_supported,
+                )?;
+                // The tests can only be listed for targets which can run on the host.
+                if target == host_target {
+                    // cargo test -- --list
+                    cargo_out += &run_cargo(
+                        Command::new("cargo")
+                            .envs(envs.clone())
+                            .args(["test", "--target", target])
+                            .arg("--target-dir")
+                            .arg(&target_dir)
+                            .args(&workspace_args)
+                            .args(&feature_args)
+                            .args(["--", "--list"]),
+                        true,
+                    )?;
+                }
+            }
         }
-        Err(e) => Err(anyhow!("error when reading {path:?}: {e}")),
     }
+
+    Ok(CargoOutput { cargo_metadata, cargo_out })
 }
 
-/// Create the build file for `package_dir`.
+/// Copies the generated out files of each variant to the variant's out directory in `package_dir`,
+/// if `copy_out` is enabled for the package.
 ///
-/// `crates` and `out_files` are both indexed by variant.
-fn write_build_files(
+/// `out_files` is indexed by variant.
+fn copy_out_files(
     cfg: &Config,
     package_name: &str,
-    package_dir: PathBuf,
-    crates: &[Vec<Crate>],
+    package_dir: &Path,
     out_files: &[Vec<PathBuf>],
 ) -> Result<()> {
-    assert_eq!(crates.len(), out_files.len());
-
-    let mut bp_contents = String::new();
-    let mut mk_contents = String::new();
     for (variant_index, variant_config) in cfg.variants.iter().enumerate() {
-        let variant_crates = &crates[variant_index];
         let def = PackageVariantConfig::default();
         let package_variant_cfg = variant_config.package.get(package_name).unwrap_or(&def);
 
         // If `copy_out` is enabled and there are any generated out files for the package, copy them to
         // the appropriate directory.
         if package_variant_cfg.copy_out && !out_files[variant_index].is_empty() {
-            let out_dir = package_dir.join("out");
+            let out_dir = package_dir.join(out_dir_name(variant_index));
             if !out_dir.exists() {
                 std::fs::create_dir(&out_dir).expect("failed to create out dir");
             }
@@ -628,658 +943,122 @@ fn write_build_files(
                 std::fs::copy(f, dest).expect("failed to copy out file");
             }
         }
-
-        if variant_config.generate_androidbp {
-            bp_contents += &generate_android_bp(
-                variant_config,
-                package_variant_cfg,
-                package_name,
-                variant_crates,
-                &out_files[variant_index],
-            )?;
-        }
-        if variant_config.generate_rulesmk {
-            mk_contents += &generate_rules_mk(
-                variant_config,
-                package_variant_cfg,
-                package_name,
-                variant_crates,
-                &out_files[variant_index],
-            )?;
-        }
-    }
-
-    let def = PackageConfig::default();
-    let package_cfg = cfg.package.get(package_name).unwrap_or(&def);
-    if let Some(path) = &package_cfg.add_toplevel_block {
-        bp_contents +=
-            &std::fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
-        bp_contents += "\n";
-    }
-    if !bp_contents.is_empty() {
-        let output_path = package_dir.join("Android.bp");
-        let package_header = generate_android_bp_package_header(
-            package_name,
-            package_cfg,
-            read_license_header(&output_path)?.trim(),
-            crates,
-        )?;
-        let bp_contents = package_header + &bp_contents;
-        write_format_android_bp(&output_path, &bp_contents, package_cfg.patch.as_deref())?;
-    }
-    if !mk_contents.is_empty() {
-        let output_path = package_dir.join("rules.mk");
-        let mk_contents = "# This file is generated by cargo_embargo.\n".to_owned()
-            + "# Do not modify this file after the LOCAL_DIR line\n"
-            + "# because the changes will be overridden on upgrade.\n"
-            + "# Content before the first line starting with LOCAL_DIR is preserved.\n"
-            + read_license_header(&output_path)?.trim()
-            + "\n"
-            + &mk_contents;
-        File::create(&output_path)?.write_all(mk_contents.as_bytes())?;
-        if let Some(patch) = package_cfg.rulesmk_patch.as_deref() {
-            apply_patch_file(&output_path, patch)?;
-        }
     }
-
     Ok(())
 }
 
-fn generate_android_bp_package_header(
+/// Create the build files for `package_dir`.
+///
+/// `crates` and `out_files` are both indexed by variant.
+fn write_build_files(
+    cfg: &Config,
     package_name: &str,
-    package_cfg: &PackageConfig,
-    license_header: &str,
+    package_dir: &Path,
     crates: &[Vec<Crate>],
-) -> Result<String> {
-    let crates = crates.iter().flatten().collect::<Vec<_>>();
-    if let Some(first) = crates.first() {
-        if let Some(license) = first.license.as_ref() {
-            if crates.iter().all(|c| c.license.as_ref() == Some(license)) {
-                let mut modules = Vec::new();
-                let license = choose_license(license);
-                let license_name = format!("external_rust_crates_{}_license", package_name);
-
-                let mut package_module = BpModule::new("package".to_string());
-                package_module.props.set("default_team", "trendy_team_android_rust");
-                package_module.props.set("default_applicable_licenses", vec![license_name.clone()]);
-                modules.push(package_module);
-
-                let mut license_module = BpModule::new("license".to_string());
-                license_module.props.set("name", license_name);
-                license_module.props.set("visibility", vec![":__subpackages__"]);
-                license_module
-                    .props
-                    .set("license_kinds", vec![format!("SPDX-license-identifier-{}", license)]);
-                let license_text = package_cfg
-                    .license_text
-                    .as_deref()
-                    .unwrap_or_else(|| first.license_file.as_deref().unwrap_or("LICENSE"))
-                    .to_string();
-                license_module.props.set("license_text", vec![license_text]);
-                modules.push(license_module);
-
-                let mut bp_contents = "// This file is generated by cargo_embargo.\n".to_owned()
-                + "// Do not modify this file because the changes will be overridden on upgrade.\n\n";
-                for m in modules {
-                    m.write(&mut bp_contents)?;
-                    bp_contents += "\n";
-                }
-                return Ok(bp_contents);
-            } else {
-                eprintln!("Crates have different licenses.");
-            }
-        }
-    }
-
-    Ok("// This file is generated by cargo_embargo.\n".to_owned()
-        + "// Do not modify this file after the first \"rust_*\" or \"genrule\" module\n"
-        + "// because the changes will be overridden on upgrade.\n"
-        + "// Content before the first \"rust_*\" or \"genrule\" module is preserved.\n\n"
-        + license_header
-        + "\n")
-}
-
-/// Given an SPDX license expression that may offer a choice between several licenses, choose one to
-/// use.
-fn choose_license(license: &str) -> &str {
-    match license {
-        "MIT OR Apache-2.0" => "Apache-2.0",
-        "Apache-2.0 OR MIT" => "Apache-2.0",
-        "MIT/Apache-2.0" => "Apache-2.0",
-        "Apache-2.0/MIT" => "Apache-2.0",
-        "Apache-2.0 or BSD-3-Clause" => "Apache-2.0",
-        "Zlib OR Apache-2.0 OR MIT" => "Apache-2.0",
-        "MIT OR LGPL-3.0-or-later" => "MIT",
-        "Apache-2.0 / MIT" => "Apache-2.0",
-        "Unlicense OR MIT" => "MIT",
-        "BSD-3-Clause OR MIT OR Apache-2.0" => "Apache-2.0",
-        "BSD-2-Clause OR Apache-2.0 OR MIT" => "Apache-2.0",
-        "Apache-2.0 WITH LLVM-exception OR Apache-2.0 OR MIT" => "Apache-2.0",
-        _ => license,
-    }
-}
-
-/// Generates and returns a Soong Blueprint for the given set of crates, for a single variant of a
-/// package.
-fn generate_android_bp(
-    cfg: &VariantConfig,
-    package_cfg: &PackageVariantConfig,
-    package_name: &str,
-    crates: &[Crate],
-    out_files: &[PathBuf],
-) -> Result<String> {
-    let mut bp_contents = String::new();
-
-    let mut modules = Vec::new();
-
-    let extra_srcs = if package_cfg.copy_out && !out_files.is_empty() {
-        let outs: Vec<String> = out_files
-            .iter()
-            .map(|f| f.file_name().unwrap().to_str().unwrap().to_string())
-            .collect();
-
-        let mut m = BpModule::new("genrule".to_string());
-        if let Some(module_name) = override_module_name(
-            &format!("copy_{}_build_out", package_name),
-            &cfg.module_blocklist,
-            &cfg.module_name_overrides,
-            &RENAME_MAP,
-        ) {
-            m.props.set("name", module_name.clone());
-            m.props.set("srcs", vec!["out/*"]);
-            m.props.set("cmd", "cp $(in) $(genDir)");
-            m.props.set("out", outs);
-            modules.push(m);
-
-            vec![":".to_string() + &module_name]
-        } else {
-            vec![]
-        }
-    } else {
-        vec![]
-    };
-
-    for c in crates {
-        modules.extend(crate_to_bp_modules(c, cfg, package_cfg, &extra_srcs).with_context(
-            || {
-                format!(
-                    "failed to generate bp module for crate \"{}\" with package name \"{}\"",
-                    c.name, c.package_name
-                )
-            },
-        )?);
-    }
-
-    // In some cases there are nearly identical rustc invocations that that get processed into
-    // identical BP modules. So far, dedup'ing them is a good enough fix. At some point we might
-    // need something more complex, maybe like cargo2android's logic for merging crates.
-    modules.sort();
-    modules.dedup();
-
-    modules.sort_by_key(|m| m.props.get_string("name").unwrap().to_string());
-    for m in modules {
-        m.write(&mut bp_contents)?;
-        bp_contents += "\n";
-    }
-    Ok(bp_contents)
-}
-
-/// Generates and returns a Trusty rules.mk file for the given set of crates.
-fn generate_rules_mk(
-    cfg: &VariantConfig,
-    package_cfg: &PackageVariantConfig,
-    package_name: &str,
-    crates: &[Crate],
-    out_files: &[PathBuf],
-) -> Result<String> {
-    let out_files = if package_cfg.copy_out && !out_files.is_empty() {
-        out_files.iter().map(|f| f.file_name().unwrap().to_str().unwrap().to_string()).collect()
-    } else {
-        vec![]
-    };
-
-    let crates: Vec<_> = crates
-        .iter()
-        .filter(|c| {
-            if c.types.contains(&CrateType::Bin) {
-                eprintln!("WARNING: skipped generation of rules.mk for binary crate: {}", c.name);
-                false
-            } else if c.types.iter().any(|t| t.is_test()) {
-                // Test build file generation is not yet implemented
-                eprintln!("WARNING: skipped generation of rules.mk for test crate: {}", c.name);
-                false
-            } else {
-                true
-            }
-        })
-        .collect();
-    let [crate_] = &crates[..] else {
-        bail!(
-            "Expected exactly one library crate for package {package_name} when generating \
-               rules.mk, found: {crates:?}"
-        );
-    };
-    crate_to_rulesmk(crate_, cfg, package_cfg, &out_files).with_context(|| {
-        format!(
-            "failed to generate rules.mk for crate \"{}\" with package name \"{}\"",
-            crate_.name, crate_.package_name
-        )
-    })
-}
-
-/// Apply patch from `patch_path` to file `output_path`.
-///
-/// Warns but still returns ok if the patch did not cleanly apply,
-fn apply_patch_file(output_path: &Path, patch_path: &Path) -> Result<()> {
-    let patch_output = Command::new("patch")
-        .arg("-s")
-        .arg(output_path)
-        .arg(patch_path)
-        .output()
-        .context("Running patch")?;
-    if !patch_output.status.success() {
-        // These errors will cause the cargo_embargo command to fail, but not yet!
-        return Err(anyhow!("failed to apply patch {patch_path:?}"));
-    }
-    Ok(())
-}
-
-/// Writes the given contents to the given `Android.bp` file, formats it with `bpfmt`, and applies
-/// the patch if there is one.
-fn write_format_android_bp(
-    bp_path: &Path,
-    bp_contents: &str,
-    patch_path: Option<&Path>,
+    out_files: &[Vec<PathBuf>],
+    write_rejects: bool,
 ) -> Result<()> {
-    File::create(bp_path)?.write_all(bp_contents.as_bytes())?;
-
-    let bpfmt_output =
-        Command::new("bpfmt").arg("-w").arg(bp_path).output().context("Running bpfmt")?;
-    if !bpfmt_output.status.success() {
-        eprintln!(
-            "WARNING: bpfmt -w {:?} failed before patch: {}",
-            bp_path,
-            String::from_utf8_lossy(&bpfmt_output.stderr)
-        );
-    }
-
-    if let Some(patch_path) = patch_path {
-        apply_patch_file(bp_path, patch_path)?;
-        // Re-run bpfmt after the patch so
-        let bpfmt_output = Command::new("bpfmt")
-            .arg("-w")
-            .arg(bp_path)
-            .output()
-            .context("Running bpfmt after patch")?;
-        if !bpfmt_output.status.success() {
-            eprintln!(
-                "WARNING: bpfmt -w {:?} failed after patch: {}",
-                bp_path,
-                String::from_utf8_lossy(&bpfmt_output.stderr)
-            );
+    copy_out_files(cfg, package_name, package_dir, out_files)?;
+    for (path, contents) in
+        generate_build_files(cfg, package_name, package_dir, crates, out_files, write_rejects)?
+    {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent).with_context(|| format!("failed to create {parent:?}"))?;
         }
+        write(&path, contents).with_context(|| format!("failed to write {path:?}"))?;
     }
-
     Ok(())
 }
 
-/// Convert a `Crate` into `BpModule`s.
+/// Checks whether the build files for `package_dir` are up to date, printing a diff for each one
+/// which isn't.
 ///
-/// If messy business logic is necessary, prefer putting it here.
-fn crate_to_bp_modules(
-    crate_: &Crate,
-    cfg: &VariantConfig,
-    package_cfg: &PackageVariantConfig,
-    extra_srcs: &[String],
-) -> Result<Vec<BpModule>> {
-    let mut modules = Vec::new();
-    for crate_type in &crate_.types {
-        let host = if package_cfg.device_supported { "" } else { "_host" };
-        let rlib = if package_cfg.force_rlib { "_rlib" } else { "" };
-        let (module_type, module_name) = match crate_type {
-            CrateType::Bin => ("rust_binary".to_string() + host, crate_.name.clone()),
-            CrateType::Lib | CrateType::RLib => {
-                let stem = "lib".to_string() + &crate_.name;
-                ("rust_library".to_string() + host + rlib, stem)
-            }
-            CrateType::DyLib => {
-                let stem = "lib".to_string() + &crate_.name;
-                ("rust_library".to_string() + host + "_dylib", stem + "_dylib")
-            }
-            CrateType::CDyLib => {
-                let stem = "lib".to_string() + &crate_.name;
-                ("rust_ffi".to_string() + host + "_shared", stem + "_shared")
-            }
-            CrateType::StaticLib => {
-                let stem = "lib".to_string() + &crate_.name;
-                ("rust_ffi".to_string() + host + "_static", stem + "_static")
-            }
-            CrateType::ProcMacro => {
-                let stem = "lib".to_string() + &crate_.name;
-                ("rust_proc_macro".to_string(), stem)
-            }
-            CrateType::Test | CrateType::TestNoHarness => {
-                let suffix = crate_.main_src.to_string_lossy().into_owned();
-                let suffix = suffix.replace('/', "_").replace(".rs", "");
-                let stem = crate_.package_name.clone() + "_test_" + &suffix;
-                if crate_.empty_test {
-                    return Ok(Vec::new());
-                }
-                if crate_type == &CrateType::TestNoHarness {
-                    eprintln!(
-                        "WARNING: ignoring test \"{}\" with harness=false. not supported yet",
-                        stem
-                    );
-                    return Ok(Vec::new());
-                }
-                ("rust_test".to_string() + host, stem)
-            }
-        };
-
-        let mut m = BpModule::new(module_type.clone());
-        let Some(module_name) = override_module_name(
-            &module_name,
-            &cfg.module_blocklist,
-            &cfg.module_name_overrides,
-            &RENAME_MAP,
-        ) else {
-            continue;
-        };
-        if matches!(
-            crate_type,
-            CrateType::Lib
-                | CrateType::RLib
-                | CrateType::DyLib
-                | CrateType::CDyLib
-                | CrateType::StaticLib
-        ) && !module_name.starts_with(&format!("lib{}", crate_.name))
-        {
-            bail!("Module name must start with lib{} but was {}", crate_.name, module_name);
-        }
-        m.props.set("name", module_name.clone());
-
-        if let Some(defaults) = &cfg.global_defaults {
-            m.props.set("defaults", vec![defaults.clone()]);
-        }
-
-        if package_cfg.host_supported
-            && package_cfg.device_supported
-            && module_type != "rust_proc_macro"
-        {
-            m.props.set("host_supported", true);
-        }
-
-        if module_type != "rust_proc_macro" {
-            if package_cfg.host_supported && !package_cfg.host_cross_supported {
-                m.props.set("host_cross_supported", false);
-            } else if crate_.externs.iter().any(|extern_dep| extern_dep.name == "proc_macro2") {
-                // proc_macro2 is host_cross_supported: false.
-                // If there's a dependency on it, then we shouldn't build for HostCross.
-                m.props.set("host_cross_supported", false);
-            } else if crate_.package_name == "proc-macro2" {
-                m.props.set("host_cross_supported", false);
-            }
-        }
-
-        if !crate_type.is_test() && package_cfg.host_supported && package_cfg.host_first_multilib {
-            m.props.set("compile_multilib", "first");
-        }
-        if crate_type.is_c_library() {
-            m.props.set_if_nonempty("include_dirs", package_cfg.exported_c_header_dir.clone());
-        }
-
-        m.props.set("crate_name", crate_.name.clone());
-        m.props.set("cargo_env_compat", true);
-
-        if let Some(version) = &crate_.version {
-            m.props.set("cargo_pkg_version", version.clone());
-        }
-
-        if crate_.types.contains(&CrateType::Test) {
-            m.props.set("test_suites", vec!["general-tests"]);
-            m.props.set("auto_gen_config", true);
-            if package_cfg.host_supported {
-                m.props.object("test_options").set("unit_test", !package_cfg.no_presubmit);
-            }
-        }
-
-        m.props.set("crate_root", crate_.main_src.clone());
-        m.props.set_if_nonempty("srcs", extra_srcs.to_owned());
-
-        m.props.set("edition", crate_.edition.clone());
-        m.props.set_if_nonempty("features", crate_.features.clone());
-        m.props.set_if_nonempty(
-            "cfgs",
-            crate_
-                .cfgs
-                .clone()
-                .into_iter()
-                .filter(|crate_cfg| !cfg.cfg_blocklist.contains(crate_cfg))
-                .collect(),
-        );
-
-        let mut flags = Vec::new();
-        if !crate_.cap_lints.is_empty() {
-            flags.push(crate_.cap_lints.clone());
-        }
-        flags.extend(crate_.codegens.iter().map(|codegen| format!("-C {}", codegen)));
-        m.props.set_if_nonempty("flags", flags);
-
-        let mut rust_libs = Vec::new();
-        let mut proc_macro_libs = Vec::new();
-        let mut aliases = Vec::new();
-        for extern_dep in &crate_.externs {
-            match extern_dep.extern_type {
-                ExternType::Rust => rust_libs.push(extern_dep.lib_name.clone()),
-                ExternType::ProcMacro => proc_macro_libs.push(extern_dep.lib_name.clone()),
-            }
-            if extern_dep.name != extern_dep.lib_name {
-                aliases.push(format!("{}:{}", extern_dep.lib_name, extern_dep.name));
-            }
-        }
-
-        // Add "lib" prefix and apply name overrides.
-        let process_lib_deps = |libs: Vec<String>| -> Vec<String> {
-            let mut result = Vec::new();
-            for x in libs {
-                let module_name = "lib".to_string() + x.as_str();
-                if let Some(module_name) = override_module_name(
-                    &module_name,
-                    &package_cfg.dep_blocklist,
-                    &cfg.module_name_overrides,
-                    &RENAME_MAP,
-                ) {
-                    result.push(module_name);
-                }
-            }
-            result.sort();
-            result.dedup();
-            result
+/// `crates` and `out_files` are both indexed by variant.
+fn check_build_files(
+    cfg: &Config,
+    package_name: &str,
+    package_dir: &Path,
+    crates: &[Vec<Crate>],
+    out_files: &[Vec<PathBuf>],
+    write_rejects: bool,
+) -> Result<bool> {
+    let mut up_to_date = true;
+    for (path, contents) in
+        generate_build_files(cfg, package_name, package_dir, crates, out_files, write_rejects)?
+    {
+        let existing = match read_to_string(&path) {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {path:?}")),
         };
-        m.props.set_if_nonempty("rustlibs", process_lib_deps(rust_libs));
-        m.props.set_if_nonempty("proc_macros", process_lib_deps(proc_macro_libs));
-        let (whole_static_libs, static_libs) = process_lib_deps(crate_.static_libs.clone())
-            .into_iter()
-            .partition(|static_lib| package_cfg.whole_static_libs.contains(static_lib));
-        m.props.set_if_nonempty("static_libs", static_libs);
-        m.props.set_if_nonempty("whole_static_libs", whole_static_libs);
-        m.props.set_if_nonempty("shared_libs", process_lib_deps(crate_.shared_libs.clone()));
-        m.props.set_if_nonempty("aliases", aliases);
-
-        if package_cfg.device_supported {
-            if !crate_type.is_test() {
-                if cfg.native_bridge_supported {
-                    m.props.set("native_bridge_supported", true);
-                }
-                if cfg.product_available {
-                    m.props.set("product_available", true);
-                }
-                if cfg.ramdisk_available {
-                    m.props.set("ramdisk_available", true);
-                }
-                if cfg.recovery_available {
-                    m.props.set("recovery_available", true);
-                }
-                if cfg.vendor_available {
-                    m.props.set("vendor_available", true);
-                }
-                if cfg.vendor_ramdisk_available {
-                    m.props.set("vendor_ramdisk_available", true);
-                }
-            }
-            if crate_type.is_library() {
-                m.props.set_if_nonempty("apex_available", cfg.apex_available.clone());
-                if let Some(min_sdk_version) = &cfg.min_sdk_version {
-                    m.props.set("min_sdk_version", min_sdk_version.clone());
-                }
-            }
-        }
-        if crate_type.is_test() {
-            if let Some(data) =
-                package_cfg.test_data.get(crate_.main_src.to_string_lossy().as_ref())
-            {
-                m.props.set("data", data.clone());
-            }
-        } else if package_cfg.no_std {
-            m.props.set("prefer_rlib", true);
-            m.props.set("no_stdlibs", true);
-            let mut stdlibs = vec!["libcompiler_builtins.rust_sysroot", "libcore.rust_sysroot"];
-            if package_cfg.alloc {
-                stdlibs.push("liballoc.rust_sysroot");
-            }
-            stdlibs.sort();
-            m.props.set("stdlibs", stdlibs);
+        if existing != contents {
+            let path = path.to_string_lossy();
+            print!("{}", diff::unified_diff(&existing, &contents, &path, &path));
+            up_to_date = false;
         }
-
-        if let Some(visibility) = cfg.module_visibility.get(&module_name) {
-            m.props.set("visibility", visibility.clone());
-        }
-
-        if let Some(path) = &package_cfg.add_module_block {
-            let content = std::fs::read_to_string(path)
-                .with_context(|| format!("failed to read {path:?}"))?;
-            m.props.raw_block = Some(content);
-        }
-
-        modules.push(m);
     }
-    Ok(modules)
+    Ok(up_to_date)
 }
 
-/// Convert a `Crate` into a rules.mk file.
+/// Generates the build files for `package_dir`, returning the path and contents of each.
 ///
-/// If messy business logic is necessary, prefer putting it here.
-fn crate_to_rulesmk(
-    crate_: &Crate,
-    cfg: &VariantConfig,
-    package_cfg: &PackageVariantConfig,
-    out_files: &[String],
-) -> Result<String> {
-    let mut contents = String::new();
-
-    contents += "LOCAL_DIR := $(GET_LOCAL_DIR)\n";
-    contents += "MODULE := $(LOCAL_DIR)\n";
-    contents += &format!("MODULE_CRATE_NAME := {}\n", crate_.name);
-
-    if !crate_.types.is_empty() {
-        contents += "MODULE_RUST_CRATE_TYPES :=";
-        for crate_type in &crate_.types {
-            contents += match crate_type {
-                CrateType::Lib => " rlib",
-                CrateType::StaticLib => " staticlib",
-                CrateType::ProcMacro => " proc-macro",
-                _ => bail!("Cannot generate rules.mk for crate type {crate_type:?}"),
-            };
-        }
-        contents += "\n";
-    }
-
-    contents += &format!("MODULE_SRCS := $(LOCAL_DIR)/{}\n", crate_.main_src.display());
-
-    if !out_files.is_empty() {
-        contents += &format!("OUT_FILES := {}\n", out_files.join(" "));
-        contents += "BUILD_OUT_FILES := $(addprefix $(call TOBUILDDIR,$(MODULE))/,$(OUT_FILES))\n";
-        contents += "$(BUILD_OUT_FILES): $(call TOBUILDDIR,$(MODULE))/% : $(MODULE)/out/%\n";
-        contents += "\t@echo copying $^ to $@\n";
-        contents += "\t@$(MKDIR)\n";
-        contents += "\t@cp $^ $@\n\n";
-        contents += "MODULE_RUST_ENV += OUT_DIR=$(call TOBUILDDIR,$(MODULE))\n\n";
-        contents += "MODULE_SRCDEPS += $(BUILD_OUT_FILES)\n\n";
-        contents += "OUT_FILES :=\n";
-        contents += "BUILD_OUT_FILES :=\n";
-        contents += "\n";
-    }
+/// `crates` and `out_files` are both indexed by variant. If `write_rejects` is true then hunks of
+/// patches which fail to apply are written to `.rej` files in `package_dir`.
+fn generate_build_files(
+    cfg: &Config,
+    package_name: &str,
+    package_dir: &Path,
+    crates: &[Vec<Crate>],
+    out_files: &[Vec<PathBuf>],
+    write_rejects: bool,
+) -> Result<Vec<(PathBuf, String)>> {
+    assert_eq!(crates.len(), out_files.len());
 
-    // crate dependencies without lib- prefix. Since paths to trusty modules may
-    // contain hyphens, we generate the module path using the raw name output by
-    // cargo metadata or cargo build.
-    let mut library_deps: Vec<_> = crate_.externs.iter().map(|dep| dep.raw_name.clone()).collect();
-    if package_cfg.no_std {
-        contents += "MODULE_ADD_IMPLICIT_DEPS := false\n";
-        library_deps.push("compiler_builtins".to_string());
-        library_deps.push("core".to_string());
-        if package_cfg.alloc {
-            library_deps.push("alloc".to_string());
+    let def = PackageConfig::default();
+    let package = Package {
+        name: package_name,
+        dir: package_dir,
+        cfg: cfg.package.get(package_name).unwrap_or(&def),
+        crates,
+        write_rejects,
+    };
+    let default_package_variant_cfg = PackageVariantConfig::default();
+    let mut backends: Vec<Box<dyn Backend>> = vec![
+        Box::<AndroidBpBackend>::default(),
+        Box::<RulesMkBackend>::default(),
+        Box::<BazelBackend>::default(),
+        Box::<GnBackend>::default(),
+    ];
+    for (variant_index, variant_config) in cfg.variants.iter().enumerate() {
+        let variant = Variant {
+            cfg: variant_config,
+            package_cfg: variant_config
+                .package
+                .get(package_name)
+                .unwrap_or(&default_package_variant_cfg),
+            package_name,
+            crates: &crates[variant_index],
+            out_files: &out_files[variant_index],
+            out_dir: out_dir_name(variant_index),
+        };
+        for backend in &mut backends {
+            if backend.enabled(variant_config) {
+                backend.add_variant(&variant)?;
+            }
         }
     }
 
-    contents += &format!("MODULE_RUST_EDITION := {}\n", crate_.edition);
-
-    let mut flags = Vec::new();
-    if !crate_.cap_lints.is_empty() {
-        flags.push(crate_.cap_lints.clone());
-    }
-    flags.extend(crate_.codegens.iter().map(|codegen| format!("-C {}", codegen)));
-    flags.extend(crate_.features.iter().map(|feat| format!("--cfg 'feature=\"{feat}\"'")));
-    flags.extend(
-        crate_
-            .cfgs
-            .iter()
-            .filter(|crate_cfg| !cfg.cfg_blocklist.contains(crate_cfg))
-            .map(|cfg| format!("--cfg '{cfg}'")),
-    );
-    if !flags.is_empty() {
-        contents += "MODULE_RUSTFLAGS += \\\n\t";
-        contents += &flags.join(" \\\n\t");
-        contents += "\n\n";
+    let mut build_files = Vec::new();
+    for backend in backends {
+        build_files.extend(backend.build_files(&package)?);
     }
-
-    let mut library_deps: Vec<String> = library_deps
-        .into_iter()
-        .flat_map(|dep| {
-            override_module_name(
-                &format!("lib{dep}"),
-                &package_cfg.dep_blocklist,
-                &cfg.module_name_overrides,
-                &RULESMK_RENAME_MAP,
-            )
-        })
-        .map(|dep| {
-            // Rewrite dependency name so it is passed to the FIND_CRATE macro
-            // which will expand to the module path when building Trusty.
-            if let Some(dep) = dep.strip_prefix("lib") {
-                format!("$(call FIND_CRATE,{dep})")
-            } else {
-                dep
-            }
-        })
-        .collect();
-    library_deps.sort();
-    library_deps.dedup();
-    contents += "MODULE_LIBRARY_DEPS := \\\n\t";
-    contents += &library_deps.join(" \\\n\t");
-    contents += "\n\n";
-
-    contents += "include make/library.mk\n";
-    Ok(contents)
+    Ok(build_files)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use googletest::matchers::eq;
-    use googletest::prelude::assert_that;
-    use std::env::{current_dir, set_current_dir};
+    use crate::cargo::CrateType;
     use std::fs::{self, read_to_string};
     use std::path::PathBuf;
 
@@ -1321,155 +1100,101 @@ mod tests {
     }
 
     #[test]
-    fn generate_bp() {
-        for testdata_directory_path in testdata_directories() {
-            let cfg = Config::from_json_str(
-                &read_to_string(testdata_directory_path.join("cargo_embargo.json"))
-                    .expect("Failed to open cargo_embargo.json"),
-            )
-            .unwrap();
-            let crates: Vec<Vec<Crate>> = serde_json::from_reader(
-                File::open(testdata_directory_path.join("crates.json"))
-                    .expect("Failed to open crates.json"),
-            )
-            .unwrap();
-            let expected_output =
-                read_to_string(testdata_directory_path.join("expected_Android.bp")).unwrap();
-
-            let old_current_dir = current_dir().unwrap();
-            set_current_dir(&testdata_directory_path).unwrap();
-
-            let module_by_package = group_by_package(crates);
-            assert_eq!(module_by_package.len(), 1);
-            let crates = module_by_package.into_values().next().unwrap();
-
-            let package_name = &crates[0][0].package_name;
-            let def = PackageConfig::default();
-            let package_cfg = cfg.package.get(package_name).unwrap_or(&def);
-            let mut output =
-                generate_android_bp_package_header(package_name, package_cfg, "", &crates).unwrap();
-            for (variant_index, variant_cfg) in cfg.variants.iter().enumerate() {
-                let variant_crates = &crates[variant_index];
-                let package_name = &variant_crates[0].package_name;
-                let def = PackageVariantConfig::default();
-                let package_variant_cfg = variant_cfg.package.get(package_name).unwrap_or(&def);
-
-                output += &generate_android_bp(
-                    variant_cfg,
-                    package_variant_cfg,
-                    package_name,
-                    variant_crates,
-                    &Vec::new(),
-                )
-                .unwrap();
-            }
-
-            assert_that!(output, eq(expected_output));
-
-            set_current_dir(old_current_dir).unwrap();
-        }
-    }
-
-    #[test]
-    fn crate_to_bp_empty() {
-        let c = Crate {
-            name: "name".to_string(),
-            package_name: "package_name".to_string(),
-            edition: "2021".to_string(),
-            types: vec![],
+    fn parse_all_crates_mixed_run_cargo() {
+        let cargo_metadata =
+            read_to_string(Path::new(TESTDATA_PATH).join("either/cargo.metadata")).unwrap();
+        let with_cargo = VariantConfig::default();
+        let without_cargo = VariantConfig { run_cargo: false, ..Default::default() };
+        let without_cargo_tests = VariantConfig { tests: true, ..without_cargo.clone() };
+        let cfg = Config {
+            variants: vec![with_cargo, without_cargo.clone(), without_cargo_tests.clone()],
             ..Default::default()
         };
-        let cfg = VariantConfig { ..Default::default() };
-        let package_cfg = PackageVariantConfig { ..Default::default() };
-        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+        // Nothing was built, so the variant which ran cargo has no crates.
+        let cargo_outputs =
+            vec![
+                CargoOutput { cargo_metadata: cargo_metadata.clone(), cargo_out: String::new() };
+                3
+            ];
+        let from_metadata = parse_cargo_metadata_str(&cargo_metadata, &without_cargo).unwrap();
+        let from_metadata_tests =
+            parse_cargo_metadata_str(&cargo_metadata, &without_cargo_tests).unwrap();
+        assert_ne!(from_metadata, from_metadata_tests);
+
+        // The crates are in the same order as the variants.
+        assert_eq!(
+            parse_all_crates(&cfg, &cargo_outputs).unwrap(),
+            vec![vec![], from_metadata.clone(), from_metadata_tests.clone()]
+        );
 
-        assert_eq!(modules, vec![]);
+        // The same output can be reused to check whether cargo needs to be run.
+        let mut cfg_no_cargo = cfg.clone();
+        for variant in &mut cfg_no_cargo.variants {
+            variant.run_cargo = false;
+        }
+        assert_eq!(
+            parse_all_crates(&cfg_no_cargo, &cargo_outputs).unwrap(),
+            vec![from_metadata.clone(), from_metadata, from_metadata_tests]
+        );
     }
 
     #[test]
-    fn crate_to_bp_minimal() {
+    fn rustc_warnings_limit() {
         let c = Crate {
             name: "name".to_string(),
-            package_name: "package_name".to_string(),
-            edition: "2021".to_string(),
             types: vec![CrateType::Lib],
+            warnings: vec!["unused variable: `x` (src/lib.rs:1:5)".to_string()],
+            ..Default::default()
+        };
+        let crates = vec![vec![c.clone(), Crate { types: vec![CrateType::Test], ..c }]];
+        let cfg = Config {
+            variants: vec![VariantConfig { max_rustc_warnings: Some(1), ..Default::default() }],
+            ..Default::default()
+        };
+        check_rustc_warnings(&cfg, &crates).unwrap();
+        let cfg = Config {
+            variants: vec![VariantConfig { max_rustc_warnings: Some(0), ..Default::default() }],
             ..Default::default()
         };
-        let cfg = VariantConfig { ..Default::default() };
-        let package_cfg = PackageVariantConfig { ..Default::default() };
-        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
-
         assert_eq!(
-            modules,
-            vec![BpModule {
-                module_type: "rust_library".to_string(),
-                props: BpProperties {
-                    map: [
-                        (
-                            "apex_available".to_string(),
-                            BpValue::List(vec![
-                                BpValue::String("//apex_available:platform".to_string()),
-                                BpValue::String("//apex_available:anyapex".to_string()),
-                            ])
-                        ),
-                        ("cargo_env_compat".to_string(), BpValue::Bool(true)),
-                        ("crate_name".to_string(), BpValue::String("name".to_string())),
-                        ("edition".to_string(), BpValue::String("2021".to_string())),
-                        ("host_supported".to_string(), BpValue::Bool(true)),
-                        ("name".to_string(), BpValue::String("libname".to_string())),
-                        ("product_available".to_string(), BpValue::Bool(true)),
-                        ("crate_root".to_string(), BpValue::String("".to_string())),
-                        ("vendor_available".to_string(), BpValue::Bool(true)),
-                    ]
-                    .into_iter()
-                    .collect(),
-                    raw_block: None
-                }
-            }]
+            check_rustc_warnings(&cfg, &crates).unwrap_err().to_string(),
+            "variant 0 has 1 rustc warnings, more than max_rustc_warnings (0)"
         );
     }
 
     #[test]
-    fn crate_to_bp_rename() {
-        let c = Crate {
-            name: "ash".to_string(),
-            package_name: "package_name".to_string(),
-            edition: "2021".to_string(),
-            types: vec![CrateType::Lib],
+    fn record_build_script_directives_removes_stale_output() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let package_dir = dir.path().join("foo");
+        let intermediates_dir = dir.path().join("intermediates");
+        let build_dir = variant_intermediates_dir(&intermediates_dir, 0)
+            .join("target.tmp/x86_64-unknown-linux-gnu/debug/build/foo-1234");
+        create_dir_all(&package_dir)?;
+        create_dir_all(&build_dir)?;
+        let cfg = Config { variants: vec![VariantConfig::default()], ..Default::default() };
+        let crates = vec![vec![Crate {
+            package_name: "foo".to_string(),
+            package_dir: package_dir.clone(),
             ..Default::default()
-        };
-        let cfg = VariantConfig { ..Default::default() };
-        let package_cfg = PackageVariantConfig { ..Default::default() };
-        let modules = crate_to_bp_modules(&c, &cfg, &package_cfg, &[]).unwrap();
+        }]];
+        let path = package_dir.join(BUILD_SCRIPT_OUTPUT_FILE);
 
+        write(build_dir.join("output"), "cargo:rustc-cfg=has_foo\n")?;
         assert_eq!(
-            modules,
-            vec![BpModule {
-                module_type: "rust_library".to_string(),
-                props: BpProperties {
-                    map: [
-                        (
-                            "apex_available".to_string(),
-                            BpValue::List(vec![
-                                BpValue::String("//apex_available:platform".to_string()),
-                                BpValue::String("//apex_available:anyapex".to_string()),
-                            ])
-                        ),
-                        ("cargo_env_compat".to_string(), BpValue::Bool(true)),
-                        ("crate_name".to_string(), BpValue::String("ash".to_string())),
-                        ("edition".to_string(), BpValue::String("2021".to_string())),
-                        ("host_supported".to_string(), BpValue::Bool(true)),
-                        ("name".to_string(), BpValue::String("libash_rust".to_string())),
-                        ("product_available".to_string(), BpValue::Bool(true)),
-                        ("crate_root".to_string(), BpValue::String("".to_string())),
-                        ("vendor_available".to_string(), BpValue::Bool(true)),
-                    ]
-                    .into_iter()
-                    .collect(),
-                    raw_block: None
-                }
-            }]
+            record_build_script_directives(&cfg, &crates, &intermediates_dir)?,
+            vec![(path.clone(), None)]
+        );
+        let recorded = read_to_string(&path)?;
+        assert!(recorded.contains("has_foo"), "{recorded}");
+
+        // The build script no longer prints anything which affects the build.
+        write(build_dir.join("output"), "cargo:rerun-if-changed=build.rs\n")?;
+        assert_eq!(
+            record_build_script_directives(&cfg, &crates, &intermediates_dir)?,
+            vec![(path.clone(), Some(recorded))]
         );
+        assert!(!path.exists());
+        Ok(())
     }
 
     /// Returns a list of directories containing test data.
@@ -0,0 +1,376 @@
+// Copyright (C) 2024 The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Applying unified diffs to generated build files, like `patch` but reporting exactly why a hunk
+//! didn't apply.
+
+use anyhow::{bail, Context, Result};
+use std::fmt::Write;
+
+/// Maximum number of context lines which may be ignored at the start and end of each hunk when
+/// looking for where to apply it, as for `patch --fuzz`.
+const MAX_FUZZ: usize = 2;
+
+/// A single line of a hunk, including its newline if it has one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum HunkLine {
+    Context(String),
+    Delete(String),
+    Insert(String),
+}
+
+impl HunkLine {
+    /// Returns the line if it is in the original file.
+    fn old_line(&self) -> Option<&str> {
+        match self {
+            HunkLine::Context(line) | HunkLine::Delete(line) => Some(line),
+            HunkLine::Insert(_) => None,
+        }
+    }
+
+    /// Returns the line if it is in the patched file.
+    fn new_line(&self) -> Option<&str> {
+        match self {
+            HunkLine::Context(line) | HunkLine::Insert(line) => Some(line),
+            HunkLine::Delete(_) => None,
+        }
+    }
+}
+
+/// A hunk of a unified diff.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Hunk {
+    /// The 1-based line number of the hunk header in the patch file.
+    patch_line: usize,
+    /// The hunk header, e.g. `@@ -1,3 +1,4 @@`.
+    header: String,
+    /// The 0-based index of the first line of the hunk in the original file.
+    old_start: usize,
+    lines: Vec<HunkLine>,
+    /// The hunk as it appears in the patch file, for writing to a reject file.
+    text: String,
+}
+
+impl Hunk {
+    /// Returns the number of context lines at the start and end of the hunk.
+    fn context_lengths(&self) -> (usize, usize) {
+        let is_context = |line: &&HunkLine| matches!(line, HunkLine::Context(_));
+        let leading = self.lines.iter().take_while(is_context).count();
+        let trailing = self.lines.iter().rev().take_while(is_context).count();
+        (leading, trailing)
+    }
+}
+
+/// A parsed unified diff. All the hunks are applied to the same file, whatever file names it has.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Patch {
+    hunks: Vec<Hunk>,
+}
+
+/// Where a hunk was applied, if it wasn't exactly where the patch said.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AppliedHunk {
+    /// The 1-based index of the hunk in the patch.
+    pub index: usize,
+    /// The 1-based line in the original file at which the hunk was applied.
+    pub line: usize,
+    /// The number of lines between where the hunk was applied and where the patch said it should
+    /// be.
+    pub offset: isize,
+    /// The number of context lines ignored at the start and end of the hunk.
+    pub fuzz: usize,
+}
+
+/// The result of applying a patch.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PatchResult {
+    /// The patched contents, without any hunks which failed.
+    pub contents: String,
+    /// The hunks which were applied with an offset or fuzz.
+    pub inexact: Vec<AppliedHunk>,
+    /// A description of each hunk which failed to apply.
+    pub failures: Vec<String>,
+    /// The failed hunks in unified diff format, like a `.rej` file from `patch`, or an empty
+    /// string if there were none.
+    pub rejects: String,
+}
+
+impl Patch {
+    /// Parses a unified diff.
+    pub fn parse(patch: &str) -> Result<Self> {
+        let lines: Vec<&str> = patch.split_inclusive('\n').collect();
+        let mut hunks = Vec::new();
+        let mut i = 0;
+        while i < lines.len() {
+            if !lines[i].starts_with("@@ ") {
+                // Skip file headers and any other text between hunks.
+                i += 1;
+                continue;
+            }
+            let patch_line = i + 1;
+            let header = lines[i].trim_end().to_string();
+            let (old_start, old_count, new_count) = parse_hunk_header(&header)
+                .with_context(|| format!("invalid hunk header at line {patch_line}: {header}"))?;
+            i += 1;
+            let mut hunk_lines: Vec<HunkLine> = Vec::new();
+            let (mut old_seen, mut new_seen) = (0, 0);
+            while i < lines.len() && (old_seen < old_count || new_seen < new_count) {
+                let line = lines[i];
+                // Some tools strip the trailing space from empty context lines.
+                let (prefix, rest) = if line == "\n" { (' ', "\n") } else { split_prefix(line) };
+                match prefix {
+                    ' ' => hunk_lines.push(HunkLine::Context(rest.to_string())),
+                    '-' => hunk_lines.push(HunkLine::Delete(rest.to_string())),
+                    '+' => hunk_lines.push(HunkLine::Insert(rest.to_string())),
+                    '\\' => {
+                        strip_newline(hunk_lines.last_mut());
+                        i += 1;
+                        continue;
+                    }
+                    _ => bail!(
+                        "unexpected line {} in hunk at line {patch_line}: {}",
+                        i + 1,
+                        line.trim_end()
+                    ),
+                }
+                let last = hunk_lines.last().unwrap();
+                old_seen += usize::from(last.old_line().is_some());
+                new_seen += usize::from(last.new_line().is_some());
+                i += 1;
+            }
+            if old_seen != old_count || new_seen != new_count {
+                bail!("hunk at line {patch_line} is truncated");
+            }
+            // The marker for a missing newline comes after the last line of the hunk.
+            if lines.get(i).is_some_and(|line| line.starts_with('\\')) {
+                strip_newline(hunk_lines.last_mut());
+                i += 1;
+            }
+            hunks.push(Hunk {
+                patch_line,
+                header,
+                // A hunk which doesn't remove any lines gives the line before it.
+                old_start: if old_count == 0 { old_start } else { old_start - 1 },
+                lines: hunk_lines,
+                text: lines[patch_line - 1..i].concat(),
+            });
+        }
+        if hunks.is_empty() {
+            bail!("no hunks found");
+        }
+        Ok(Patch { hunks })
+    }
+
+    /// Applies the patch to the given contents.
+    ///
+    /// Like `patch`, each hunk may be applied at an offset from where the patch says, and with up
+    /// to `MAX_FUZZ` lines of context at each end ignored if it doesn't apply otherwise. Hunks which
+    /// can't be applied are skipped and reported in the result.
+    pub fn apply(&self, contents: &str, file_name: &str) -> PatchResult {
+        let lines: Vec<&str> = contents.split_inclusive('\n').collect();
+        let mut patched = String::new();
+        let mut inexact = Vec::new();
+        let mut failures = Vec::new();
+        let mut rejects = String::new();
+        // The index of the first line of `lines` which hasn't been copied to `patched` yet.
+        let mut cursor = 0;
+        // The offset of the last hunk applied, which is likely to apply to later hunks too.
+        let mut offset: isize = 0;
+        for (index, hunk) in self.hunks.iter().enumerate() {
+            let index = index + 1;
+            let (leading, trailing) = hunk.context_lengths();
+            let found = (0..=MAX_FUZZ).find_map(|fuzz| {
+                let skip_start = fuzz.min(leading);
+                let skip_end = fuzz.min(trailing);
+                if fuzz > 0 && skip_start + skip_end == 0 {
+                    return None;
+                }
+                let hunk_lines = &hunk.lines[skip_start..hunk.lines.len() - skip_end];
+                let old: Vec<&str> = hunk_lines.iter().filter_map(HunkLine::old_line).collect();
+                let expected = (hunk.old_start + skip_start) as isize + offset;
+                let position = find_lines(&lines, &old, cursor, expected)?;
+                Some((position, fuzz, skip_start, hunk_lines, old.len()))
+            });
+            let Some((position, fuzz, skip_start, hunk_lines, old_len)) = found else {
+                failures.push(describe_failure(index, hunk, &lines, offset));
+                rejects += &hunk.text;
+                continue;
+            };
+            offset = position as isize - (hunk.old_start + skip_start) as isize;
+            if offset != 0 || fuzz != 0 {
+                inexact.push(AppliedHunk { index, line: position + 1, offset, fuzz });
+            }
+            patched.extend(lines[cursor..position].iter().copied());
+            patched.extend(hunk_lines.iter().filter_map(HunkLine::new_line));
+            cursor = position + old_len;
+        }
+        patched.extend(lines[cursor..].iter().copied());
+        if !rejects.is_empty() {
+            rejects = format!("--- {file_name}\n+++ {file_name}\n{rejects}");
+        }
+        PatchResult { contents: patched, inexact, failures, rejects }
+    }
+}
+
+/// Splits the first character from the given line of a hunk.
+fn split_prefix(line: &str) -> (char, &str) {
+    let mut chars = line.chars();
+    (chars.next().unwrap_or(' '), chars.as_str())
+}
+
+/// Removes the trailing newline from the given hunk line, if any.
+fn strip_newline(line: Option<&mut HunkLine>) {
+    if let Some(HunkLine::Context(line) | HunkLine::Delete(line) | HunkLine::Insert(line)) = line {
+        if line.ends_with('\n') {
+            line.pop();
+        }
+    }
+}
+
+/// Parses a hunk header such as `@@ -1,3 +1,4 @@ fn foo()`, returning the start line and number of
+/// lines in the original file and the number of lines in the new file.
+fn parse_hunk_header(header: &str) -> Result<(usize, usize, usize)> {
+    let ranges = header.strip_prefix("@@ -").context("missing \"@@ -\"")?;
+    let (ranges, _) = ranges.split_once(" @@").context("missing \" @@\"")?;
+    let (old, new) = ranges.split_once(" +").context("missing new range")?;
+    let parse_range = |range: &str| -> Result<(usize, usize)> {
+        Ok(match range.split_once(',') {
+            Some((start, count)) => (start.parse()?, count.parse()?),
+            None => (range.parse()?, 1),
+        })
+    };
+    let (old_start, old_count) = parse_range(old)?;
+    let (_, new_count) = parse_range(new)?;
+    if old_start == 0 && old_count != 0 {
+        bail!("line numbers start at 1");
+    }
+    Ok((old_start, old_count, new_count))
+}
+
+/// Returns the index at which `needle` occurs in `lines` at or after `start`, choosing the closest
+/// to `expected` if there are several.
+fn find_lines(lines: &[&str], needle: &[&str], start: usize, expected: isize) -> Option<usize> {
+    let last = lines.len().checked_sub(needle.len())?;
+    (start..=last)
+        .filter(|&i| lines[i..i + needle.len()] == *needle)
+        .min_by_key(|&i| (i as isize - expected).abs())
+}
+
+/// Returns a description of why the given hunk couldn't be applied, comparing the lines it expects
+/// to those at the position where it should have applied.
+fn describe_failure(index: usize, hunk: &Hunk, lines: &[&str], offset: isize) -> String {
+    let old: Vec<&str> = hunk.lines.iter().filter_map(HunkLine::old_line).collect();
+    let start = (hunk.old_start as isize + offset).clamp(0, lines.len() as isize) as usize;
+    let actual = &lines[start..(start + old.len()).min(lines.len())];
+    let mut description = format!(
+        "hunk #{index} ({}, line {} of the patch) failed at line {}",
+        hunk.header,
+        hunk.patch_line,
+        start + 1
+    );
+    if offset != 0 {
+        write!(description, " (offset {offset} lines)").unwrap();
+    }
+    description += "\nexpected:\n";
+    for line in &old {
+        writeln!(description, "    {}", line.trim_end_matches('\n')).unwrap();
+    }
+    description += "found:\n";
+    for line in actual {
+        writeln!(description, "    {}", line.trim_end_matches('\n')).unwrap();
+    }
+    if actual.is_empty() {
+        description += "    <end of file>\n";
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIGINAL: &str = "a\nb\nc\nd\ne\nf\ng\nh\n";
+
+    #[test]
+    fn apply_exact() {
+        let patch = Patch::parse(
+            "--- a/file\n+++ b/file\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n@@ -8 +8,2 @@\n h\n+i\n",
+        )
+        .unwrap();
+        let result = patch.apply(ORIGINAL, "file");
+        assert_eq!(result.contents, "a\nb\nC\nd\ne\nf\ng\nh\ni\n");
+        assert_eq!(result.inexact, vec![]);
+        assert_eq!(result.failures, Vec::<String>::new());
+        assert_eq!(result.rejects, "");
+    }
+
+    #[test]
+    fn apply_with_offset_and_fuzz() {
+        let patch = Patch::parse("@@ -1,3 +1,3 @@\n x\n-c\n+C\n d\n@@ -5,0 +6 @@\n+new\n").unwrap();
+        let result = patch.apply(ORIGINAL, "file");
+        assert_eq!(result.contents, "a\nb\nC\nd\ne\nf\nnew\ng\nh\n");
+        assert_eq!(
+            result.inexact,
+            vec![
+                AppliedHunk { index: 1, line: 3, offset: 1, fuzz: 1 },
+                AppliedHunk { index: 2, line: 7, offset: 1, fuzz: 0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_newline() {
+        let patch = Patch::parse(
+            "@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+b\n\\ No newline at end of file\n",
+        )
+        .unwrap();
+        assert_eq!(patch.apply("a", "file").contents, "b");
+    }
+
+    #[test]
+    fn report_failure() {
+        let patch_text = "@@ -2,5 +2,5 @@\n b\n x\n-y\n+Y\n z\n f\n";
+        let patch = Patch::parse(patch_text).unwrap();
+        let result = patch.apply(ORIGINAL, "Android.bp");
+        assert_eq!(result.contents, ORIGINAL);
+        assert_eq!(
+            result.failures,
+            vec!["hunk #1 (@@ -2,5 +2,5 @@, line 1 of the patch) failed at line 2
+expected:
+    b
+    x
+    y
+    z
+    f
+found:
+    b
+    c
+    d
+    e
+    f
+"
+            .to_string()]
+        );
+        assert_eq!(result.rejects, format!("--- Android.bp\n+++ Android.bp\n{patch_text}"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Patch::parse("not a patch\n").unwrap_err().to_string(), "no hunks found");
+        assert_eq!(
+            Patch::parse("@@ -1,2 +1,2 @@\n a\n").unwrap_err().to_string(),
+            "hunk at line 1 is truncated"
+        );
+    }
+}